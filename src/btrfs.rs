@@ -0,0 +1,802 @@
+//! Btrfs management: subvolumes, snapshots, scrub, balance and devices.
+//!
+//! Subvolume creation, deletion and snapshots, the default subvolume,
+//! adding and removing devices and the per device error counters go through
+//! ioctls on the mounted filesystem.  Listing subvolumes, scrub, balance and
+//! replace use the btrfs tool since their ioctls either block until the
+//! operation finishes or need the tree search API to be useful.
+//! Filesystems are created with `Filesystem::Btrfs` or `mkfs::BtrfsSpec`.
+use crate::{run_command, BlockResult, BlockUtilsError, MetadataProfile};
+
+use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
+use serde::{Deserialize, Serialize};
+use strum::Display;
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+/// Object id of the root directory of every subvolume
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+const BTRFS_DEV_STAT_VALUES_MAX: usize = 5;
+
+#[repr(C)]
+struct BtrfsIoctlVolArgs {
+    fd: i64,
+    name: [u8; 4088],
+}
+
+#[repr(C)]
+struct BtrfsIoctlVolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; 4040],
+}
+
+#[repr(C)]
+struct BtrfsIoctlInoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; 4080],
+}
+
+#[repr(C)]
+struct BtrfsIoctlFsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    reserved: [u8; 976],
+}
+
+#[repr(C)]
+struct BtrfsIoctlDevInfoArgs {
+    devid: u64,
+    uuid: [u8; 16],
+    bytes_used: u64,
+    total_bytes: u64,
+    unused: [u8; 3032],
+    path: [u8; 1024],
+}
+
+#[repr(C)]
+struct BtrfsIoctlGetDevStats {
+    devid: u64,
+    nr_items: u64,
+    flags: u64,
+    values: [u64; BTRFS_DEV_STAT_VALUES_MAX],
+    unused: [u64; 121],
+}
+
+ioctl_write_ptr!(btrfs_ioc_add_dev, BTRFS_IOCTL_MAGIC, 10, BtrfsIoctlVolArgs);
+ioctl_write_ptr!(btrfs_ioc_rm_dev, BTRFS_IOCTL_MAGIC, 11, BtrfsIoctlVolArgs);
+ioctl_write_ptr!(
+    btrfs_ioc_subvol_create,
+    BTRFS_IOCTL_MAGIC,
+    14,
+    BtrfsIoctlVolArgs
+);
+ioctl_write_ptr!(
+    btrfs_ioc_snap_destroy,
+    BTRFS_IOCTL_MAGIC,
+    15,
+    BtrfsIoctlVolArgs
+);
+ioctl_readwrite!(
+    btrfs_ioc_ino_lookup,
+    BTRFS_IOCTL_MAGIC,
+    18,
+    BtrfsIoctlInoLookupArgs
+);
+ioctl_write_ptr!(btrfs_ioc_default_subvol, BTRFS_IOCTL_MAGIC, 19, u64);
+ioctl_write_ptr!(
+    btrfs_ioc_snap_create_v2,
+    BTRFS_IOCTL_MAGIC,
+    23,
+    BtrfsIoctlVolArgsV2
+);
+ioctl_readwrite!(
+    btrfs_ioc_dev_info,
+    BTRFS_IOCTL_MAGIC,
+    30,
+    BtrfsIoctlDevInfoArgs
+);
+ioctl_read!(
+    btrfs_ioc_fs_info,
+    BTRFS_IOCTL_MAGIC,
+    31,
+    BtrfsIoctlFsInfoArgs
+);
+ioctl_readwrite!(
+    btrfs_ioc_get_dev_stats,
+    BTRFS_IOCTL_MAGIC,
+    52,
+    BtrfsIoctlGetDevStats
+);
+
+/// Copy `name` into a NUL terminated ioctl name field
+fn copy_name(dst: &mut [u8], name: &OsStr) -> BlockResult<()> {
+    let bytes = name.as_bytes();
+    if bytes.len() >= dst.len() {
+        return Err(BlockUtilsError::new(format!(
+            "{} is longer than the {} bytes btrfs allows",
+            name.to_string_lossy(),
+            dst.len() - 1
+        )));
+    }
+    dst[..bytes.len()].copy_from_slice(bytes);
+    Ok(())
+}
+
+/// The directory a subvolume lives in and its name in there
+fn split_path(path: &Path) -> BlockResult<(PathBuf, OsString)> {
+    let name = path.file_name().ok_or_else(|| {
+        BlockUtilsError::new(format!("{} has no final component", path.display()))
+    })?;
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    Ok((parent, name.to_os_string()))
+}
+
+fn vol_args(name: &OsStr) -> BlockResult<BtrfsIoctlVolArgs> {
+    let mut args = BtrfsIoctlVolArgs {
+        fd: 0,
+        name: [0; 4088],
+    };
+    copy_name(&mut args.name, name)?;
+    Ok(args)
+}
+
+/// Create an empty subvolume at `path`
+pub fn create_subvolume(path: impl AsRef<Path>) -> BlockResult<()> {
+    let (parent, name) = split_path(path.as_ref())?;
+    let dir = File::open(parent)?;
+    let args = vol_args(&name)?;
+    unsafe { btrfs_ioc_subvol_create(dir.as_raw_fd(), &args) }?;
+    Ok(())
+}
+
+/// Delete the subvolume or snapshot at `path`.  Needs CAP_SYS_ADMIN unless
+/// the filesystem is mounted with user_subvol_rm_allowed
+pub fn delete_subvolume(path: impl AsRef<Path>) -> BlockResult<()> {
+    let (parent, name) = split_path(path.as_ref())?;
+    let dir = File::open(parent)?;
+    let args = vol_args(&name)?;
+    unsafe { btrfs_ioc_snap_destroy(dir.as_raw_fd(), &args) }?;
+    Ok(())
+}
+
+/// Snapshot the subvolume `source` at `dest`
+pub fn snapshot(
+    source: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    read_only: bool,
+) -> BlockResult<()> {
+    let source = File::open(source.as_ref())?;
+    let (parent, name) = split_path(dest.as_ref())?;
+    let dir = File::open(parent)?;
+    let mut args = BtrfsIoctlVolArgsV2 {
+        fd: source.as_raw_fd() as i64,
+        transid: 0,
+        flags: if read_only { BTRFS_SUBVOL_RDONLY } else { 0 },
+        unused: [0; 4],
+        name: [0; 4040],
+    };
+    copy_name(&mut args.name, &name)?;
+    unsafe { btrfs_ioc_snap_create_v2(dir.as_raw_fd(), &args) }?;
+    Ok(())
+}
+
+/// Id of the subvolume `path` is the root of, or lives in
+pub fn subvolume_id(path: impl AsRef<Path>) -> BlockResult<u64> {
+    let f = File::open(path.as_ref())?;
+    let mut args = BtrfsIoctlInoLookupArgs {
+        treeid: 0,
+        objectid: BTRFS_FIRST_FREE_OBJECTID,
+        name: [0; 4080],
+    };
+    unsafe { btrfs_ioc_ino_lookup(f.as_raw_fd(), &mut args) }?;
+    Ok(args.treeid)
+}
+
+/// Mount the subvolume at `subvolume` when the filesystem is mounted
+/// without a subvol option
+pub fn set_default_subvolume(subvolume: impl AsRef<Path>) -> BlockResult<()> {
+    let id = subvolume_id(subvolume.as_ref())?;
+    let f = File::open(subvolume.as_ref())?;
+    unsafe { btrfs_ioc_default_subvol(f.as_raw_fd(), &id) }?;
+    Ok(())
+}
+
+/// A subvolume or snapshot from `btrfs subvolume list`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Subvolume {
+    pub id: u64,
+    pub generation: u64,
+    /// Id of the subvolume containing this one
+    pub parent_id: u64,
+    pub top_level: u64,
+    /// Relative to the top level subvolume
+    pub path: PathBuf,
+    pub uuid: Option<Uuid>,
+    /// The subvolume a snapshot was taken of
+    pub parent_uuid: Option<Uuid>,
+}
+
+impl Subvolume {
+    pub fn is_snapshot(&self) -> bool {
+        self.parent_uuid.is_some()
+    }
+}
+
+/// Run btrfs and return stdout
+fn btrfs_command(args: &[&str]) -> BlockResult<String> {
+    let output = run_command("btrfs", args)?;
+    if !output.status.success() {
+        return Err(BlockUtilsError::new(format!(
+            "btrfs {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse `btrfs subvolume list -p -u -q` lines like "ID 257 gen 12 parent 5
+/// top level 5 parent_uuid - uuid 9c... path home"
+pub fn parse_subvolume_list(output: &str) -> BlockResult<Vec<Subvolume>> {
+    let mut subvolumes = Vec::new();
+    for line in output.lines().filter(|l| !l.trim().is_empty()) {
+        // The path is last and may contain spaces
+        let (fields, path) = line.split_once(" path ").ok_or_else(|| {
+            BlockUtilsError::new(format!("Unable to parse subvolume line: {}", line))
+        })?;
+        let fields = fields.replace("top level", "top_level");
+        let words: Vec<&str> = fields.split_whitespace().collect();
+        let mut values: BTreeMap<&str, &str> = BTreeMap::new();
+        for pair in words.chunks(2) {
+            if let [key, value] = pair {
+                values.insert(key, value);
+            }
+        }
+        let number = |key: &str| -> BlockResult<u64> {
+            Ok(values.get(key).map(|v| v.parse()).transpose()?.unwrap_or(0))
+        };
+        let uuid = |key: &str| values.get(key).and_then(|v| Uuid::parse_str(v).ok());
+        subvolumes.push(Subvolume {
+            id: number("ID")?,
+            generation: number("gen")?,
+            parent_id: number("parent")?,
+            top_level: number("top_level")?,
+            path: PathBuf::from(path),
+            uuid: uuid("uuid"),
+            parent_uuid: uuid("parent_uuid"),
+        });
+    }
+    Ok(subvolumes)
+}
+
+/// Every subvolume and snapshot of the filesystem mounted at `mount_point`
+pub fn list_subvolumes(mount_point: impl AsRef<Path>) -> BlockResult<Vec<Subvolume>> {
+    parse_subvolume_list(&btrfs_command(&[
+        "subvolume",
+        "list",
+        "-p",
+        "-u",
+        "-q",
+        &mount_point.as_ref().to_string_lossy(),
+    ])?)
+}
+
+/// Snapshots of the filesystem mounted at `mount_point`
+pub fn list_snapshots(mount_point: impl AsRef<Path>) -> BlockResult<Vec<Subvolume>> {
+    Ok(list_subvolumes(mount_point)?
+        .into_iter()
+        .filter(|s| s.is_snapshot())
+        .collect())
+}
+
+/// A device of a mounted btrfs filesystem
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BtrfsDevice {
+    pub devid: u64,
+    pub path: PathBuf,
+    pub bytes_used: u64,
+    pub total_bytes: u64,
+}
+
+/// Error counters the kernel keeps per device
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeviceErrorStats {
+    pub devid: u64,
+    pub write_errors: u64,
+    pub read_errors: u64,
+    pub flush_errors: u64,
+    /// Checksum mismatches
+    pub corruption_errors: u64,
+    /// Blocks with an unexpected generation, ie: lost writes
+    pub generation_errors: u64,
+}
+
+/// Devices of the filesystem mounted at `mount_point`
+pub fn list_devices(mount_point: impl AsRef<Path>) -> BlockResult<Vec<BtrfsDevice>> {
+    let f = File::open(mount_point.as_ref())?;
+    let mut fs_info = BtrfsIoctlFsInfoArgs {
+        max_id: 0,
+        num_devices: 0,
+        fsid: [0; 16],
+        nodesize: 0,
+        sectorsize: 0,
+        clone_alignment: 0,
+        csum_type: 0,
+        csum_size: 0,
+        reserved: [0; 976],
+    };
+    unsafe { btrfs_ioc_fs_info(f.as_raw_fd(), &mut fs_info) }?;
+    let mut devices = Vec::new();
+    // Device ids have holes after devices were removed
+    for devid in 1..=fs_info.max_id {
+        let mut info = BtrfsIoctlDevInfoArgs {
+            devid,
+            uuid: [0; 16],
+            bytes_used: 0,
+            total_bytes: 0,
+            unused: [0; 3032],
+            path: [0; 1024],
+        };
+        match unsafe { btrfs_ioc_dev_info(f.as_raw_fd(), &mut info) } {
+            Ok(_) => {
+                let len = info
+                    .path
+                    .iter()
+                    .position(|&b| b == 0)
+                    .unwrap_or(info.path.len());
+                devices.push(BtrfsDevice {
+                    devid,
+                    path: PathBuf::from(OsStr::from_bytes(&info.path[..len])),
+                    bytes_used: info.bytes_used,
+                    total_bytes: info.total_bytes,
+                });
+            }
+            Err(nix::errno::Errno::ENODEV) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(devices)
+}
+
+/// Error counters of device `devid`.  `reset` zeroes them after reading
+pub fn device_error_stats(
+    mount_point: impl AsRef<Path>,
+    devid: u64,
+    reset: bool,
+) -> BlockResult<DeviceErrorStats> {
+    let f = File::open(mount_point.as_ref())?;
+    let mut args = BtrfsIoctlGetDevStats {
+        devid,
+        nr_items: BTRFS_DEV_STAT_VALUES_MAX as u64,
+        // BTRFS_DEV_STATS_RESET
+        flags: reset as u64,
+        values: [0; BTRFS_DEV_STAT_VALUES_MAX],
+        unused: [0; 121],
+    };
+    unsafe { btrfs_ioc_get_dev_stats(f.as_raw_fd(), &mut args) }?;
+    let [write_errors, read_errors, flush_errors, corruption_errors, generation_errors] =
+        args.values;
+    Ok(DeviceErrorStats {
+        devid,
+        write_errors,
+        read_errors,
+        flush_errors,
+        corruption_errors,
+        generation_errors,
+    })
+}
+
+/// Error counters of every device of the filesystem
+pub fn error_stats(mount_point: impl AsRef<Path>) -> BlockResult<Vec<DeviceErrorStats>> {
+    list_devices(mount_point.as_ref())?
+        .iter()
+        .map(|d| device_error_stats(mount_point.as_ref(), d.devid, false))
+        .collect()
+}
+
+/// Add `device` to the filesystem mounted at `mount_point`.  Run a balance
+/// afterwards to spread existing data onto it
+pub fn add_device(mount_point: impl AsRef<Path>, device: impl AsRef<Path>) -> BlockResult<()> {
+    let f = File::open(mount_point.as_ref())?;
+    let args = vol_args(device.as_ref().as_os_str())?;
+    unsafe { btrfs_ioc_add_dev(f.as_raw_fd(), &args) }?;
+    Ok(())
+}
+
+/// Remove `device` from the filesystem, moving its data to the other
+/// devices first.  Blocks until the data is moved
+pub fn remove_device(mount_point: impl AsRef<Path>, device: impl AsRef<Path>) -> BlockResult<()> {
+    let f = File::open(mount_point.as_ref())?;
+    let args = vol_args(device.as_ref().as_os_str())?;
+    unsafe { btrfs_ioc_rm_dev(f.as_raw_fd(), &args) }?;
+    Ok(())
+}
+
+/// Start replacing `source`, a device path or devid, with `target` in the
+/// background.  `force` overwrites a filesystem found on `target`
+pub fn replace_device(
+    mount_point: impl AsRef<Path>,
+    source: &str,
+    target: impl AsRef<Path>,
+    force: bool,
+) -> BlockResult<()> {
+    let target = target.as_ref().to_string_lossy();
+    let mount_point = mount_point.as_ref().to_string_lossy();
+    let mut args = vec!["replace", "start"];
+    if force {
+        args.push("-f");
+    }
+    args.extend_from_slice(&[source, &target, &mount_point]);
+    btrfs_command(&args)?;
+    Ok(())
+}
+
+/// State of a scrub, balance or replace
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum OperationState {
+    /// Nothing was started
+    None,
+    Running,
+    Paused,
+    Finished,
+    Canceled,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReplaceStatus {
+    pub state: OperationState,
+    pub percent_done: Option<f64>,
+}
+
+pub fn parse_replace_status(output: &str) -> ReplaceStatus {
+    let line = output.lines().last().unwrap_or_default();
+    let state = if line.starts_with("Never started") {
+        OperationState::None
+    } else if line.contains("% done") {
+        OperationState::Running
+    } else if line.contains("canceled") {
+        OperationState::Canceled
+    } else if line.contains("suspended") {
+        OperationState::Paused
+    } else {
+        OperationState::Finished
+    };
+    let percent_done = line
+        .split_once("% done")
+        .and_then(|(before, _)| before.rsplit(' ').next()?.parse().ok());
+    ReplaceStatus {
+        state,
+        percent_done,
+    }
+}
+
+/// Progress of the current or last device replace
+pub fn replace_status(mount_point: impl AsRef<Path>) -> BlockResult<ReplaceStatus> {
+    Ok(parse_replace_status(&btrfs_command(&[
+        "replace",
+        "status",
+        "-1",
+        &mount_point.as_ref().to_string_lossy(),
+    ])?))
+}
+
+/// Start a scrub of every device in the background
+pub fn start_scrub(mount_point: impl AsRef<Path>) -> BlockResult<()> {
+    btrfs_command(&["scrub", "start", &mount_point.as_ref().to_string_lossy()])?;
+    Ok(())
+}
+
+pub fn cancel_scrub(mount_point: impl AsRef<Path>) -> BlockResult<()> {
+    btrfs_command(&["scrub", "cancel", &mount_point.as_ref().to_string_lossy()])?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ScrubStatus {
+    pub state: OperationState,
+    pub bytes_scrubbed: u64,
+    pub read_errors: u64,
+    pub csum_errors: u64,
+    pub verify_errors: u64,
+    pub super_errors: u64,
+    pub corrected_errors: u64,
+    pub uncorrectable_errors: u64,
+    /// Every raw counter btrfs reported
+    pub counters: BTreeMap<String, u64>,
+}
+
+/// Parse `btrfs scrub status -R`
+pub fn parse_scrub_status(output: &str) -> ScrubStatus {
+    let mut counters = BTreeMap::new();
+    let mut state = None;
+    for line in output.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+        if key == "Status" {
+            state = Some(match value {
+                "running" => OperationState::Running,
+                "finished" => OperationState::Finished,
+                "aborted" | "interrupted" => OperationState::Canceled,
+                _ => OperationState::None,
+            });
+        } else if let Ok(n) = value.parse::<u64>() {
+            counters.insert(key.to_string(), n);
+        }
+    }
+    // Older btrfs-progs only describe the state in prose
+    let state = state.unwrap_or_else(|| {
+        if output.contains("no stats available") {
+            OperationState::None
+        } else if output.contains("running") {
+            OperationState::Running
+        } else if output.contains("aborted") || output.contains("interrupted") {
+            OperationState::Canceled
+        } else {
+            OperationState::Finished
+        }
+    });
+    let get = |key: &str| counters.get(key).copied().unwrap_or(0);
+    ScrubStatus {
+        state,
+        bytes_scrubbed: get("data_bytes_scrubbed") + get("tree_bytes_scrubbed"),
+        read_errors: get("read_errors"),
+        csum_errors: get("csum_errors"),
+        verify_errors: get("verify_errors"),
+        super_errors: get("super_errors"),
+        corrected_errors: get("corrected_errors"),
+        uncorrectable_errors: get("uncorrectable_errors"),
+        counters,
+    }
+}
+
+/// Progress of the current scrub or the result of the last one
+pub fn scrub_status(mount_point: impl AsRef<Path>) -> BlockResult<ScrubStatus> {
+    Ok(parse_scrub_status(&btrfs_command(&[
+        "scrub",
+        "status",
+        "-R",
+        &mount_point.as_ref().to_string_lossy(),
+    ])?))
+}
+
+/// Which chunks a balance rewrites
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BalanceFilters {
+    /// Only data chunks less than this percent full
+    pub data_usage: Option<u8>,
+    /// Only metadata chunks less than this percent full
+    pub metadata_usage: Option<u8>,
+    /// Convert data chunks to this profile
+    pub convert_data: Option<MetadataProfile>,
+    /// Convert metadata chunks to this profile
+    pub convert_metadata: Option<MetadataProfile>,
+}
+
+impl BalanceFilters {
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, usage, convert) in &[
+            ("-d", self.data_usage, &self.convert_data),
+            ("-m", self.metadata_usage, &self.convert_metadata),
+        ] {
+            let mut filters = Vec::new();
+            if let Some(usage) = usage {
+                filters.push(format!("usage={}", usage));
+            }
+            if let Some(profile) = convert {
+                filters.push(format!("convert={}", profile));
+            }
+            if !filters.is_empty() {
+                args.push(format!("{}{}", flag, filters.join(",")));
+            }
+        }
+        args
+    }
+}
+
+/// Start a balance in the background.  Without filters every chunk is
+/// rewritten, which can take a long time
+pub fn start_balance(mount_point: impl AsRef<Path>, filters: &BalanceFilters) -> BlockResult<()> {
+    let mount_point = mount_point.as_ref().to_string_lossy();
+    let filter_args = filters.args();
+    let mut args = vec!["balance", "start", "--bg"];
+    args.extend(filter_args.iter().map(|a| a.as_str()));
+    args.push(&mount_point);
+    btrfs_command(&args)?;
+    Ok(())
+}
+
+pub fn pause_balance(mount_point: impl AsRef<Path>) -> BlockResult<()> {
+    btrfs_command(&["balance", "pause", &mount_point.as_ref().to_string_lossy()])?;
+    Ok(())
+}
+
+pub fn resume_balance(mount_point: impl AsRef<Path>) -> BlockResult<()> {
+    btrfs_command(&["balance", "resume", &mount_point.as_ref().to_string_lossy()])?;
+    Ok(())
+}
+
+pub fn cancel_balance(mount_point: impl AsRef<Path>) -> BlockResult<()> {
+    btrfs_command(&["balance", "cancel", &mount_point.as_ref().to_string_lossy()])?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BalanceStatus {
+    pub state: OperationState,
+    pub chunks_balanced: Option<u64>,
+    pub chunks_total: Option<u64>,
+    pub percent_left: Option<f64>,
+}
+
+/// Parse `btrfs balance status`, ie: "Balance on '/mnt' is running" then
+/// "2 out of about 10 chunks balanced (3 considered),  80% left"
+pub fn parse_balance_status(output: &str) -> BalanceStatus {
+    let state = if output.contains("No balance found") {
+        OperationState::None
+    } else if output.contains("is paused") {
+        OperationState::Paused
+    } else if output.contains("is running") {
+        OperationState::Running
+    } else {
+        OperationState::Finished
+    };
+    let words: Vec<&str> = output.split_whitespace().collect();
+    let chunks = words
+        .windows(4)
+        .find(|w| w[1] == "out" && w[2] == "of" && w[3] == "about");
+    let chunks_balanced = chunks.and_then(|w| w[0].parse().ok());
+    let chunks_total = words
+        .windows(2)
+        .find(|w| w[0] == "about")
+        .and_then(|w| w[1].parse().ok());
+    let percent_left = words
+        .windows(2)
+        .find(|w| w[1] == "left" && w[0].ends_with('%'))
+        .and_then(|w| w[0].trim_end_matches('%').parse().ok());
+    BalanceStatus {
+        state,
+        chunks_balanced,
+        chunks_total,
+        percent_left,
+    }
+}
+
+/// Progress of the current balance
+pub fn balance_status(mount_point: impl AsRef<Path>) -> BlockResult<BalanceStatus> {
+    // balance status exits with 1 while a balance is running so the output
+    // is parsed regardless of the exit code
+    let output = run_command(
+        "btrfs",
+        &["balance", "status", &mount_point.as_ref().to_string_lossy()],
+    )?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        return Err(BlockUtilsError::new(format!(
+            "btrfs balance status failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(parse_balance_status(&stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    #[test]
+    fn test_ioctl_struct_sizes() {
+        // The sizes are encoded in the ioctl numbers so they have to match
+        // the kernel's exactly
+        assert_eq!(size_of::<BtrfsIoctlVolArgs>(), 4096);
+        assert_eq!(size_of::<BtrfsIoctlVolArgsV2>(), 4096);
+        assert_eq!(size_of::<BtrfsIoctlInoLookupArgs>(), 4096);
+        assert_eq!(size_of::<BtrfsIoctlFsInfoArgs>(), 1024);
+        assert_eq!(size_of::<BtrfsIoctlDevInfoArgs>(), 4096);
+        assert_eq!(size_of::<BtrfsIoctlGetDevStats>(), 1032);
+
+        let mut name = [0u8; 8];
+        assert!(copy_name(&mut name, OsStr::new("1234567")).is_ok());
+        assert!(copy_name(&mut name, OsStr::new("12345678")).is_err());
+        assert_eq!(
+            split_path(Path::new("snap")).unwrap(),
+            (PathBuf::from("."), OsString::from("snap"))
+        );
+    }
+
+    #[test]
+    fn test_parse_subvolume_list() {
+        let subvolumes = parse_subvolume_list(
+            "ID 256 gen 20 parent 5 top level 5 parent_uuid - \
+             uuid 6a3c1f2e-8d4b-4e5a-9f10-2b3c4d5e6f70 path @\n\
+             ID 258 gen 22 parent 256 top level 5 parent_uuid 6a3c1f2e-8d4b-4e5a-9f10-2b3c4d5e6f70 \
+             uuid 0e1f2a3b-4c5d-4e6f-8a9b-0c1d2e3f4a5b path @/.snapshots/before upgrade\n",
+        )
+        .unwrap();
+        assert_eq!(subvolumes.len(), 2);
+        assert_eq!(subvolumes[0].id, 256);
+        assert_eq!(subvolumes[0].top_level, 5);
+        assert!(!subvolumes[0].is_snapshot());
+        assert_eq!(subvolumes[1].parent_id, 256);
+        assert_eq!(
+            subvolumes[1].path,
+            PathBuf::from("@/.snapshots/before upgrade")
+        );
+        assert_eq!(subvolumes[1].parent_uuid, subvolumes[0].uuid);
+    }
+
+    #[test]
+    fn test_parse_operation_status() {
+        let scrub = parse_scrub_status(
+            "UUID:             5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b\n\
+             Scrub started:    Sun Oct 18 10:00:00 2026\n\
+             Status:           running\n\
+             Duration:         0:00:05\n\
+             \tdata_extents_scrubbed: 72\n\
+             \tdata_bytes_scrubbed: 4575232\n\
+             \ttree_bytes_scrubbed: 720896\n\
+             \tread_errors: 0\n\
+             \tcsum_errors: 2\n\
+             \tcorrected_errors: 2\n\
+             \tuncorrectable_errors: 0\n",
+        );
+        assert_eq!(scrub.state, OperationState::Running);
+        assert_eq!(scrub.bytes_scrubbed, 4575232 + 720896);
+        assert_eq!(scrub.csum_errors, 2);
+        assert_eq!(scrub.counters["data_extents_scrubbed"], 72);
+
+        let balance = parse_balance_status(
+            "Balance on '/mnt' is running\n\
+             2 out of about 10 chunks balanced (3 considered),  80% left\n",
+        );
+        assert_eq!(balance.state, OperationState::Running);
+        assert_eq!(balance.chunks_balanced, Some(2));
+        assert_eq!(balance.chunks_total, Some(10));
+        assert_eq!(balance.percent_left, Some(80.0));
+        assert_eq!(
+            parse_balance_status("No balance found on '/mnt'\n").state,
+            OperationState::None
+        );
+
+        let filters = BalanceFilters {
+            data_usage: Some(50),
+            convert_metadata: Some(MetadataProfile::Raid1),
+            ..Default::default()
+        };
+        assert_eq!(filters.args(), vec!["-dusage=50", "-mconvert=raid1"]);
+
+        let replace = parse_replace_status("45.6% done, 0 write errs, 0 uncorr. read errs\n");
+        assert_eq!(replace.state, OperationState::Running);
+        assert_eq!(replace.percent_done, Some(45.6));
+        assert_eq!(
+            parse_replace_status("Never started\n").state,
+            OperationState::None
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! cgroup v2 I/O controls.
+//!
+//! The io controller takes per device settings keyed by the major:minor of a
+//! whole disk.  The functions here take a `Device` and resolve its device
+//! number, using the parent disk for partitions since the kernel refuses
+//! limits on partitions.
+use crate::{BlockResult, BlockUtilsError, DevNum, Device};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Throttling limits from io.max.  None means unlimited
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IoMax {
+    /// Read bytes per second
+    pub rbps: Option<u64>,
+    /// Write bytes per second
+    pub wbps: Option<u64>,
+    /// Read operations per second
+    pub riops: Option<u64>,
+    /// Write operations per second
+    pub wiops: Option<u64>,
+}
+
+impl fmt::Display for IoMax {
+    /// Format as the key=value list io.max expects, ie: "rbps=1048576 wbps=max riops=max wiops=max"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |v: Option<u64>| v.map_or_else(|| "max".to_string(), |v| v.to_string());
+        write!(
+            f,
+            "rbps={} wbps={} riops={} wiops={}",
+            value(self.rbps),
+            value(self.wbps),
+            value(self.riops),
+            value(self.wiops)
+        )
+    }
+}
+
+/// Counters for one device from io.stat
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IoStat {
+    pub devnum: Option<DevNum>,
+    pub rbytes: u64,
+    pub wbytes: u64,
+    pub rios: u64,
+    pub wios: u64,
+    pub dbytes: u64,
+    pub dios: u64,
+    /// Keys added by other io controllers, ie: blk-iolatency's avg_lat
+    pub extra: BTreeMap<String, String>,
+}
+
+/// A cgroup in the unified hierarchy
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// A cgroup by its path in the hierarchy, like
+    /// "system.slice/ceph-osd@0.service" or the "/system.slice/ceph-osd@0.service"
+    /// form in /proc/<pid>/cgroup.  Both are taken relative to /sys/fs/cgroup.
+    pub fn new(path: impl AsRef<Path>) -> BlockResult<Self> {
+        Cgroup::under(Path::new(CGROUP_ROOT), path.as_ref())
+    }
+
+    fn under(root: &Path, path: &Path) -> BlockResult<Self> {
+        let mut full = root.to_path_buf();
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(name) => full.push(name),
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(BlockUtilsError::new(format!(
+                        "Invalid cgroup path {}.  It must stay below {}",
+                        path.display(),
+                        root.display()
+                    )))
+                }
+            }
+        }
+        Ok(Cgroup { path: full })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Create the cgroup directory if needed and enable the io controller in
+    /// its parent so the io.* files show up
+    pub fn create(&self) -> BlockResult<()> {
+        if let Some(parent) = self.path.parent() {
+            let controllers = fs::read_to_string(parent.join("cgroup.controllers"))?;
+            if !controllers.split_whitespace().any(|c| c == "io") {
+                return Err(BlockUtilsError::new(format!(
+                    "The io controller is not available in {}",
+                    parent.display()
+                )));
+            }
+            let subtree = fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+            if !subtree.split_whitespace().any(|c| c == "io") {
+                fs::write(parent.join("cgroup.subtree_control"), "+io")?;
+            }
+        }
+        if !self.path.exists() {
+            fs::create_dir(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// The io.max limits for `device`.  All None when no limit is set
+    pub fn io_max(&self, device: &Device) -> BlockResult<IoMax> {
+        self.io_max_for(disk_devnum(device)?)
+    }
+
+    pub fn set_io_max(&self, device: &Device, limits: &IoMax) -> BlockResult<()> {
+        self.set_io_max_for(disk_devnum(device)?, limits)
+    }
+
+    /// The io.weight of `device`, or the cgroup's default weight if there's
+    /// no per device override
+    pub fn io_weight(&self, device: &Device) -> BlockResult<u16> {
+        self.io_weight_for(disk_devnum(device)?)
+    }
+
+    /// Set the proportional weight (1-10000) of `device`.  None reverts it
+    /// to the cgroup's default weight
+    pub fn set_io_weight(&self, device: &Device, weight: Option<u16>) -> BlockResult<()> {
+        self.set_io_weight_for(disk_devnum(device)?, weight)
+    }
+
+    /// The io.latency target of `device` in microseconds
+    pub fn io_latency(&self, device: &Device) -> BlockResult<Option<u64>> {
+        self.io_latency_for(disk_devnum(device)?)
+    }
+
+    /// Set the io.latency target of `device` in microseconds.  None removes it
+    pub fn set_io_latency(&self, device: &Device, target_us: Option<u64>) -> BlockResult<()> {
+        self.set_io_latency_for(disk_devnum(device)?, target_us)
+    }
+
+    /// io.stat counters of `device`.  None if the cgroup hasn't done any
+    /// I/O to it yet
+    pub fn io_stat(&self, device: &Device) -> BlockResult<Option<IoStat>> {
+        let devnum = disk_devnum(device)?;
+        Ok(self
+            .io_stats()?
+            .into_iter()
+            .find(|s| s.devnum == Some(devnum)))
+    }
+
+    /// io.stat counters of every device
+    pub fn io_stats(&self) -> BlockResult<Vec<IoStat>> {
+        parse_io_stat(&self.read("io.stat")?)
+    }
+
+    fn io_max_for(&self, devnum: DevNum) -> BlockResult<IoMax> {
+        let io_max = self.read("io.max")?;
+        let line = match find_device_line(&io_max, devnum) {
+            Some(line) => line,
+            None => return Ok(IoMax::default()),
+        };
+        let mut limits = IoMax::default();
+        for (key, value) in line.filter_map(|kv| kv.split_once('=')) {
+            let value = match value {
+                "max" => None,
+                v => Some(v.parse::<u64>()?),
+            };
+            match key {
+                "rbps" => limits.rbps = value,
+                "wbps" => limits.wbps = value,
+                "riops" => limits.riops = value,
+                "wiops" => limits.wiops = value,
+                _ => {}
+            }
+        }
+        Ok(limits)
+    }
+
+    fn set_io_max_for(&self, devnum: DevNum, limits: &IoMax) -> BlockResult<()> {
+        self.write("io.max", &format!("{} {}", devnum, limits))
+    }
+
+    fn io_weight_for(&self, devnum: DevNum) -> BlockResult<u16> {
+        let io_weight = self.read("io.weight")?;
+        if let Some(mut line) = find_device_line(&io_weight, devnum) {
+            if let Some(weight) = line.next() {
+                return Ok(weight.parse::<u16>()?);
+            }
+        }
+        io_weight
+            .lines()
+            .find_map(|l| l.strip_prefix("default "))
+            .ok_or_else(|| BlockUtilsError::new(format!("No default weight in {:?}", io_weight)))?
+            .trim()
+            .parse::<u16>()
+            .map_err(|e| e.into())
+    }
+
+    fn set_io_weight_for(&self, devnum: DevNum, weight: Option<u16>) -> BlockResult<()> {
+        match weight {
+            Some(w) if !(1..=10000).contains(&w) => Err(BlockUtilsError::new(format!(
+                "io.weight must be between 1 and 10000.  Got {}",
+                w
+            ))),
+            Some(w) => self.write("io.weight", &format!("{} {}", devnum, w)),
+            None => self.write("io.weight", &format!("{} default", devnum)),
+        }
+    }
+
+    fn io_latency_for(&self, devnum: DevNum) -> BlockResult<Option<u64>> {
+        let io_latency = self.read("io.latency")?;
+        let line = match find_device_line(&io_latency, devnum) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        for (key, value) in line.filter_map(|kv| kv.split_once('=')) {
+            if key == "target" && value != "max" {
+                return Ok(Some(value.parse::<u64>()?));
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_io_latency_for(&self, devnum: DevNum, target_us: Option<u64>) -> BlockResult<()> {
+        let target = target_us.map_or_else(|| "max".to_string(), |t| t.to_string());
+        self.write("io.latency", &format!("{} target={}", devnum, target))
+    }
+
+    fn read(&self, file: &str) -> BlockResult<String> {
+        let path = self.path.join(file);
+        fs::read_to_string(&path).map_err(|e| {
+            BlockUtilsError::new(format!(
+                "Unable to read {}: {}.  Is the io controller enabled?",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    fn write(&self, file: &str, value: &str) -> BlockResult<()> {
+        fs::write(self.path.join(file), value)?;
+        Ok(())
+    }
+}
+
+/// The words after the device number on the line for `devnum`
+fn find_device_line(input: &str, devnum: DevNum) -> Option<std::str::SplitWhitespace<'_>> {
+    let prefix = devnum.to_string();
+    input.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() == Some(prefix.as_str()) {
+            Some(words)
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse io.stat, ie: "8:0 rbytes=1024 wbytes=0 rios=2 wios=0 dbytes=0 dios=0"
+pub(crate) fn parse_io_stat(input: &str) -> BlockResult<Vec<IoStat>> {
+    let mut stats = Vec::new();
+    for line in input.lines().filter(|l| !l.trim().is_empty()) {
+        let mut words = line.split_whitespace();
+        let mut stat = IoStat {
+            devnum: words.next().map(|d| d.parse::<DevNum>()).transpose()?,
+            ..Default::default()
+        };
+        for (key, value) in words.filter_map(|kv| kv.split_once('=')) {
+            let counter = match key {
+                "rbytes" => &mut stat.rbytes,
+                "wbytes" => &mut stat.wbytes,
+                "rios" => &mut stat.rios,
+                "wios" => &mut stat.wios,
+                "dbytes" => &mut stat.dbytes,
+                "dios" => &mut stat.dios,
+                _ => {
+                    stat.extra.insert(key.to_string(), value.to_string());
+                    continue;
+                }
+            };
+            *counter = value.parse::<u64>()?;
+        }
+        stats.push(stat);
+    }
+    Ok(stats)
+}
+
+/// Device number of the whole disk `device` lives on
+fn disk_devnum(device: &Device) -> BlockResult<DevNum> {
+    let sys_path = crate::dev_path_to_sys_path(&device.name)?;
+    let disk_path = if sys_path.join("partition").exists() {
+        fs::canonicalize(&sys_path)?
+            .parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or(sys_path)
+    } else if let Some(devnum) = device.devnum {
+        return Ok(devnum);
+    } else {
+        sys_path
+    };
+    fs::read_to_string(disk_path.join("dev"))?.parse::<DevNum>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_io_stat() {
+        let stats = parse_io_stat(
+            "8:0 rbytes=1024 wbytes=4096 rios=2 wios=1 dbytes=0 dios=0 depth=max avg_lat=1500 win=100\n\
+             259:0 rbytes=0 wbytes=0 rios=0 wios=0 dbytes=0 dios=0\n",
+        )
+        .unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].devnum, Some(DevNum { major: 8, minor: 0 }));
+        assert_eq!(stats[0].wbytes, 4096);
+        assert_eq!(stats[0].extra["avg_lat"], "1500");
+        assert!(stats[1].extra.is_empty());
+    }
+
+    #[test]
+    fn test_cgroup_io_files() {
+        let tmp_dir = TempDir::new().unwrap();
+        assert_eq!(
+            Cgroup::new("/system.slice/ceph-osd@0.service")
+                .unwrap()
+                .path(),
+            Path::new("/sys/fs/cgroup/system.slice/ceph-osd@0.service")
+        );
+        assert_eq!(
+            Cgroup::new("system.slice").unwrap().path(),
+            Path::new("/sys/fs/cgroup/system.slice")
+        );
+        assert!(Cgroup::new("system.slice/../../../etc").is_err());
+        let cgroup = Cgroup::under(tmp_dir.path(), Path::new("/")).unwrap();
+        assert_eq!(cgroup.path(), tmp_dir.path());
+        let sda = DevNum { major: 8, minor: 0 };
+        let nvme = DevNum {
+            major: 259,
+            minor: 0,
+        };
+
+        fs::write(
+            tmp_dir.path().join("io.max"),
+            "259:0 rbps=max wbps=1048576 riops=max wiops=500\n",
+        )
+        .unwrap();
+        assert_eq!(cgroup.io_max_for(sda).unwrap(), IoMax::default());
+        let limits = cgroup.io_max_for(nvme).unwrap();
+        assert_eq!(limits.wbps, Some(1_048_576));
+        assert_eq!(limits.wiops, Some(500));
+        cgroup.set_io_max_for(sda, &limits).unwrap();
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("io.max")).unwrap(),
+            "8:0 rbps=max wbps=1048576 riops=max wiops=500"
+        );
+
+        fs::write(tmp_dir.path().join("io.weight"), "default 100\n8:0 250\n").unwrap();
+        assert_eq!(cgroup.io_weight_for(sda).unwrap(), 250);
+        assert_eq!(cgroup.io_weight_for(nvme).unwrap(), 100);
+        assert!(cgroup.set_io_weight_for(sda, Some(0)).is_err());
+        cgroup.set_io_weight_for(sda, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("io.weight")).unwrap(),
+            "8:0 default"
+        );
+
+        fs::write(tmp_dir.path().join("io.latency"), "8:0 target=10000\n").unwrap();
+        assert_eq!(cgroup.io_latency_for(sda).unwrap(), Some(10000));
+        assert_eq!(cgroup.io_latency_for(nvme).unwrap(), None);
+        cgroup.set_io_latency_for(sda, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("io.latency")).unwrap(),
+            "8:0 target=max"
+        );
+    }
+}
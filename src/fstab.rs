@@ -0,0 +1,602 @@
+//! Editing `/etc/fstab`.
+//!
+//! Entries are added, updated and removed by mount point.  Lines this module
+//! doesn't change, including comments and blank lines, are written back
+//! byte for byte.  Devices are referenced by UUID, PARTUUID or LABEL so the
+//! entries survive kernel names changing between boots.
+//!
+//! The same entry can instead be written as a systemd `.mount` unit with
+//! `MountUnit`.
+use crate::maintenance::{escape_path, systemctl, SYSTEMD_UNIT_DIR};
+use crate::{BlockResult, BlockUtilsError, Device};
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const FSTAB_PATH: &str = "/etc/fstab";
+
+/// How an entry names its device (the first fstab field)
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsSpec {
+    /// Filesystem UUID
+    Uuid(String),
+    /// GPT partition UUID
+    PartUuid(String),
+    /// Filesystem label
+    Label(String),
+    /// GPT partition label
+    PartLabel(String),
+    /// A device path or anything else, ie: tmpfs
+    Path(String),
+}
+
+/// Which identifier of a `Device` to reference it by
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecKind {
+    Uuid,
+    PartUuid,
+    Label,
+    PartLabel,
+}
+
+impl FsSpec {
+    /// Reference `device` by `kind`.  Errors if the device doesn't have
+    /// that identifier
+    pub fn for_device(device: &Device, kind: SpecKind) -> BlockResult<Self> {
+        let spec = match kind {
+            SpecKind::Uuid => device
+                .id
+                .map(|id| FsSpec::Uuid(id.hyphenated().to_string())),
+            SpecKind::PartUuid => partition_uuid(device).map(FsSpec::PartUuid),
+            SpecKind::Label => device.label.clone().map(FsSpec::Label),
+            SpecKind::PartLabel => device.partition_label.clone().map(FsSpec::PartLabel),
+        };
+        spec.ok_or_else(|| {
+            BlockUtilsError::new(format!("{} has no {:?} to reference", device.name, kind))
+        })
+    }
+
+    /// The device node path systemd and udev use for this spec
+    pub fn device_path(&self) -> PathBuf {
+        let by = |dir: &str, value: &str| {
+            Path::new("/dev/disk")
+                .join(dir)
+                .join(value.replace('/', "\\x2f"))
+        };
+        match self {
+            FsSpec::Uuid(v) => by("by-uuid", v),
+            FsSpec::PartUuid(v) => by("by-partuuid", v),
+            FsSpec::Label(v) => by("by-label", v),
+            FsSpec::PartLabel(v) => by("by-partlabel", v),
+            FsSpec::Path(v) => PathBuf::from(v),
+        }
+    }
+}
+
+impl fmt::Display for FsSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsSpec::Uuid(v) => write!(f, "UUID={}", v),
+            FsSpec::PartUuid(v) => write!(f, "PARTUUID={}", v),
+            FsSpec::Label(v) => write!(f, "LABEL={}", v),
+            FsSpec::PartLabel(v) => write!(f, "PARTLABEL={}", v),
+            FsSpec::Path(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl FromStr for FsSpec {
+    type Err = BlockUtilsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = match s.split_once('=') {
+            Some(("UUID", v)) => FsSpec::Uuid(v.to_string()),
+            Some(("PARTUUID", v)) => FsSpec::PartUuid(v.to_string()),
+            Some(("LABEL", v)) => FsSpec::Label(v.to_string()),
+            Some(("PARTLABEL", v)) => FsSpec::PartLabel(v.to_string()),
+            _ => FsSpec::Path(s.to_string()),
+        };
+        Ok(spec)
+    }
+}
+
+/// One fstab entry
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FstabEntry {
+    pub spec: FsSpec,
+    /// "none" for swap
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub options: Vec<String>,
+    /// dump(8) frequency, almost always 0
+    pub dump: u32,
+    /// fsck order.  0 skips the check, 1 is for the root filesystem
+    pub pass: u32,
+}
+
+impl FstabEntry {
+    /// An entry with the "defaults" options, dump 0 and pass 2
+    pub fn new(spec: FsSpec, mount_point: impl AsRef<Path>, fs_type: &str) -> Self {
+        FstabEntry {
+            spec,
+            mount_point: mount_point.as_ref().to_path_buf(),
+            fs_type: fs_type.to_string(),
+            options: vec!["defaults".to_string()],
+            dump: 0,
+            pass: 2,
+        }
+    }
+
+    /// An entry for `device` using its detected filesystem type
+    pub fn for_device(
+        device: &Device,
+        kind: SpecKind,
+        mount_point: impl AsRef<Path>,
+    ) -> BlockResult<Self> {
+        Ok(FstabEntry::new(
+            FsSpec::for_device(device, kind)?,
+            mount_point,
+            device.fs_type.to_str(),
+        ))
+    }
+
+    pub fn options(mut self, options: &[&str]) -> Self {
+        self.options = options.iter().map(|o| o.to_string()).collect();
+        self
+    }
+
+    pub fn dump(mut self, dump: u32) -> Self {
+        self.dump = dump;
+        self
+    }
+
+    pub fn pass(mut self, pass: u32) -> Self {
+        self.pass = pass;
+        self
+    }
+
+    fn is_swap(&self) -> bool {
+        self.fs_type == "swap"
+    }
+
+    /// Check the entry is complete and its mount point is an existing directory
+    pub fn validate(&self) -> BlockResult<()> {
+        let invalid = |reason: &str| {
+            Err(BlockUtilsError::new(format!(
+                "Invalid fstab entry for {}: {}",
+                self.mount_point.display(),
+                reason
+            )))
+        };
+        if self.fs_type.is_empty() || self.fs_type == "unknown" {
+            return invalid("no filesystem type");
+        }
+        if self.spec.to_string().is_empty() {
+            return invalid("no device");
+        }
+        if !self.is_swap() {
+            if !self.mount_point.is_absolute() {
+                return invalid("the mount point must be an absolute path");
+            }
+            if !self.mount_point.is_dir() {
+                return invalid("the mount point is not an existing directory");
+            }
+        }
+        Ok(())
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0].starts_with('#') {
+            return None;
+        }
+        Some(FstabEntry {
+            spec: unescape(fields[0]).parse().ok()?,
+            mount_point: PathBuf::from(unescape(fields[1])),
+            fs_type: fields[2].to_string(),
+            options: fields
+                .get(3)
+                .map(|o| o.split(',').map(unescape).collect())
+                .unwrap_or_else(|| vec!["defaults".to_string()]),
+            dump: fields.get(4).and_then(|d| d.parse().ok()).unwrap_or(0),
+            pass: fields.get(5).and_then(|p| p.parse().ok()).unwrap_or(0),
+        })
+    }
+
+    /// Render as an fstab line
+    pub fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            escape(&self.spec.to_string()),
+            escape(&self.mount_point.to_string_lossy()),
+            self.fs_type,
+            escape(&self.options.join(",")),
+            self.dump,
+            self.pass
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Line {
+    Entry(FstabEntry, String),
+    Other(String),
+}
+
+/// An fstab file
+#[derive(Clone, Debug)]
+pub struct Fstab {
+    path: PathBuf,
+    lines: Vec<Line>,
+}
+
+impl Fstab {
+    /// Load /etc/fstab
+    pub fn load() -> BlockResult<Self> {
+        Self::load_from(FSTAB_PATH)
+    }
+
+    /// Load an fstab file.  A missing file is an empty fstab.
+    pub fn load_from(path: impl AsRef<Path>) -> BlockResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let contents = if path.exists() {
+            fs::read_to_string(&path)?
+        } else {
+            String::new()
+        };
+        Ok(Fstab {
+            path,
+            lines: contents
+                .lines()
+                .map(|line| match FstabEntry::parse(line) {
+                    Some(entry) => Line::Entry(entry, line.to_string()),
+                    None => Line::Other(line.to_string()),
+                })
+                .collect(),
+        })
+    }
+
+    pub fn entries(&self) -> Vec<&FstabEntry> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                Line::Entry(entry, _) => Some(entry),
+                Line::Other(_) => None,
+            })
+            .collect()
+    }
+
+    /// The entry for `mount_point`.  Swap entries are found by their spec
+    /// with `find_spec`
+    pub fn find(&self, mount_point: impl AsRef<Path>) -> Option<&FstabEntry> {
+        self.entries()
+            .into_iter()
+            .find(|e| e.mount_point == mount_point.as_ref() && !e.is_swap())
+    }
+
+    pub fn find_spec(&self, spec: &FsSpec) -> Option<&FstabEntry> {
+        self.entries().into_iter().find(|e| e.spec == *spec)
+    }
+
+    /// Add `entry` or update the entry with the same mount point (or the
+    /// same spec for swap) in place.  Returns false if an identical entry
+    /// was already present.
+    pub fn set(&mut self, entry: FstabEntry) -> BlockResult<bool> {
+        entry.validate()?;
+        let existing = self.lines.iter_mut().find_map(|l| match l {
+            Line::Entry(e, raw) if Self::same_slot(e, &entry) => Some((e, raw)),
+            _ => None,
+        });
+        match existing {
+            Some((e, _)) if *e == entry => Ok(false),
+            Some((e, raw)) => {
+                *raw = entry.to_line();
+                *e = entry;
+                Ok(true)
+            }
+            None => {
+                let line = entry.to_line();
+                self.lines.push(Line::Entry(entry, line));
+                Ok(true)
+            }
+        }
+    }
+
+    /// Remove the entry for `mount_point`.  Returns true if there was one
+    pub fn remove(&mut self, mount_point: impl AsRef<Path>) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|l| match l {
+            Line::Entry(e, _) => e.mount_point != mount_point.as_ref() || e.is_swap(),
+            Line::Other(_) => true,
+        });
+        before != self.lines.len()
+    }
+
+    /// Remove every entry referencing `spec`.  Returns true if there were any
+    pub fn remove_spec(&mut self, spec: &FsSpec) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|l| match l {
+            Line::Entry(e, _) => e.spec != *spec,
+            Line::Other(_) => true,
+        });
+        before != self.lines.len()
+    }
+
+    fn same_slot(a: &FstabEntry, b: &FstabEntry) -> bool {
+        if a.is_swap() || b.is_swap() {
+            a.spec == b.spec
+        } else {
+            a.mount_point == b.mount_point
+        }
+    }
+
+    /// The file contents
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for line in &self.lines {
+            match line {
+                Line::Entry(_, raw) | Line::Other(raw) => out.push_str(raw),
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Write the file if it changed.  The new contents are written to a
+    /// temporary file next to it and renamed over it so a crash never leaves
+    /// a truncated fstab.  Returns true if anything on disk changed.
+    pub fn save(&self) -> BlockResult<bool> {
+        let contents = self.render();
+        if self.path.exists() && fs::read_to_string(&self.path)? == contents {
+            return Ok(false);
+        }
+        let tmp = self.path.with_extension("block-utils.tmp");
+        fs::write(&tmp, &contents)?;
+        if let Ok(meta) = fs::metadata(&self.path) {
+            fs::set_permissions(&tmp, meta.permissions())?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(true)
+    }
+}
+
+/// A systemd .mount unit equivalent to an fstab entry
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MountUnit {
+    pub entry: FstabEntry,
+}
+
+impl MountUnit {
+    pub fn new(entry: FstabEntry) -> Self {
+        MountUnit { entry }
+    }
+
+    /// systemd requires mount units to be named after their mount point,
+    /// ie: mnt-data.mount for /mnt/data
+    pub fn unit_name(&self) -> String {
+        format!("{}.mount", escape_path(&self.entry.mount_point))
+    }
+
+    /// The unit file contents
+    pub fn render(&self) -> String {
+        let entry = &self.entry;
+        let network = entry.options.iter().any(|o| o == "_netdev");
+        let options: Vec<&str> = entry
+            .options
+            .iter()
+            .map(|o| o.as_str())
+            .filter(|o| *o != "defaults")
+            .collect();
+        let mut unit = format!(
+            "# Managed by block-utils\n\
+             [Unit]\n\
+             Description=Mount {spec} at {mount}\n",
+            spec = escape_specifiers(&entry.spec.to_string()),
+            mount = escape_specifiers(&entry.mount_point.to_string_lossy()),
+        );
+        if entry.pass > 0 {
+            let what = entry.spec.device_path();
+            unit.push_str(&format!(
+                "Requires=systemd-fsck@{dev}.service\n\
+                 After=systemd-fsck@{dev}.service\n",
+                dev = escape_path(&what)
+            ));
+        }
+        unit.push_str(&format!(
+            "\n\
+             [Mount]\n\
+             What={what}\n\
+             Where={mount}\n\
+             Type={fs_type}\n",
+            what = escape_specifiers(&entry.spec.device_path().to_string_lossy()),
+            mount = escape_specifiers(&entry.mount_point.to_string_lossy()),
+            fs_type = entry.fs_type,
+        ));
+        if !options.is_empty() {
+            unit.push_str(&format!("Options={}\n", options.join(",")));
+        }
+        unit.push_str(&format!(
+            "\n\
+             [Install]\n\
+             WantedBy={}\n",
+            if network {
+                "remote-fs.target"
+            } else {
+                "local-fs.target"
+            }
+        ));
+        unit
+    }
+
+    /// Write the unit to /etc/systemd/system, reload systemd and start it.
+    /// Returns the unit path
+    pub fn install(&self) -> BlockResult<PathBuf> {
+        let path = self.write(SYSTEMD_UNIT_DIR)?;
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", &self.unit_name()])?;
+        Ok(path)
+    }
+
+    /// Write the unit file into `unit_dir` if it changed.  Returns its path
+    pub fn write(&self, unit_dir: impl AsRef<Path>) -> BlockResult<PathBuf> {
+        if self.entry.is_swap() {
+            return Err(BlockUtilsError::new(
+                "Swap entries need a .swap unit, not a .mount unit".to_string(),
+            ));
+        }
+        self.entry.validate()?;
+        let path = unit_dir.as_ref().join(self.unit_name());
+        let contents = self.render();
+        if !path.exists() || fs::read_to_string(&path)? != contents {
+            fs::write(&path, contents)?;
+        }
+        Ok(path)
+    }
+}
+
+/// The GPT partition UUID udev found for `device`
+#[cfg(target_os = "linux")]
+fn partition_uuid(device: &Device) -> Option<String> {
+    let sys_path = crate::dev_path_to_sys_path(&device.name).ok()?;
+    let udev_device = udev::Device::from_syspath(&sys_path).ok()?;
+    crate::get_udev_property(&udev_device, "ID_PART_ENTRY_UUID")
+}
+
+/// The GPT partition UUID whose `/dev/disk/by-partuuid` link points at
+/// `device`
+#[cfg(not(target_os = "linux"))]
+fn partition_uuid(device: &Device) -> Option<String> {
+    fs::read_dir("/dev/disk/by-partuuid")
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|entry| {
+            fs::read_link(entry.path())
+                .map(|target| target.file_name() == Some(device.name.as_ref()))
+                .unwrap_or(false)
+        })
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+}
+
+/// systemd takes What= and Where= verbatim, without unquoting, but expands
+/// % specifiers in them
+fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// fstab escapes whitespace and backslashes in fields as octal
+fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            ' ' => out.push_str("\\040"),
+            '\t' => out.push_str("\\011"),
+            '\n' => out.push_str("\\012"),
+            '\\' => out.push_str("\\134"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(field: &str) -> String {
+    crate::mountinfo::unescape_str(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const FSTAB: &str = "# /etc/fstab: static file system information.
+#
+# <file system> <mount point>   <type>  <options>       <dump>  <pass>
+UUID=0b8e5f6a-7d37-4a57-9f0c-6ad9a7a6b0f1 /               ext4    errors=remount-ro 0       1
+
+LABEL=swap      none    swap    sw      0       0
+/dev/sdb1       /mnt/old\\040data xfs defaults 0 2
+";
+
+    #[test]
+    fn test_fstab_edit() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("fstab");
+        fs::write(&path, FSTAB).unwrap();
+        let data = tmp_dir.path().join("data");
+        fs::create_dir(&data).unwrap();
+
+        let mut fstab = Fstab::load_from(&path).unwrap();
+        assert_eq!(fstab.entries().len(), 3);
+        assert_eq!(fstab.render(), FSTAB);
+        assert_eq!(fstab.find("/").unwrap().pass, 1);
+        assert_eq!(
+            fstab.find("/mnt/old data").unwrap().spec,
+            FsSpec::Path("/dev/sdb1".into())
+        );
+        assert!(fstab.find_spec(&FsSpec::Label("swap".into())).is_some());
+        // Saving without changes leaves the file alone
+        assert!(!fstab.save().unwrap());
+
+        let entry = FstabEntry::new(FsSpec::PartUuid("1c2d-01".into()), &data, "xfs")
+            .options(&["noatime", "nofail"]);
+        assert!(fstab.set(entry.clone()).unwrap());
+        assert!(!fstab.set(entry.clone()).unwrap());
+        assert!(fstab.set(entry.clone().pass(0)).unwrap());
+        assert_eq!(fstab.entries().len(), 4);
+        assert!(fstab.save().unwrap());
+
+        let mut loaded = Fstab::load_from(&path).unwrap();
+        assert_eq!(loaded.find(&data), Some(&entry.clone().pass(0)));
+        assert!(loaded.render().starts_with("# /etc/fstab"));
+        assert!(loaded.remove("/mnt/old data"));
+        assert!(!loaded.remove("/mnt/old data"));
+        assert!(loaded.remove_spec(&FsSpec::PartUuid("1c2d-01".into())));
+        assert!(loaded.save().unwrap());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            FSTAB.replace("/dev/sdb1       /mnt/old\\040data xfs defaults 0 2\n", "")
+        );
+
+        let missing = FstabEntry::new(FsSpec::Uuid("x".into()), "/does/not/exist", "xfs");
+        assert!(loaded.set(missing).is_err());
+    }
+
+    #[test]
+    fn test_mount_unit() {
+        let tmp_dir = TempDir::new().unwrap();
+        let entry = FstabEntry::new(
+            FsSpec::Uuid("0b8e5f6a-7d37-4a57-9f0c-6ad9a7a6b0f1".into()),
+            tmp_dir.path(),
+            "xfs",
+        )
+        .options(&["noatime", "_netdev"]);
+        let unit = MountUnit::new(entry.clone());
+        assert_eq!(
+            unit.unit_name(),
+            format!("{}.mount", escape_path(tmp_dir.path()))
+        );
+        let rendered = unit.render();
+        assert!(rendered.contains("What=/dev/disk/by-uuid/0b8e5f6a-7d37-4a57-9f0c-6ad9a7a6b0f1\n"));
+        assert!(rendered.contains("Type=xfs\nOptions=noatime,_netdev\n"));
+        assert!(rendered.contains(
+            "Requires=systemd-fsck@dev-disk-by\\x2duuid-0b8e5f6a\\x2d7d37\\x2d4a57\\x2d9f0c\\x2d6ad9a7a6b0f1.service"
+        ));
+        assert!(rendered.contains("WantedBy=remote-fs.target"));
+
+        let path = unit.write(tmp_dir.path()).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), rendered);
+
+        let spaced = FstabEntry::new(FsSpec::Label("data".into()), "/mnt/my data%1", "xfs");
+        let rendered = MountUnit::new(spaced).render();
+        assert!(rendered.contains("What=/dev/disk/by-label/data\nWhere=/mnt/my data%%1\n"));
+        assert!(rendered.contains("Description=Mount LABEL=data at /mnt/my data%%1\n"));
+
+        let swap = FstabEntry::new(FsSpec::Label("swap".into()), "none", "swap");
+        assert!(MountUnit::new(swap).write(tmp_dir.path()).is_err());
+        assert_eq!(escape("a b\\c"), "a\\040b\\134c");
+    }
+}
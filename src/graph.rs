@@ -0,0 +1,462 @@
+//! Block device dependency graph built from sysfs.
+//!
+//! The kernel records how block devices are stacked on top of each other in
+//! `/sys/class/block/<dev>/holders` (devices built on top of `<dev>`) and
+//! `/sys/class/block/<dev>/slaves` (devices `<dev>` is built from).  Together
+//! with the partition layout this is enough to answer questions like "what is
+//! built on top of sda" or "which physical disks back dm-3".
+use crate::mountinfo::{MountTable, MOUNTINFO_PATH};
+use crate::{BlockResult, BlockUtilsError, DevNum};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, read_dir};
+use std::path::{Path, PathBuf};
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+
+/// What kind of block device a node in the graph is
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Disk,
+    Partition,
+    /// Software raid device
+    MdRaid,
+    /// dm-crypt (LUKS or plain) mapping
+    DmCrypt,
+    /// LVM logical volume
+    Lvm,
+    /// dm-multipath mapping
+    Multipath,
+    /// Any other device-mapper target
+    DeviceMapper,
+    Bcache,
+    Loopback,
+}
+
+/// A single block device and its direct relationships
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceNode {
+    /// Kernel name, ie: sda, sda1, dm-3
+    pub name: String,
+    pub kind: NodeKind,
+    /// major:minor of the device
+    pub devnum: Option<DevNum>,
+    /// The device-mapper name if this is a dm device, ie: vg0-root
+    pub dm_name: Option<String>,
+    /// The disk this partition lives on if this node is a partition
+    pub parent: Option<String>,
+    /// Partitions of this disk
+    pub partitions: Vec<String>,
+    /// Devices directly built on top of this device
+    pub holders: Vec<String>,
+    /// Devices this device is directly built from
+    pub slaves: Vec<String>,
+    /// Filesystem type if the device is mounted
+    pub fs_type: Option<String>,
+    /// Places this device is mounted at
+    pub mountpoints: Vec<PathBuf>,
+}
+
+impl DeviceNode {
+    /// Devices directly on top of this one: partitions first, then holders
+    pub fn children(&self) -> impl Iterator<Item = &String> {
+        self.partitions.iter().chain(self.holders.iter())
+    }
+
+    /// Devices directly underneath this one: the partition's disk or the slaves
+    pub fn lower(&self) -> impl Iterator<Item = &String> {
+        self.parent.iter().chain(self.slaves.iter())
+    }
+}
+
+/// Every block device on the system and how they are stacked
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceGraph {
+    nodes: BTreeMap<String, DeviceNode>,
+}
+
+impl DeviceGraph {
+    /// Scan `/sys/class/block` and `/proc/self/mountinfo` and build the graph
+    pub fn new() -> BlockResult<Self> {
+        Self::from_paths(Path::new(SYS_CLASS_BLOCK), Path::new(MOUNTINFO_PATH))
+    }
+
+    pub(crate) fn from_paths(sys_class_block: &Path, mountinfo: &Path) -> BlockResult<Self> {
+        let mounts = if mountinfo.exists() {
+            read_mounts_by_devnum(&fs::read_to_string(mountinfo)?)?
+        } else {
+            HashMap::new()
+        };
+        let mut nodes = BTreeMap::new();
+        for entry in read_dir(sys_class_block)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let mut node = read_node(&entry.path(), name)?;
+            if let Some((fs_type, mountpoints)) =
+                node.devnum.as_ref().and_then(|devnum| mounts.get(devnum))
+            {
+                node.fs_type = Some(fs_type.clone());
+                node.mountpoints = mountpoints.clone();
+            }
+            nodes.insert(node.name.clone(), node);
+        }
+
+        // Partitions only know their parent, fill in the reverse direction
+        let partitions: Vec<(String, String)> = nodes
+            .values()
+            .filter_map(|n| n.parent.clone().map(|p| (p, n.name.clone())))
+            .collect();
+        for (parent, partition) in partitions {
+            if let Some(node) = nodes.get_mut(&parent) {
+                node.partitions.push(partition);
+            }
+        }
+        for node in nodes.values_mut() {
+            node.partitions.sort();
+        }
+
+        Ok(DeviceGraph { nodes })
+    }
+
+    /// Look up a device by kernel name (sda) or device path (/dev/sda)
+    pub fn node(&self, device: impl AsRef<Path>) -> Option<&DeviceNode> {
+        let name = device.as_ref().file_name()?.to_string_lossy();
+        self.nodes.get(name.as_ref())
+    }
+
+    /// All the devices in the graph ordered by name
+    pub fn nodes(&self) -> impl Iterator<Item = &DeviceNode> {
+        self.nodes.values()
+    }
+
+    fn lookup(&self, device: impl AsRef<Path>) -> BlockResult<&DeviceNode> {
+        self.node(&device).ok_or_else(|| {
+            BlockUtilsError::new(format!(
+                "Unable to find block device {} in {}",
+                device.as_ref().display(),
+                SYS_CLASS_BLOCK
+            ))
+        })
+    }
+
+    /// Every device built on top of `device`, directly or indirectly.
+    /// For sda this includes its partitions and anything stacked on them.
+    pub fn dependents(&self, device: impl AsRef<Path>) -> BlockResult<Vec<String>> {
+        let node = self.lookup(device)?;
+        Ok(self.walk(node, |n| Box::new(n.children())))
+    }
+
+    /// Every device `device` is built from, directly or indirectly
+    pub fn dependencies(&self, device: impl AsRef<Path>) -> BlockResult<Vec<String>> {
+        let node = self.lookup(device)?;
+        Ok(self.walk(node, |n| Box::new(n.lower())))
+    }
+
+    /// The whole disks at the bottom of the stack backing `device`.
+    /// A disk with nothing underneath it returns itself.
+    pub fn backing_disks(&self, device: impl AsRef<Path>) -> BlockResult<Vec<String>> {
+        let node = self.lookup(device)?;
+        let mut disks: Vec<String> = self
+            .walk(node, |n| Box::new(n.lower()))
+            .into_iter()
+            .chain(std::iter::once(node.name.clone()))
+            .filter(|name| match self.nodes.get(name) {
+                Some(n) => n.parent.is_none() && n.slaves.is_empty(),
+                None => false,
+            })
+            .collect();
+        disks.sort();
+        disks.dedup();
+        Ok(disks)
+    }
+
+    /// A device is safe to wipe when `safety::find_blockers` finds nothing
+    /// using it or any of its partitions.
+    pub fn is_safe_to_wipe(&self, device: impl AsRef<Path>) -> BlockResult<bool> {
+        let node = self.lookup(device)?;
+        Ok(crate::safety::graph_blockers(self, Path::new(&node.name))?.is_empty())
+    }
+
+    /// Render the stack built on top of `device` as a tree, ie:
+    ///
+    /// ```text
+    /// sda [disk]
+    /// └─sda1 [partition]
+    ///   └─md0 [md_raid]
+    ///     └─dm-0 [dm_crypt] luks-md0
+    ///       └─dm-1 [lvm] vg0-root xfs /
+    /// ```
+    pub fn render(&self, device: impl AsRef<Path>) -> BlockResult<String> {
+        let node = self.lookup(device)?;
+        let mut out = String::new();
+        let mut visited = BTreeSet::new();
+        self.render_node(node, "", "", &mut visited, &mut out);
+        Ok(out)
+    }
+
+    fn render_node(
+        &self,
+        node: &DeviceNode,
+        prefix: &str,
+        child_prefix: &str,
+        visited: &mut BTreeSet<String>,
+        out: &mut String,
+    ) {
+        let _ = write!(out, "{}{} [{}]", prefix, node.name, node.kind);
+        if let Some(ref dm_name) = node.dm_name {
+            let _ = write!(out, " {}", dm_name);
+        }
+        if let Some(ref fs_type) = node.fs_type {
+            let _ = write!(out, " {}", fs_type);
+        }
+        for mountpoint in &node.mountpoints {
+            let _ = write!(out, " {}", mountpoint.display());
+        }
+        out.push('\n');
+        // Guard against the same device being reached twice, ie: a raid1
+        // built from two partitions of the same disk
+        if !visited.insert(node.name.clone()) {
+            return;
+        }
+
+        let children: Vec<&DeviceNode> = node
+            .children()
+            .filter_map(|name| self.nodes.get(name))
+            .collect();
+        for (i, child) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let (branch, indent) = if last {
+                ("└─", "  ")
+            } else {
+                ("├─", "│ ")
+            };
+            self.render_node(
+                child,
+                &format!("{}{}", child_prefix, branch),
+                &format!("{}{}", child_prefix, indent),
+                visited,
+                out,
+            );
+        }
+    }
+
+    fn walk<'a, F>(&'a self, start: &'a DeviceNode, next: F) -> Vec<String>
+    where
+        F: Fn(&'a DeviceNode) -> Box<dyn Iterator<Item = &'a String> + 'a>,
+    {
+        let mut seen = BTreeSet::new();
+        let mut stack: Vec<&String> = next(start).collect();
+        while let Some(name) = stack.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            if let Some(node) = self.nodes.get(name) {
+                stack.extend(next(node));
+            }
+        }
+        seen.into_iter().collect()
+    }
+}
+
+impl std::fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match *self {
+            NodeKind::Disk => "disk",
+            NodeKind::Partition => "partition",
+            NodeKind::MdRaid => "md_raid",
+            NodeKind::DmCrypt => "dm_crypt",
+            NodeKind::Lvm => "lvm",
+            NodeKind::Multipath => "multipath",
+            NodeKind::DeviceMapper => "device_mapper",
+            NodeKind::Bcache => "bcache",
+            NodeKind::Loopback => "loopback",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn read_trimmed(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn read_dir_names(path: impl AsRef<Path>) -> BlockResult<Vec<String>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut names = read_dir(path)?
+        .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<String>, _>>()?;
+    names.sort();
+    Ok(names)
+}
+
+fn read_node(sys_path: &Path, name: String) -> BlockResult<DeviceNode> {
+    let dm_uuid = read_trimmed(sys_path.join("dm/uuid"));
+    let is_partition = sys_path.join("partition").exists();
+    // /sys/class/block/sda1 links to .../block/sda/sda1
+    let parent = if is_partition {
+        fs::canonicalize(sys_path)?
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|p| p.to_string_lossy().into_owned())
+    } else {
+        None
+    };
+    let kind = if is_partition {
+        NodeKind::Partition
+    } else if let Some(ref uuid) = dm_uuid {
+        dm_kind(uuid)
+    } else if sys_path.join("md").exists() || name.starts_with("md") {
+        NodeKind::MdRaid
+    } else if name.starts_with("bcache") {
+        NodeKind::Bcache
+    } else if name.starts_with("loop") {
+        NodeKind::Loopback
+    } else if name.starts_with("dm-") {
+        NodeKind::DeviceMapper
+    } else {
+        NodeKind::Disk
+    };
+
+    Ok(DeviceNode {
+        devnum: read_trimmed(sys_path.join("dev")).and_then(|dev| dev.parse::<DevNum>().ok()),
+        dm_name: read_trimmed(sys_path.join("dm/name")),
+        parent,
+        partitions: vec![],
+        holders: read_dir_names(sys_path.join("holders"))?,
+        slaves: read_dir_names(sys_path.join("slaves"))?,
+        fs_type: None,
+        mountpoints: vec![],
+        name,
+        kind,
+    })
+}
+
+/// Device-mapper targets prefix their uuid with the subsystem that created them
+fn dm_kind(dm_uuid: &str) -> NodeKind {
+    if dm_uuid.starts_with("CRYPT-") {
+        NodeKind::DmCrypt
+    } else if dm_uuid.starts_with("LVM-") {
+        NodeKind::Lvm
+    } else if dm_uuid.starts_with("mpath-") {
+        NodeKind::Multipath
+    } else {
+        NodeKind::DeviceMapper
+    }
+}
+
+/// Map major:minor to the filesystem type and mountpoints from mountinfo
+fn read_mounts_by_devnum(mountinfo: &str) -> BlockResult<HashMap<DevNum, (String, Vec<PathBuf>)>> {
+    let mut mounts: HashMap<DevNum, (String, Vec<PathBuf>)> = HashMap::new();
+    for mount in MountTable::parse(mountinfo)?.entries {
+        let entry = mounts
+            .entry(mount.devnum)
+            .or_insert_with(|| (mount.fs_type.clone(), vec![]));
+        entry.1.push(mount.mount_point);
+    }
+    Ok(mounts)
+}
+
+/// Devices directly built on top of the device at `dev_path` (like "/dev/sda1")
+pub fn get_holders(dev_path: impl AsRef<Path>) -> BlockResult<Vec<String>> {
+    Ok(DeviceGraph::new()?.lookup(dev_path)?.holders.clone())
+}
+
+/// Devices the device at `dev_path` (like "/dev/dm-3") is directly built from
+pub fn get_slaves(dev_path: impl AsRef<Path>) -> BlockResult<Vec<String>> {
+    Ok(DeviceGraph::new()?.lookup(dev_path)?.slaves.clone())
+}
+
+/// Checks that nothing is using the device at `dev_path` or its partitions.
+/// See `safety::find_blockers` for what counts as using it
+pub fn is_safe_to_wipe(dev_path: impl AsRef<Path>) -> BlockResult<bool> {
+    DeviceGraph::new()?.is_safe_to_wipe(dev_path)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// Add a fake sysfs device at `root`/devices/`devpath` and link it into
+    /// `root`/class
+    pub(crate) fn add_device(root: &Path, devpath: &str, devnum: &str) -> PathBuf {
+        let dev_dir = root.join("devices").join(devpath);
+        fs::create_dir_all(dev_dir.join("holders")).unwrap();
+        fs::create_dir_all(dev_dir.join("slaves")).unwrap();
+        fs::write(dev_dir.join("dev"), format!("{}\n", devnum)).unwrap();
+        let name = Path::new(devpath).file_name().unwrap();
+        symlink(&dev_dir, root.join("class").join(name)).unwrap();
+        dev_dir
+    }
+
+    fn stack(dev_dir: &Path, lower_dir: &Path, lower: &str, upper: &str) {
+        symlink(dev_dir, lower_dir.join("holders").join(upper)).unwrap();
+        symlink(lower_dir, dev_dir.join("slaves").join(lower)).unwrap();
+    }
+
+    #[test]
+    fn test_device_graph() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("class")).unwrap();
+
+        add_device(root, "sda", "8:0");
+        let sda1 = add_device(root, "sda/sda1", "8:1");
+        fs::write(sda1.join("partition"), "1\n").unwrap();
+        let sdb = add_device(root, "sdb", "8:16");
+        let md0 = add_device(root, "md0", "9:0");
+        fs::create_dir_all(md0.join("md")).unwrap();
+        let dm0 = add_device(root, "dm-0", "253:0");
+        fs::create_dir_all(dm0.join("dm")).unwrap();
+        fs::write(dm0.join("dm/uuid"), "CRYPT-LUKS2-abcd-luks-md0\n").unwrap();
+        fs::write(dm0.join("dm/name"), "luks-md0\n").unwrap();
+        let dm1 = add_device(root, "dm-1", "253:1");
+        fs::create_dir_all(dm1.join("dm")).unwrap();
+        fs::write(dm1.join("dm/uuid"), "LVM-abcdef\n").unwrap();
+        fs::write(dm1.join("dm/name"), "vg0-root\n").unwrap();
+
+        stack(&md0, &sda1, "sda1", "md0");
+        stack(&md0, &sdb, "sdb", "md0");
+        stack(&dm0, &md0, "md0", "dm-0");
+        stack(&dm1, &dm0, "dm-0", "dm-1");
+
+        let mountinfo = root.join("mountinfo");
+        fs::write(
+            &mountinfo,
+            "22 1 253:1 / / rw,relatime shared:1 - xfs /dev/mapper/vg0-root rw\n",
+        )
+        .unwrap();
+
+        let graph = DeviceGraph::from_paths(&root.join("class"), &mountinfo).unwrap();
+        assert_eq!(graph.node("sda1").unwrap().kind, NodeKind::Partition);
+        assert_eq!(graph.node("sda1").unwrap().parent, Some("sda".to_string()));
+        assert_eq!(graph.node("/dev/dm-0").unwrap().kind, NodeKind::DmCrypt);
+        assert_eq!(graph.node("dm-1").unwrap().kind, NodeKind::Lvm);
+        assert_eq!(
+            graph.dependents("/dev/sda").unwrap(),
+            vec!["dm-0", "dm-1", "md0", "sda1"]
+        );
+        assert_eq!(graph.backing_disks("dm-1").unwrap(), vec!["sda", "sdb"]);
+        assert_eq!(graph.backing_disks("sdb").unwrap(), vec!["sdb"]);
+        assert!(!graph.is_safe_to_wipe("sda").unwrap());
+        assert!(!graph.is_safe_to_wipe("dm-1").unwrap());
+        assert_eq!(
+            graph.render("sda").unwrap(),
+            "sda [disk]\n\
+             └─sda1 [partition]\n  \
+               └─md0 [md_raid]\n    \
+                 └─dm-0 [dm_crypt] luks-md0\n      \
+                   └─dm-1 [lvm] vg0-root xfs /\n"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Versioned JSON snapshots of the devices on a host.
+//!
+//! Every public data type in this crate implements `Serialize` and
+//! `Deserialize`.  `Inventory` wraps them with a schema version so data
+//! shipped to another system can be loaded back and diffed later even after
+//! the crate grows new fields.
+//!
+//! Schema version 1 looks like:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "devices": [
+//!     {
+//!       "id": "0b8e5f6a-7d37-4a57-9f0c-6ad9a7a6b0f1",
+//!       "name": "sda",
+//!       "media_type": "rotational",
+//!       "device_type": "disk",
+//!       "capacity": 4000787030016,
+//!       "fs_type": "xfs",
+//!       "label": null,
+//!       "serial_number": "ST4000NM0035_ZC11ABCD",
+//!       "logical_block_size": 512,
+//!       "physical_block_size": 4096,
+//!       "model": "ST4000NM0035",
+//!       "vendor": "ATA",
+//!       "wwn": "0x5000c500a1b2c3d4",
+//!       "wwn_source": "wwn_with_extension",
+//!       "firmware_revision": "TN05",
+//!       "transport": "sata",
+//!       "rotation_rate_rpm": 7200,
+//!       "removable": false,
+//!       "read_only": false,
+//!       "hidden": false,
+//!       "zoned": "none",
+//!       "partition_number": null,
+//!       "partition_label": null,
+//!       "devnum": { "major": 8, "minor": 0 },
+//!       "links": ["/dev/disk/by-id/ata-ST4000NM0035_ZC11ABCD"]
+//!     }
+//!   ],
+//!   "scsi": [
+//!     {
+//!       "block_device": "/dev/sda",
+//!       "enclosure": null,
+//!       "host": "6",
+//!       "channel": 0,
+//!       "id": 1,
+//!       "lun": 0,
+//!       "vendor": "hp",
+//!       "vendor_str": "HP",
+//!       "model": "MB4000JFEPB",
+//!       "rev": "HPD1",
+//!       "state": "running",
+//!       "scsi_type": "direct_access",
+//!       "scsi_revision": 6
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Enums are serialized as snake_case strings.  `FilesystemType` uses the same
+//! name as `FilesystemType::to_str`.  New optional fields may be added without
+//! bumping the version.  Renaming or removing a field bumps it.
+use crate::{BlockResult, BlockUtilsError, Device, ScsiInfo};
+
+use serde::{Deserialize, Serialize};
+
+/// The JSON layout version written by this crate
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A snapshot of devices and scsi information from one host
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Inventory {
+    pub schema_version: u32,
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub scsi: Vec<ScsiInfo>,
+}
+
+impl Inventory {
+    pub fn new(devices: Vec<Device>, scsi: Vec<ScsiInfo>) -> Self {
+        Inventory {
+            schema_version: SCHEMA_VERSION,
+            devices,
+            scsi,
+        }
+    }
+
+    pub fn to_json(&self) -> BlockResult<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Load a snapshot.  Snapshots written by a newer schema are refused
+    /// because fields may have changed meaning.
+    pub fn from_json(json: &str) -> BlockResult<Self> {
+        let inventory: Inventory = serde_json::from_str(json)?;
+        if inventory.schema_version > SCHEMA_VERSION {
+            return Err(BlockUtilsError::new(format!(
+                "Inventory schema version {} is newer than the supported version {}",
+                inventory.schema_version, SCHEMA_VERSION
+            )));
+        }
+        Ok(inventory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DevNum, DeviceType, FilesystemType, MediaType, Transport, ZonedModel};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_inventory_round_trip() {
+        let device = Device {
+            id: None,
+            name: "sda".to_string(),
+            media_type: MediaType::Rotational,
+            device_type: DeviceType::Disk,
+            capacity: 4_000_787_030_016,
+            fs_type: FilesystemType::Lvm,
+            label: None,
+            serial_number: Some("ZC11ABCD".to_string()),
+            logical_block_size: Some(512),
+            physical_block_size: Some(4096),
+            model: Some("ST4000NM0035".to_string()),
+            vendor: Some("ATA".to_string()),
+            wwn: Some("0x5000c500a1b2c3d4".to_string()),
+            wwn_source: Some(crate::WwnSource::WwnWithExtension),
+            firmware_revision: Some("TN05".to_string()),
+            transport: Transport::Sata,
+            rotation_rate_rpm: Some(7200),
+            removable: false,
+            read_only: false,
+            hidden: false,
+            zoned: ZonedModel::HostAware,
+            partition_number: None,
+            partition_label: None,
+            devnum: Some(DevNum { major: 8, minor: 0 }),
+            links: vec![PathBuf::from("/dev/disk/by-id/ata-ST4000NM0035_ZC11ABCD")],
+        };
+        let json = Inventory::new(vec![device], vec![]).to_json().unwrap();
+        assert!(json.contains("\"media_type\": \"rotational\""));
+        assert!(json.contains("\"fs_type\": \"lvm\""));
+        assert!(json.contains("\"zoned\": \"host_aware\""));
+
+        let loaded = Inventory::from_json(&json).unwrap();
+        assert_eq!(loaded.schema_version, SCHEMA_VERSION);
+        assert_eq!(loaded.devices[0].fs_type, FilesystemType::Lvm);
+        assert_eq!(loaded.devices[0].transport, Transport::Sata);
+
+        let newer = json.replace("\"schema_version\": 1", "\"schema_version\": 2");
+        assert!(Inventory::from_json(&newer).is_err());
+    }
+}
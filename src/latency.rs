@@ -0,0 +1,601 @@
+//! Per-device I/O latency distributions.
+//!
+//! `/proc/diskstats` only yields average latency which hides slow outliers.
+//! The kernel exposes finer grained data in a few places:
+//!
+//! * blk-iolatency reports a moving average per cgroup and device in `io.stat`
+//!   once `io.latency` is configured.
+//! * Polled queues keep per request size statistics in debugfs under
+//!   `block/<disk>/poll_stat`.
+//! * The `block_rq_issue` and `block_rq_complete` tracepoints give the exact
+//!   service time of every request.  `TraceCollector` turns those into
+//!   histograms with percentiles.
+use crate::{BlockResult, BlockUtilsError, DevNum};
+
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const DEBUGFS_BLOCK_PATH: &str = "/sys/kernel/debug/block";
+const TRACEFS_PATHS: &[&str] = &["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// One bucket per power of two microseconds, up to about 2^40us (12 days)
+const HISTOGRAM_BUCKETS: usize = 41;
+
+/// Latencies in microseconds grouped into power of two buckets
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    /// buckets[0] counts latencies below 2us, buckets[i] counts
+    /// latencies in [2^i, 2^(i+1)) us
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_us: u64,
+    pub min_us: Option<u64>,
+    pub max_us: Option<u64>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: vec![0; HISTOGRAM_BUCKETS],
+            count: 0,
+            sum_us: 0,
+            min_us: None,
+            max_us: None,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, latency_us: u64) {
+        let bucket = (63 - (latency_us | 1).leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(latency_us);
+        self.min_us = Some(self.min_us.map_or(latency_us, |m| m.min(latency_us)));
+        self.max_us = Some(self.max_us.map_or(latency_us, |m| m.max(latency_us)));
+    }
+
+    /// Add the samples of `other` to this histogram
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (b, o) in self.buckets.iter_mut().zip(&other.buckets) {
+            *b += o;
+        }
+        self.count += other.count;
+        self.sum_us = self.sum_us.saturating_add(other.sum_us);
+        self.min_us = match (self.min_us, other.min_us) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max_us = match (self.max_us, other.max_us) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /// Upper bound in microseconds of the bucket holding the `percentile`
+    /// (0-100) sample, capped at the largest recorded latency.
+    /// None if nothing was recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = (1u64 << (i + 1)) - 1;
+                return Some(self.max_us.map_or(upper, |max| upper.min(max)));
+            }
+        }
+        self.max_us
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            min_us: self.min_us,
+            max_us: self.max_us,
+            mean_us: if self.count > 0 {
+                Some(self.sum_us as f64 / self.count as f64)
+            } else {
+                None
+            },
+            p50_us: self.percentile(50.0),
+            p90_us: self.percentile(90.0),
+            p99_us: self.percentile(99.0),
+            p999_us: self.percentile(99.9),
+        }
+    }
+}
+
+/// Percentiles of a `LatencyHistogram`.  Fields are None when nothing was recorded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min_us: Option<u64>,
+    pub max_us: Option<u64>,
+    pub mean_us: Option<f64>,
+    pub p50_us: Option<u64>,
+    pub p90_us: Option<u64>,
+    pub p99_us: Option<u64>,
+    pub p999_us: Option<u64>,
+}
+
+/// Latency histograms of one device split by request type
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeviceLatency {
+    pub devnum: Option<DevNum>,
+    /// Kernel name, ie: sda.  None if the device went away before it was looked up
+    pub name: Option<String>,
+    pub read: LatencyHistogram,
+    pub write: LatencyHistogram,
+    pub discard: LatencyHistogram,
+}
+
+impl DeviceLatency {
+    /// Reads, writes and discards together
+    pub fn combined(&self) -> LatencyHistogram {
+        let mut all = self.read.clone();
+        all.merge(&self.write);
+        all.merge(&self.discard);
+        all
+    }
+}
+
+/// blk-iolatency state of one device in a cgroup's io.stat
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IoLatencyStat {
+    pub devnum: Option<DevNum>,
+    /// Current queue depth limit.  None means unlimited
+    pub depth: Option<u64>,
+    /// Moving average latency in microseconds.  Reported for rotational disks
+    pub avg_lat_us: Option<u64>,
+    /// Sampling window in milliseconds
+    pub win_ms: Option<u64>,
+    /// Requests that missed the target in the last window.  Reported for ssds
+    pub missed: Option<u64>,
+    pub total: Option<u64>,
+}
+
+/// Read the blk-iolatency statistics of every device in `cgroup`, ie:
+/// "/sys/fs/cgroup/ceph-osd.slice".  Devices without an io.latency target
+/// are skipped.  The kernel only prints these with the
+/// `blkcg_debug_stats` module parameter enabled.
+pub fn get_iolatency_stats(cgroup: impl AsRef<Path>) -> BlockResult<Vec<IoLatencyStat>> {
+    let io_stat = fs::read_to_string(cgroup.as_ref().join("io.stat"))?;
+    parse_iolatency_stats(&io_stat)
+}
+
+fn parse_iolatency_stats(io_stat: &str) -> BlockResult<Vec<IoLatencyStat>> {
+    Ok(crate::cgroup::parse_io_stat(io_stat)?
+        .into_iter()
+        .filter_map(|s| {
+            let num = |key: &str| s.extra.get(key).and_then(|v| v.parse::<u64>().ok());
+            let stat = IoLatencyStat {
+                devnum: s.devnum,
+                depth: num("depth"),
+                avg_lat_us: num("avg_lat"),
+                win_ms: num("win"),
+                missed: num("missed"),
+                total: num("total"),
+            };
+            let found = ["depth", "avg_lat", "win", "missed", "total"]
+                .iter()
+                .any(|k| s.extra.contains_key(*k));
+            if found {
+                Some(stat)
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoDirection {
+    Read,
+    Write,
+}
+
+/// Completion statistics for polled requests of one size.  Times are in
+/// nanoseconds.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PollStat {
+    pub direction: IoDirection,
+    pub request_bytes: u64,
+    pub samples: u64,
+    pub mean_ns: Option<u64>,
+    pub min_ns: Option<u64>,
+    pub max_ns: Option<u64>,
+}
+
+/// Read the polled I/O statistics of `device` from debugfs.  The queue must
+/// have io_poll enabled and debugfs must be mounted.
+pub fn get_poll_stats(device: impl AsRef<Path>) -> BlockResult<Vec<PollStat>> {
+    let queue = crate::queue::queue_dir(&device)?;
+    let disk = fs::canonicalize(&queue)?
+        .parent()
+        .and_then(|p| p.file_name())
+        .map(|n| n.to_os_string())
+        .ok_or_else(|| {
+            BlockUtilsError::new(format!(
+                "Unable to find the disk of {}",
+                device.as_ref().display()
+            ))
+        })?;
+    let poll_stat = Path::new(DEBUGFS_BLOCK_PATH).join(disk).join("poll_stat");
+    parse_poll_stats(&fs::read_to_string(poll_stat)?)
+}
+
+/// Parse lines like "read  (512 Bytes): samples=3, mean=9000, min=8000, max=11000"
+fn parse_poll_stats(input: &str) -> BlockResult<Vec<PollStat>> {
+    let mut stats = Vec::new();
+    for line in input.lines().filter(|l| !l.trim().is_empty()) {
+        let invalid = || BlockUtilsError::new(format!("Invalid poll_stat line: {}", line));
+        let (header, values) = line.split_once("):").ok_or_else(invalid)?;
+        let (direction, size) = header.split_once('(').ok_or_else(invalid)?;
+        let direction = match direction.trim() {
+            "read" => IoDirection::Read,
+            "write" => IoDirection::Write,
+            _ => return Err(invalid()),
+        };
+        let request_bytes = size
+            .trim()
+            .trim_end_matches("Bytes")
+            .trim()
+            .parse::<u64>()?;
+        let mut stat = PollStat {
+            direction,
+            request_bytes,
+            samples: 0,
+            mean_ns: None,
+            min_ns: None,
+            max_ns: None,
+        };
+        for (key, value) in values.split(',').filter_map(|kv| kv.trim().split_once('=')) {
+            let value = value.parse::<u64>()?;
+            match key {
+                "samples" => stat.samples = value,
+                "mean" => stat.mean_ns = Some(value),
+                "min" => stat.min_ns = Some(value),
+                "max" => stat.max_ns = Some(value),
+                _ => {}
+            }
+        }
+        stats.push(stat);
+    }
+    Ok(stats)
+}
+
+#[derive(Debug, PartialEq)]
+enum TraceEvent {
+    Issue,
+    Complete,
+}
+
+#[derive(Debug, PartialEq)]
+struct TraceRecord {
+    event: TraceEvent,
+    timestamp: f64,
+    devnum: DevNum,
+    rwbs: String,
+    sector: u64,
+}
+
+/// Parse a block_rq_issue or block_rq_complete line from trace_pipe, ie:
+/// "fio-1234 [002] d..1 1234.567890: block_rq_issue: 8,0 R 4096 () 123456 + 8 [fio]"
+fn parse_trace_line(line: &str) -> Option<TraceRecord> {
+    let (event, marker) = if let Some(pos) = line.find(" block_rq_issue: ") {
+        (TraceEvent::Issue, pos)
+    } else if let Some(pos) = line.find(" block_rq_complete: ") {
+        (TraceEvent::Complete, pos)
+    } else {
+        return None;
+    };
+    let timestamp = line[..marker]
+        .split_whitespace()
+        .last()?
+        .trim_end_matches(':')
+        .parse::<f64>()
+        .ok()?;
+    let fields: Vec<&str> = line[marker..].split_whitespace().skip(1).collect();
+    let (major, minor) = fields.first()?.split_once(',')?;
+    let devnum = DevNum {
+        major: major.parse().ok()?,
+        minor: minor.parse().ok()?,
+    };
+    let rwbs = fields.get(1)?.to_string();
+    let plus = fields.iter().position(|f| *f == "+")?;
+    let sector = fields.get(plus.checked_sub(1)?)?.parse::<u64>().ok()?;
+    let sectors = fields.get(plus + 1)?.parse::<u64>().ok()?;
+    // Flushes are printed as "0 + 0" and would be paired with whatever
+    // else is in flight at sector 0, leave them out
+    if rwbs.contains('F') || sectors == 0 {
+        return None;
+    }
+    Some(TraceRecord {
+        event,
+        timestamp,
+        devnum,
+        rwbs,
+        sector,
+    })
+}
+
+/// Pairs issue and completion events into per device histograms
+#[derive(Debug, Default)]
+struct TraceAggregator {
+    in_flight: HashMap<(DevNum, u64), f64>,
+    devices: HashMap<DevNum, DeviceLatency>,
+}
+
+impl TraceAggregator {
+    fn add_line(&mut self, line: &str) {
+        let record = match parse_trace_line(line) {
+            Some(r) => r,
+            None => return,
+        };
+        let key = (record.devnum, record.sector);
+        match record.event {
+            TraceEvent::Issue => {
+                self.in_flight.insert(key, record.timestamp);
+            }
+            TraceEvent::Complete => {
+                let issued = match self.in_flight.remove(&key) {
+                    Some(t) => t,
+                    None => return,
+                };
+                let latency_us =
+                    ((record.timestamp - issued).max(0.0) * 1_000_000.0).round() as u64;
+                let device = self
+                    .devices
+                    .entry(record.devnum)
+                    .or_insert_with(|| DeviceLatency {
+                        devnum: Some(record.devnum),
+                        ..Default::default()
+                    });
+                if record.rwbs.contains('D') {
+                    device.discard.record(latency_us);
+                } else if record.rwbs.contains('R') {
+                    device.read.record(latency_us);
+                } else if record.rwbs.contains('W') {
+                    device.write.record(latency_us);
+                }
+            }
+        }
+    }
+}
+
+/// Collects request latencies from the block tracepoints.  A private ftrace
+/// instance is used so other users of the global trace buffer aren't
+/// disturbed.  Requires root and a mounted tracefs.
+#[derive(Clone, Debug)]
+pub struct TraceCollector {
+    tracing_dir: PathBuf,
+    devices: Vec<DevNum>,
+}
+
+impl TraceCollector {
+    /// Find tracefs at /sys/kernel/tracing or under debugfs
+    pub fn new() -> BlockResult<Self> {
+        let tracing_dir = TRACEFS_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|p| p.join("instances").exists())
+            .ok_or_else(|| BlockUtilsError::new("tracefs is not mounted".to_string()))?;
+        Ok(TraceCollector {
+            tracing_dir,
+            devices: vec![],
+        })
+    }
+
+    /// Only trace these devices.  By default every device is traced.
+    pub fn devices(mut self, devices: &[DevNum]) -> Self {
+        self.devices = devices.to_vec();
+        self
+    }
+
+    /// Trace for `duration` and return the latencies of every device that
+    /// completed requests, keyed by kernel name
+    pub fn collect(&self, duration: Duration) -> BlockResult<HashMap<String, DeviceLatency>> {
+        let instance = TraceInstance::create(&self.tracing_dir)?;
+        let filter = self.filter();
+        for event in &["block_rq_issue", "block_rq_complete"] {
+            let event_dir = instance.path.join("events/block").join(event);
+            if !filter.is_empty() {
+                fs::write(event_dir.join("filter"), &filter)?;
+            }
+            fs::write(event_dir.join("enable"), "1")?;
+        }
+        let mut pipe = OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(instance.path.join("trace_pipe"))?;
+
+        let mut aggregator = TraceAggregator::default();
+        let mut pending = String::new();
+        let mut buf = vec![0; 64 * 1024];
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let remaining = (deadline - now).as_millis().min(i32::MAX as u128) as i32;
+            let mut fds = [PollFd::new(pipe.as_raw_fd(), PollFlags::POLLIN)];
+            if poll(&mut fds, remaining)? == 0 {
+                continue;
+            }
+            let n = match pipe.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            };
+            pending.push_str(&String::from_utf8_lossy(&buf[..n]));
+            while let Some(pos) = pending.find('\n') {
+                aggregator.add_line(&pending[..pos]);
+                pending.drain(..=pos);
+            }
+        }
+
+        Ok(aggregator
+            .devices
+            .into_iter()
+            .map(|(devnum, mut latency)| {
+                latency.name = devnum.kernel_name();
+                let key = latency.name.clone().unwrap_or_else(|| devnum.to_string());
+                (key, latency)
+            })
+            .collect())
+    }
+
+    /// The kernel stores dev_t as (major << 20) | minor in the tracepoints
+    fn filter(&self) -> String {
+        self.devices
+            .iter()
+            .map(|d| format!("dev == {}", (d.major << 20) | d.minor))
+            .collect::<Vec<String>>()
+            .join(" || ")
+    }
+}
+
+/// A trace instance directory that is removed when dropped
+struct TraceInstance {
+    path: PathBuf,
+}
+
+impl TraceInstance {
+    fn create(tracing_dir: &Path) -> BlockResult<Self> {
+        let path = tracing_dir
+            .join("instances")
+            .join(format!("block-utils-{}", std::process::id()));
+        fs::create_dir(&path)?;
+        Ok(TraceInstance { path })
+    }
+}
+
+impl Drop for TraceInstance {
+    fn drop(&mut self) {
+        // Removing the instance disables its events and frees the buffer
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut hist = LatencyHistogram::new();
+        assert_eq!(hist.percentile(50.0), None);
+        for _ in 0..98 {
+            hist.record(100);
+        }
+        hist.record(5000);
+        hist.record(20000);
+        let summary = hist.summary();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.min_us, Some(100));
+        assert_eq!(summary.max_us, Some(20000));
+        // 100us lands in the [64, 128) bucket
+        assert_eq!(summary.p50_us, Some(127));
+        assert_eq!(summary.p99_us, Some(8191));
+        assert_eq!(summary.p999_us, Some(20000));
+
+        let mut other = LatencyHistogram::new();
+        other.record(1);
+        hist.merge(&other);
+        assert_eq!(hist.count, 101);
+        assert_eq!(hist.min_us, Some(1));
+    }
+
+    #[test]
+    fn test_parse_iolatency_stats() {
+        let io_stat =
+            "8:0 rbytes=1024 wbytes=0 rios=2 wios=0 dbytes=0 dios=0 depth=max avg_lat=1500 win=100
+8:16 rbytes=4096 wbytes=0 rios=1 wios=0 dbytes=0 dios=0
+259:0 rbytes=0 wbytes=0 rios=0 wios=0 dbytes=0 dios=0 missed=3 total=200 depth=64
+";
+        let stats = parse_iolatency_stats(io_stat).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].depth, None);
+        assert_eq!(stats[0].avg_lat_us, Some(1500));
+        assert_eq!(stats[0].win_ms, Some(100));
+        assert_eq!(
+            stats[1].devnum,
+            Some(DevNum {
+                major: 259,
+                minor: 0
+            })
+        );
+        assert_eq!(stats[1].missed, Some(3));
+        assert_eq!(stats[1].depth, Some(64));
+    }
+
+    #[test]
+    fn test_parse_poll_stats() {
+        let stats = parse_poll_stats(
+            "read  (512 Bytes): samples=3, mean=9000, min=8000, max=11000\nwrite (4096 Bytes): samples=0\n",
+        )
+        .unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].direction, IoDirection::Read);
+        assert_eq!(stats[0].request_bytes, 512);
+        assert_eq!(stats[0].mean_ns, Some(9000));
+        assert_eq!(stats[1].samples, 0);
+        assert_eq!(stats[1].max_ns, None);
+        assert!(parse_poll_stats("garbage").is_err());
+    }
+
+    #[test]
+    fn test_trace_aggregator() {
+        let mut aggregator = TraceAggregator::default();
+        for line in &[
+            "             fio-1234  [002] d..1  100.000100: block_rq_issue: 8,0 R 4096 () 2048 + 8 [fio]",
+            "             fio-1234  [002] d..1  100.000200: block_rq_issue: 8,0 WS 4096 () 4096 + 8 none,0,0 [fio]",
+            "          <idle>-0     [002] ..s1  100.000600: block_rq_complete: 8,0 R () 2048 + 8 [0]",
+            "          <idle>-0     [002] ..s1  100.002200: block_rq_complete: 8,0 WS () 4096 + 8 none,0,0 [0]",
+            "          <idle>-0     [002] ..s1  100.003000: block_rq_complete: 8,0 FF () 0 + 0 [0]",
+            "             fio-1234  [002] d..1  100.003500: block_rq_issue: 8,0 R 4096 () 0 + 8 [fio]",
+            "     kworker/2:1-99    [002] d..1  100.004000: block_rq_issue: 8,0 FF 0 () 0 + 0 [kworker/2:1]",
+            "          <idle>-0     [002] ..s1  100.004500: block_rq_complete: 8,0 FF () 0 + 0 [0]",
+            "          <idle>-0     [002] ..s1  100.004700: block_rq_complete: 8,0 R () 0 + 8 [0]",
+        ] {
+            aggregator.add_line(line);
+        }
+        let sda = &aggregator.devices[&DevNum { major: 8, minor: 0 }];
+        assert_eq!(sda.read.count, 2);
+        assert_eq!(sda.read.max_us, Some(1200));
+        assert_eq!(sda.write.min_us, Some(2000));
+        assert_eq!(sda.discard.count, 0);
+        assert!(aggregator.in_flight.is_empty());
+
+        let collector = TraceCollector {
+            tracing_dir: PathBuf::from("/sys/kernel/tracing"),
+            devices: vec![
+                DevNum { major: 8, minor: 0 },
+                DevNum {
+                    major: 259,
+                    minor: 1,
+                },
+            ],
+        };
+        assert_eq!(collector.filter(), "dev == 8388608 || dev == 271581185");
+    }
+}
@@ -1,14 +1,37 @@
+pub mod btrfs;
+pub mod cgroup;
+pub mod fstab;
+pub mod graph;
+pub mod inventory;
+pub mod latency;
+pub mod maintenance;
+pub mod mkfs;
+#[cfg(target_os = "linux")]
+pub mod monitor;
+pub mod mount;
+pub mod mountinfo;
+pub mod namespace;
 pub mod nvme;
-
-use fstab::{FsEntry, FsTab};
-use log::{debug, warn};
+pub mod queue;
+pub mod safety;
+pub mod stats;
+pub mod udev_rules;
+pub mod usage;
+pub mod zfs;
+
+use log::warn;
+use mountinfo::MountTable;
+use safety::{Blocker, Preflight};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 
 use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fmt;
 use std::fs::{self, read_dir, File};
-use std::io::{BufRead, BufReader, Write};
+use std::io::Write;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Output};
@@ -41,6 +64,13 @@ mod tests {
             inode_size: Some(512),
             force: false,
             agcount: Some(32),
+            label: None,
+            uuid: None,
+            reflink: None,
+            crc: None,
+            rmapbt: None,
+            log_size: None,
+            realtime_device: None,
         };
         let result = super::format_block_device(&file_path, &xfs_options);
         println!("Result: {:?}", result);
@@ -60,23 +90,77 @@ mod tests {
             stride: Some(2),
             stripe_width: None,
             reserved_blocks_percentage: 10,
+            label: None,
+            uuid: None,
+            journal_size: None,
+            lazy_itable_init: None,
+            metadata_csum: None,
+            sixty_four_bit: None,
+            bigalloc: None,
         };
         let result = super::format_block_device(&file_path, &xfs_options);
         println!("Result: {:?}", result);
         close(fd).expect("Failed to close file descriptor");
     }
-}
 
-const MTAB_PATH: &str = "/etc/mtab";
+    #[test]
+    fn test_serialized_enum_names() {
+        use super::{MediaType, ScsiDeviceType, Vendor, ZonedModel};
+        use std::str::FromStr;
+
+        // Part of the inventory format so these must not change
+        fn json<T: serde::Serialize>(value: &T) -> String {
+            serde_json::to_string(value).unwrap()
+        }
+        assert_eq!(json(&Vendor::NECVMWar), "\"necvmwar\"");
+        assert_eq!(json(&Vendor::VMware), "\"vmware\"");
+        assert_eq!(json(&Vendor::Hp), "\"hp\"");
+        assert_eq!(json(&MediaType::LVM), "\"lvm\"");
+        assert_eq!(json(&MediaType::NVME), "\"nvme\"");
+        assert_eq!(json(&ScsiDeviceType::DirectAccess), "\"direct_access\"");
+        assert_eq!(json(&ScsiDeviceType::CdRom), "\"cd_rom\"");
+        assert_eq!(json(&ScsiDeviceType::WellKnownLu), "\"well_known_lu\"");
+        assert_eq!(json(&ZonedModel::HostAware), "\"host_aware\"");
+        assert_eq!(
+            ZonedModel::from_str("host-aware").unwrap(),
+            ZonedModel::HostAware
+        );
+        let vendor: Vendor = serde_json::from_str("\"necvmwar\"").unwrap();
+        assert!(matches!(vendor, Vendor::NECVMWar));
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum BlockUtilsError {
     #[error("BlockUtilsError : {0}")]
     Error(String),
 
+    #[error("Device {} is in use: {}", .device.display(), safety::describe_blockers(.blockers))]
+    DeviceInUse {
+        device: PathBuf,
+        blockers: Vec<Blocker>,
+    },
+
+    #[error("Mounting {} on {} failed: {kind}", .device.display(), .target.display())]
+    MountFailed {
+        device: PathBuf,
+        target: PathBuf,
+        kind: mount::MountErrorKind,
+    },
+
+    #[error("Unmounting {} failed: {kind}{}", .target.display(), mount::describe_busy(.processes))]
+    UnmountFailed {
+        target: PathBuf,
+        kind: mount::MountErrorKind,
+        processes: Vec<mount::BusyProcess>,
+    },
+
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 
+    #[error(transparent)]
+    NixError(#[from] nix::Error),
+
     #[error(transparent)]
     ParseBoolError(#[from] std::str::ParseBoolError),
 
@@ -99,20 +183,38 @@ impl BlockUtilsError {
 
 // Formats a block device at Path p with XFS
 /// This is used for formatting btrfs filesystems and setting the metadata profile
-#[derive(Clone, Debug, Display)]
+#[derive(Clone, Debug, Eq, PartialEq, Display, Serialize, Deserialize)]
 #[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum MetadataProfile {
     Raid0,
     Raid1,
     Raid5,
     Raid6,
     Raid10,
+    /// Three copies, needs kernel 5.5
+    Raid1c3,
+    /// Four copies, needs kernel 5.5
+    Raid1c4,
     Single,
     Dup,
 }
 
+/// Checksum algorithm of a btrfs filesystem
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum BtrfsChecksum {
+    /// The default
+    Crc32c,
+    Xxhash,
+    Sha256,
+    Blake2,
+}
+
 /// What raid card if any the system is using to serve disks
-#[derive(Clone, Debug, EnumString)]
+#[derive(Clone, Debug, EnumString, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Vendor {
     #[strum(serialize = "ATA")]
     None,
@@ -127,14 +229,30 @@ pub enum Vendor {
     #[strum(serialize = "VBOX")]
     Vbox, // Virtual Box
     #[strum(serialize = "NECVMWar")]
+    #[serde(rename = "necvmwar")]
     NECVMWar, // VMWare
     #[strum(serialize = "VMware")]
+    #[serde(rename = "vmware")]
     VMware, //VMware
 }
 
+/// The udev property or sysfs attribute `Device::wwn` came from.  Devices
+/// without ID_WWN_WITH_EXTENSION, like nvme namespaces, fall back to the
+/// others.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WwnSource {
+    /// ID_WWN_WITH_EXTENSION
+    WwnWithExtension,
+    /// ID_WWN
+    Wwn,
+    /// The wwid sysfs attribute
+    Wwid,
+}
+
 // This will be used to make intelligent decisions about setting up the device
 /// Device information that is gathered with udev
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Device {
     pub id: Option<Uuid>,
     pub name: String,
@@ -142,14 +260,37 @@ pub struct Device {
     pub device_type: DeviceType,
     pub capacity: u64,
     pub fs_type: FilesystemType,
+    /// Filesystem label
+    pub label: Option<String>,
     pub serial_number: Option<String>,
     pub logical_block_size: Option<u64>,
     pub physical_block_size: Option<u64>,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    /// World wide name, ie: 0x5000c500a1b2c3d4
+    pub wwn: Option<String>,
+    /// Where `wwn` was read from
+    #[serde(default)]
+    pub wwn_source: Option<WwnSource>,
+    pub firmware_revision: Option<String>,
+    pub transport: Transport,
+    /// Spindle speed.  0 for solid state drives that report it
+    pub rotation_rate_rpm: Option<u32>,
+    pub removable: bool,
+    pub read_only: bool,
+    pub hidden: bool,
+    pub zoned: ZonedModel,
+    pub partition_number: Option<u64>,
+    /// GPT partition name
+    pub partition_label: Option<String>,
+    pub devnum: Option<DevNum>,
+    /// Every symlink udev created for this device, ie: /dev/disk/by-id/*
+    pub links: Vec<PathBuf>,
 }
 
 impl Device {
     #[cfg(target_os = "linux")]
-    fn from_udev_device(device: udev::Device) -> BlockResult<Self> {
+    pub(crate) fn from_udev_device(device: udev::Device) -> BlockResult<Self> {
         let sys_name = device.sysname();
         let id: Option<Uuid> = get_uuid(&device);
         let serial = get_serial(&device);
@@ -162,6 +303,7 @@ impl Device {
         let logical_block_size = get_udev_int_val(&device, "queue/logical_block_size");
         let physical_block_size = get_udev_int_val(&device, "queue/physical_block_size");
         let fs_type = get_fs_type(&device)?;
+        let (wwn, wwn_source) = get_wwn(&device).unzip();
 
         Ok(Device {
             id,
@@ -170,29 +312,134 @@ impl Device {
             device_type,
             capacity,
             fs_type,
+            label: get_udev_property(&device, "ID_FS_LABEL"),
             serial_number: serial,
             logical_block_size,
             physical_block_size,
+            model: get_udev_property(&device, "ID_MODEL")
+                .or_else(|| get_udev_str_attribute(&device, "device/model")),
+            vendor: get_udev_property(&device, "ID_VENDOR")
+                .or_else(|| get_udev_str_attribute(&device, "device/vendor")),
+            wwn,
+            wwn_source,
+            firmware_revision: get_udev_property(&device, "ID_REVISION")
+                .or_else(|| get_udev_str_attribute(&device, "device/firmware_rev"))
+                .or_else(|| get_udev_str_attribute(&device, "device/rev")),
+            transport: get_transport(&device),
+            rotation_rate_rpm: get_udev_property(&device, "ID_ATA_ROTATION_RATE_RPM")
+                .and_then(|rpm| rpm.parse::<u32>().ok()),
+            removable: get_udev_int_val(&device, "removable") == Some(1),
+            read_only: get_udev_int_val(&device, "ro") == Some(1),
+            hidden: get_udev_int_val(&device, "hidden") == Some(1),
+            zoned: get_udev_str_attribute(&device, "queue/zoned")
+                .and_then(|z| ZonedModel::from_str(&z).ok())
+                .unwrap_or(ZonedModel::None),
+            partition_number: get_udev_property(&device, "ID_PART_ENTRY_NUMBER")
+                .and_then(|n| n.parse::<u64>().ok())
+                .or_else(|| get_udev_int_val(&device, "partition")),
+            partition_label: get_udev_property(&device, "ID_PART_ENTRY_NAME"),
+            devnum: device.devnum().map(DevNum::from_dev_t),
+            links: get_udev_property(&device, "DEVLINKS")
+                .map(|links| links.split_whitespace().map(PathBuf::from).collect())
+                .unwrap_or_default(),
         })
     }
+}
 
-    fn from_fs_entry(fs_entry: FsEntry) -> BlockResult<Self> {
-        Ok(Device {
-            id: None,
-            name: Path::new(&fs_entry.fs_spec)
-                .file_name()
-                .unwrap_or_else(|| OsStr::new(""))
-                .to_string_lossy()
-                .into_owned(),
-            media_type: MediaType::Unknown,
-            device_type: DeviceType::Unknown,
-            capacity: 0,
-            fs_type: FilesystemType::from_str(&fs_entry.vfs_type)?,
-            serial_number: None,
-            logical_block_size: None,
-            physical_block_size: None,
-        })
+/// Device number of a block device
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct DevNum {
+    pub major: u64,
+    pub minor: u64,
+}
+
+impl DevNum {
+    pub fn from_dev_t(dev: nix::libc::dev_t) -> DevNum {
+        DevNum {
+            major: nix::sys::stat::major(dev),
+            minor: nix::sys::stat::minor(dev),
+        }
     }
+
+    /// Kernel name of the block device with this number, ie: sda.  None if
+    /// it isn't a block device, like the anonymous numbers of btrfs or tmpfs
+    pub fn kernel_name(&self) -> Option<String> {
+        fs::read_link(format!("/sys/dev/block/{}", self))
+            .ok()?
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+    }
+}
+
+impl fmt::Display for DevNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for DevNum {
+    type Err = BlockUtilsError;
+
+    /// Parse the major:minor format used in sysfs and mountinfo
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(major), Some(minor)) => Ok(DevNum {
+                major: major.parse::<u64>()?,
+                minor: minor.parse::<u64>()?,
+            }),
+            _ => Err(BlockUtilsError::new(format!(
+                "Invalid device number {}.  Should be major:minor format",
+                s
+            ))),
+        }
+    }
+}
+
+#[test]
+fn test_devnum_parse() {
+    assert_eq!(
+        "253:3\n".parse::<DevNum>().unwrap(),
+        DevNum {
+            major: 253,
+            minor: 3
+        }
+    );
+    assert_eq!("253:3".parse::<DevNum>().unwrap().to_string(), "253:3");
+    assert!("253".parse::<DevNum>().is_err());
+    assert!("253:x".parse::<DevNum>().is_err());
+}
+
+/// How the device is attached to the host
+#[derive(Clone, Debug, Eq, PartialEq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Sata,
+    Sas,
+    Nvme,
+    Usb,
+    Virtio,
+    Iscsi,
+    /// Fibre channel
+    Fc,
+    /// Parallel scsi or a raid controller that hides the real transport
+    Scsi,
+    /// SD/MMC card
+    Mmc,
+    Unknown,
+}
+
+/// Zoned block device model.  Parsed from queue/zoned's kebab-case names,
+/// serialized as snake_case like the other enums
+#[derive(Clone, Debug, Eq, PartialEq, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "snake_case")]
+pub enum ZonedModel {
+    /// Regular block device
+    None,
+    HostAware,
+    HostManaged,
 }
 
 #[derive(Debug)]
@@ -207,8 +454,9 @@ pub struct AsyncInit {
     pub device: PathBuf,
 }
 
-#[derive(Debug, Display, EnumString)]
+#[derive(Debug, Display, EnumString, Serialize, Deserialize)]
 #[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum Scheduler {
     /// Try to balance latency and throughput
     Cfq,
@@ -219,7 +467,8 @@ pub enum Scheduler {
 }
 
 /// What type of media has been detected.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MediaType {
     /// AKA SSD
     SolidState,
@@ -228,20 +477,48 @@ pub enum MediaType {
     /// Special loopback device
     Loopback,
     // Logical volume device
+    #[serde(rename = "lvm")]
     LVM,
     // Software raid device
     MdRaid,
     // NVM Express
+    #[serde(rename = "nvme")]
     NVME,
     // Ramdisk
     Ram,
+    /// Compressed ramdisk, usually used for swap
+    Zram,
+    /// dm-crypt mapping, ie: an opened LUKS volume
+    DmCrypt,
+    /// dm-multipath mapping
+    Multipath,
+    /// Any other device-mapper target
+    DeviceMapper,
+    /// USB mass storage
+    Usb,
+    /// SD/MMC card
+    Mmc,
+    /// virtio-blk paravirtual disk (/dev/vd*)
+    VirtioBlk,
+    /// Disk attached to a virtio-scsi controller
+    VirtioScsi,
+    /// Xen paravirtual disk (/dev/xvd*)
+    Xen,
+    /// Amazon Elastic Block Store volume
+    AwsEbs,
+    /// Google Cloud persistent disk
+    GcpPersistentDisk,
+    /// Azure managed or temporary disk
+    AzureDisk,
+    /// Emulated disk from QEMU, VMware or VirtualBox
     Virtual,
     Unknown,
 }
 
 /// What type of device has been detected.
-#[derive(Clone, Debug, Eq, PartialEq, Display, IntoStaticStr)]
+#[derive(Clone, Debug, Eq, PartialEq, Display, IntoStaticStr, Serialize, Deserialize)]
 #[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum DeviceType {
     Disk,
     Partition,
@@ -302,6 +579,25 @@ impl FilesystemType {
     }
 }
 
+/// Serialized as the same lowercase name `to_str` returns
+impl Serialize for FilesystemType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for FilesystemType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        // to_str doesn't use the udev names for these so FromStr can't parse them
+        Ok(match name.as_str() {
+            "lvm" => FilesystemType::Lvm,
+            "unknown" => FilesystemType::Unknown,
+            _ => FilesystemType::from_str(&name).map_err(serde::de::Error::custom)?,
+        })
+    }
+}
+
 impl fmt::Display for FilesystemType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let string = match *self {
@@ -322,18 +618,48 @@ impl fmt::Display for FilesystemType {
 }
 
 /// This allows you to tweak some settings when you're formatting the filesystem
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Filesystem {
     Btrfs {
         leaf_size: u64,
         metadata_profile: MetadataProfile,
         node_size: u64,
+        /// Up to 255 bytes
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+        /// Defaults to single for one device
+        #[serde(default)]
+        data_profile: Option<MetadataProfile>,
+        #[serde(default)]
+        checksum: Option<BtrfsChecksum>,
     },
     Ext4 {
         inode_size: u64,
         reserved_blocks_percentage: u8,
         stride: Option<u64>,
         stripe_width: Option<u64>,
+        /// Up to 16 bytes
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+        /// Journal size in megabytes
+        #[serde(default)]
+        journal_size: Option<u64>,
+        /// Initialize the inode tables in the background after mounting
+        #[serde(default)]
+        lazy_itable_init: Option<bool>,
+        #[serde(default)]
+        metadata_csum: Option<bool>,
+        /// Allow more than 2^32 blocks
+        #[serde(default)]
+        sixty_four_bit: Option<bool>,
+        /// Allocate in clusters of blocks to cut allocation overhead of big files
+        #[serde(default)]
+        bigalloc: Option<bool>,
     },
     Xfs {
         /// This is optional.  Boost knobs are on by default:
@@ -345,6 +671,26 @@ pub enum Filesystem {
         stripe_size: Option<u64>,  // RAID controllers stripe
         stripe_width: Option<u64>, // IE # of data disks
         agcount: Option<u64>,      // number of allocation  groups
+        /// Up to 12 bytes
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+        /// Share data blocks between files, needed for cp --reflink
+        #[serde(default)]
+        reflink: Option<bool>,
+        /// Metadata checksums.  reflink and rmapbt need it
+        #[serde(default)]
+        crc: Option<bool>,
+        /// Reverse mapping btree, used by online repair
+        #[serde(default)]
+        rmapbt: Option<bool>,
+        /// Log size in bytes
+        #[serde(default)]
+        log_size: Option<u64>,
+        /// Device for the realtime section
+        #[serde(default)]
+        realtime_device: Option<PathBuf>,
     },
     Zfs {
         /// The default blocksize for volumes is 8 Kbytes. An
@@ -352,16 +698,102 @@ pub enum Filesystem {
         block_size: Option<u64>,
         /// Enable compression on the volume. Default is fals
         compression: Option<bool>,
+        /// Name of the pool.  Defaults to the device name.  ZFS picks the
+        /// pool GUID itself so there is no UUID option
+        #[serde(default)]
+        label: Option<String>,
+    },
+    Ext2 {
+        inode_size: u64,
+        reserved_blocks_percentage: u8,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+    },
+    Ext3 {
+        inode_size: u64,
+        reserved_blocks_percentage: u8,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+        /// Journal size in megabytes
+        #[serde(default)]
+        journal_size: Option<u64>,
+    },
+    Vfat {
+        /// 12, 16 or 32.  mkfs.vfat picks one based on the size by default
+        fat_size: Option<u8>,
+        sectors_per_cluster: Option<u8>,
+        /// Up to 11 bytes
+        #[serde(default)]
+        label: Option<String>,
+        /// The 32 bit serial number FAT uses in place of a UUID
+        #[serde(default)]
+        volume_id: Option<u32>,
+    },
+    Exfat {
+        /// Cluster size in bytes
+        cluster_size: Option<u64>,
+        /// Up to 15 characters
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+    },
+    Ntfs {
+        /// Skip zeroing the device and the bad sector check
+        quick: bool,
+        /// Needed to format a whole disk instead of a partition
+        force: bool,
+        /// Cluster size in bytes
+        cluster_size: Option<u64>,
+        #[serde(default)]
+        label: Option<String>,
+    },
+    F2fs {
+        force: bool,
+        /// Percentage of the device kept free for garbage collection
+        overprovision: Option<u8>,
+        /// -O features, ie: extra_attr, inode_checksum or compression
+        #[serde(default)]
+        features: Vec<String>,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+    },
+    Swap {
+        /// Only needed when the swap will be used on a machine with another page size
+        page_size: Option<u64>,
+        /// Up to 16 bytes
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
+    },
+    Bcachefs {
+        /// ie: lz4, gzip or zstd
+        compression: Option<String>,
+        /// Number of copies of data and metadata
+        replicas: Option<u8>,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        uuid: Option<Uuid>,
     },
 }
 
 impl Filesystem {
-    pub fn new(name: &str) -> Filesystem {
-        match name.trim() {
+    /// The default options for filesystem `name`, ie: xfs or vfat
+    pub fn new(name: &str) -> BlockResult<Filesystem> {
+        let filesystem = match name.trim() {
             // Defaults.  Can be changed as needed by the caller
             "zfs" => Filesystem::Zfs {
                 block_size: None,
                 compression: None,
+                label: None,
             },
             "xfs" => Filesystem::Xfs {
                 stripe_size: None,
@@ -370,27 +802,92 @@ impl Filesystem {
                 inode_size: Some(512),
                 force: false,
                 agcount: Some(32),
+                label: None,
+                uuid: None,
+                reflink: None,
+                crc: None,
+                rmapbt: None,
+                log_size: None,
+                realtime_device: None,
             },
             "btrfs" => Filesystem::Btrfs {
                 metadata_profile: MetadataProfile::Single,
                 leaf_size: 32768,
                 node_size: 32768,
+                label: None,
+                uuid: None,
+                data_profile: None,
+                checksum: None,
             },
             "ext4" => Filesystem::Ext4 {
                 inode_size: 512,
                 reserved_blocks_percentage: 0,
                 stride: None,
                 stripe_width: None,
+                label: None,
+                uuid: None,
+                journal_size: None,
+                lazy_itable_init: None,
+                metadata_csum: None,
+                sixty_four_bit: None,
+                bigalloc: None,
             },
-            _ => Filesystem::Xfs {
-                stripe_size: None,
-                stripe_width: None,
-                block_size: None,
-                inode_size: None,
+            "ext2" => Filesystem::Ext2 {
+                inode_size: 256,
+                reserved_blocks_percentage: 5,
+                label: None,
+                uuid: None,
+            },
+            "ext3" => Filesystem::Ext3 {
+                inode_size: 256,
+                reserved_blocks_percentage: 5,
+                label: None,
+                uuid: None,
+                journal_size: None,
+            },
+            "vfat" | "fat" => Filesystem::Vfat {
+                fat_size: None,
+                sectors_per_cluster: None,
+                label: None,
+                volume_id: None,
+            },
+            "exfat" => Filesystem::Exfat {
+                cluster_size: None,
+                label: None,
+                uuid: None,
+            },
+            "ntfs" => Filesystem::Ntfs {
+                quick: true,
                 force: false,
-                agcount: None,
+                cluster_size: None,
+                label: None,
             },
-        }
+            "f2fs" => Filesystem::F2fs {
+                force: false,
+                overprovision: None,
+                features: vec![],
+                label: None,
+                uuid: None,
+            },
+            "swap" => Filesystem::Swap {
+                page_size: None,
+                label: None,
+                uuid: None,
+            },
+            "bcachefs" => Filesystem::Bcachefs {
+                compression: None,
+                replicas: None,
+                label: None,
+                uuid: None,
+            },
+            other => {
+                return Err(BlockUtilsError::new(format!(
+                    "Unknown filesystem {}",
+                    other
+                )))
+            }
+        };
+        Ok(filesystem)
     }
 }
 
@@ -401,88 +898,57 @@ fn run_command<S: AsRef<OsStr>>(command: &str, arg_list: &[S]) -> BlockResult<Ou
 /// Utility function to mount a device at a mount point
 /// NOTE: This assumes the device is formatted at this point.  The mount
 /// will fail if the device isn't formatted.
+#[deprecated(note = "use mount::MountOptions which takes options and reports why a mount failed")]
 pub fn mount_device(device: &Device, mount_point: impl AsRef<Path>) -> BlockResult<i32> {
-    let mut arg_list: Vec<String> = Vec::new();
-    match device.id {
-        Some(id) => {
-            arg_list.push("-U".to_string());
-            arg_list.push(id.hyphenated().to_string());
-        }
-        None => {
-            arg_list.push(format!("/dev/{}", device.name));
-        }
-    };
-    arg_list.push(mount_point.as_ref().to_string_lossy().into_owned());
-    debug!("mount: {:?}", arg_list);
-
-    process_output(&run_command("mount", &arg_list)?)
+    mount::MountOptions::new().mount_device(device, mount_point)?;
+    Ok(0)
 }
 
 //Utility function to unmount a device at a mount point
+#[deprecated(note = "use mount::UnmountOptions which supports lazy, forced and recursive unmounts")]
 pub fn unmount_device(mount_point: impl AsRef<Path>) -> BlockResult<i32> {
-    let mut arg_list: Vec<String> = Vec::new();
-    arg_list.push(mount_point.as_ref().to_string_lossy().into_owned());
-
-    process_output(&run_command("umount", &arg_list)?)
+    mount::UnmountOptions::new().unmount(mount_point)?;
+    Ok(0)
 }
 
-/// Parse mtab and return the device which is mounted at a given directory
+/// Parse mountinfo and return the device which is mounted at a given directory
 pub fn get_mount_device(mount_dir: impl AsRef<Path>) -> BlockResult<Option<PathBuf>> {
-    let dir = mount_dir.as_ref().to_string_lossy().into_owned();
-    let f = File::open(MTAB_PATH)?;
-    let reader = BufReader::new(f);
-
-    for line in reader.lines() {
-        let line = line?;
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.contains(&dir.as_str()) {
-            if !parts.is_empty() {
-                return Ok(Some(PathBuf::from(parts[0])));
-            }
-        }
-    }
-    Ok(None)
+    Ok(MountTable::read()?
+        .by_mount_point(mount_dir)
+        .map(|m| PathBuf::from(&m.source)))
 }
 
-/// Parse mtab and return iterator over all mounted block devices not including LVM
+/// Parse mountinfo and return iterator over all mounted block devices, including
+/// device mapper and LVM volumes, the members of zfs pools and multi-device
+/// btrfs filesystems and the devices under overlay mounts.  Each device is
+/// returned once, even when it's mounted more than once.
 ///
-/// Lazy version of get_mounted_devices
+/// Lazy version of get_mounted_devices.  Use `MountTable::mounted_devices` to
+/// get the mount entry of each device as well.
+#[cfg(target_os = "linux")]
 pub fn get_mounted_devices_iter() -> BlockResult<impl Iterator<Item = BlockResult<Device>>> {
-    Ok(FsTab::new(Path::new(MTAB_PATH))
-        .get_entries()?
+    let mut seen = HashSet::new();
+    Ok(MountTable::read()?
+        .mounted_devices()?
         .into_iter()
-        .filter(|d| d.fs_spec.contains("/dev/"))
-        .filter(|d| !d.fs_spec.contains("mapper"))
-        .map(Device::from_fs_entry))
+        .filter(move |m| seen.insert(m.device.name.clone()))
+        .map(|m| Ok(m.device)))
 }
-/// Parse mtab and return all mounted block devices not including LVM
+/// Parse mountinfo and return all mounted block devices, including LVM
 ///
 /// Non-lazy version of get_mounted_devices_iter
+#[cfg(target_os = "linux")]
 pub fn get_mounted_devices() -> BlockResult<Vec<Device>> {
     get_mounted_devices_iter()?.collect()
 }
 
-/// Parse mtab and return the mountpoint the device is mounted at.
+/// Parse mountinfo and return the mountpoint the device is mounted at.
 /// This is the opposite of get_mount_device
 pub fn get_mountpoint(device: impl AsRef<Path>) -> BlockResult<Option<PathBuf>> {
-    let s = device.as_ref().to_string_lossy().into_owned();
-    let f = File::open(MTAB_PATH)?;
-    let reader = BufReader::new(f);
-
-    for line in reader.lines() {
-        let l = line?;
-        let parts: Vec<&str> = l.split_whitespace().collect();
-        let mut index = -1;
-        for (i, p) in parts.iter().enumerate() {
-            if p == &s {
-                index = i as i64;
-            }
-        }
-        if index >= 0 {
-            return Ok(Some(PathBuf::from(parts[1])));
-        }
-    }
-    Ok(None)
+    Ok(MountTable::read()?
+        .by_device(device)
+        .first()
+        .map(|m| m.mount_point.clone()))
 }
 
 fn process_output(output: &Output) -> BlockResult<i32> {
@@ -494,7 +960,18 @@ fn process_output(output: &Output) -> BlockResult<i32> {
     }
 }
 
+/// Wipe the partition table of a device with `sgdisk --zap`.
+/// Refuses if the device is in use, see `safety::check_not_in_use`
 pub fn erase_block_device(device: impl AsRef<Path>) -> BlockResult<()> {
+    erase_block_device_with_preflight(device, Preflight::Check)
+}
+
+/// Same as `erase_block_device` but `Preflight::Override` skips the in-use checks
+pub fn erase_block_device_with_preflight(
+    device: impl AsRef<Path>,
+    preflight: Preflight,
+) -> BlockResult<()> {
+    safety::preflight(&device, preflight)?;
     let output = Command::new("sgdisk")
         .args(&["--zap", &device.as_ref().to_string_lossy()])
         .output()?;
@@ -512,363 +989,39 @@ pub fn erase_block_device(device: impl AsRef<Path>) -> BlockResult<()> {
 /// Synchronous utility to format a block device with a given filesystem.
 /// Note: ZFS creation can be slow because there's potentially several commands that need to
 /// be run.  async_format_block_device will be faster if you have many block devices to format
+/// Refuses if the device is in use, see `safety::check_not_in_use`
 pub fn format_block_device(device: impl AsRef<Path>, filesystem: &Filesystem) -> BlockResult<i32> {
-    //TODO REFACTOR
-    match *filesystem {
-        Filesystem::Btrfs {
-            ref metadata_profile,
-            ref leaf_size,
-            ref node_size,
-        } => {
-            let mut arg_list: Vec<String> = Vec::new();
-
-            arg_list.push("-m".to_string());
-            arg_list.push(metadata_profile.clone().to_string());
-
-            arg_list.push("-l".to_string());
-            arg_list.push(leaf_size.to_string());
-
-            arg_list.push("-n".to_string());
-            arg_list.push(node_size.to_string());
-
-            arg_list.push(device.as_ref().to_string_lossy().to_string());
-            // Check if mkfs.btrfs is installed
-            if !Path::new("/sbin/mkfs.btrfs").exists() {
-                return Err(BlockUtilsError::new(
-                    "Please install btrfs-tools".to_string(),
-                ));
-            }
-            process_output(&run_command("mkfs.btrfs", &arg_list)?)
-        }
-        Filesystem::Xfs {
-            ref inode_size,
-            ref force,
-            ref block_size,
-            ref stripe_size,
-            ref stripe_width,
-            ref agcount,
-        } => {
-            let mut arg_list: Vec<String> = Vec::new();
-
-            if let Some(b) = block_size {
-                /*
-                From XFS man page:
-                The default value is 4096 bytes (4 KiB), the minimum  is
-                512,  and the maximum is 65536 (64 KiB).  XFS on Linux currently
-                only supports pagesize or smaller blocks.
-                */
-                let b: u64 = if *b < 512 {
-                    warn!("xfs block size must be 512 bytes minimum.  Correcting");
-                    512
-                } else if *b > 65536 {
-                    warn!("xfs block size must be 65536 bytes maximum.  Correcting");
-                    65536
-                } else {
-                    *b
-                };
-                arg_list.push("-b".to_string());
-                arg_list.push(format!("size={}", b));
-            }
-
-            if (*inode_size).is_some() {
-                arg_list.push("-i".to_string());
-                arg_list.push(format!("size={}", inode_size.unwrap()));
-            }
-
-            if *force {
-                arg_list.push("-f".to_string());
-            }
-
-            if (*stripe_size).is_some() && (*stripe_width).is_some() {
-                arg_list.push("-d".to_string());
-                arg_list.push(format!("su={}", stripe_size.unwrap()));
-                arg_list.push(format!("sw={}", stripe_width.unwrap()));
-                if (*agcount).is_some() {
-                    arg_list.push(format!("agcount={}", agcount.unwrap()));
-                }
-            }
-            arg_list.push(device.as_ref().to_string_lossy().to_string());
-
-            // Check if mkfs.xfs is installed
-            if !Path::new("/sbin/mkfs.xfs").exists() {
-                return Err(BlockUtilsError::new("Please install xfsprogs".into()));
-            }
-            process_output(&run_command("/sbin/mkfs.xfs", &arg_list)?)
-        }
-        Filesystem::Ext4 {
-            ref inode_size,
-            ref reserved_blocks_percentage,
-            ref stride,
-            ref stripe_width,
-        } => {
-            let mut arg_list: Vec<String> = Vec::new();
-
-            if stride.is_some() || stripe_width.is_some() {
-                arg_list.push("-E".to_string());
-                if let Some(stride) = stride {
-                    arg_list.push(format!("stride={}", stride));
-                }
-                if let Some(stripe_width) = stripe_width {
-                    arg_list.push(format!(",stripe_width={}", stripe_width));
-                }
-            }
-
-            arg_list.push("-I".to_string());
-            arg_list.push(inode_size.to_string());
-
-            arg_list.push("-m".to_string());
-            arg_list.push(reserved_blocks_percentage.to_string());
-
-            arg_list.push(device.as_ref().to_string_lossy().to_string());
+    format_block_device_with_preflight(device, filesystem, Preflight::Check)
+}
 
-            process_output(&run_command("mkfs.ext4", &arg_list)?)
-        }
-        Filesystem::Zfs {
-            ref block_size,
-            ref compression,
-        } => {
-            // Check if zfs is installed
-            if !Path::new("/sbin/zfs").exists() {
-                return Err(BlockUtilsError::new("Please install zfsutils-linux".into()));
-            }
-            let base_name = device.as_ref().file_name();
-            match base_name {
-                Some(name) => {
-                    //Mount at /mnt/{dev_name}
-                    let arg_list: Vec<String> = vec![
-                        "create".to_string(),
-                        "-f".to_string(),
-                        "-m".to_string(),
-                        format!("/mnt/{}", name.to_string_lossy().into_owned()),
-                        name.to_string_lossy().into_owned(),
-                        device.as_ref().to_string_lossy().into_owned(),
-                    ];
-                    // Create the zpool
-                    let _ = process_output(&run_command("/sbin/zpool", &arg_list)?)?;
-                    if block_size.is_some() {
-                        // If zpool creation is successful then we set these
-                        let _ = process_output(&run_command(
-                            "/sbin/zfs",
-                            &[
-                                "set".to_string(),
-                                format!("recordsize={}", block_size.unwrap()),
-                                name.to_string_lossy().into_owned(),
-                            ],
-                        )?)?;
-                    }
-                    if compression.is_some() {
-                        let _ = process_output(&run_command(
-                            "/sbin/zfs",
-                            &[
-                                "set".to_string(),
-                                "compression=on".to_string(),
-                                name.to_string_lossy().into_owned(),
-                            ],
-                        )?)?;
-                    }
-                    let _ = process_output(&run_command(
-                        "/sbin/zfs",
-                        &[
-                            "set".to_string(),
-                            "acltype=posixacl".to_string(),
-                            name.to_string_lossy().into_owned(),
-                        ],
-                    )?)?;
-                    let _ = process_output(&run_command(
-                        "/sbin/zfs",
-                        &[
-                            "set".to_string(),
-                            "atime=off".to_string(),
-                            name.to_string_lossy().into_owned(),
-                        ],
-                    )?)?;
-                    Ok(0)
-                }
-                None => Err(BlockUtilsError::new(format!(
-                    "Unable to determine filename for device: {:?}",
-                    device.as_ref()
-                ))),
-            }
-        }
-    }
+/// Same as `format_block_device` but `Preflight::Override` skips the in-use checks
+pub fn format_block_device_with_preflight(
+    device: impl AsRef<Path>,
+    filesystem: &Filesystem,
+    preflight: Preflight,
+) -> BlockResult<i32> {
+    safety::preflight(&device, preflight)?;
+    filesystem.mkfs_plan(&device)?.run()?;
+    Ok(0)
 }
 
+/// Start formatting a block device without waiting for mkfs to finish.
+/// Refuses if the device is in use, see `safety::check_not_in_use`
 pub fn async_format_block_device(
     device: impl AsRef<Path>,
     filesystem: &Filesystem,
 ) -> BlockResult<AsyncInit> {
-    match *filesystem {
-        Filesystem::Btrfs {
-            ref metadata_profile,
-            ref leaf_size,
-            ref node_size,
-        } => {
-            let arg_list: Vec<String> = vec![
-                "-m".to_string(),
-                metadata_profile.clone().to_string(),
-                "-l".to_string(),
-                leaf_size.to_string(),
-                "-n".to_string(),
-                node_size.to_string(),
-                device.as_ref().to_string_lossy().to_string(),
-            ];
-            // Check if mkfs.btrfs is installed
-            if !Path::new("/sbin/mkfs.btrfs").exists() {
-                return Err(BlockUtilsError::new("Please install btrfs-tools".into()));
-            }
-            Ok(AsyncInit {
-                format_child: Command::new("mkfs.btrfs").args(&arg_list).spawn()?,
-                post_setup_commands: vec![],
-                device: device.as_ref().to_owned(),
-            })
-        }
-        Filesystem::Xfs {
-            ref block_size,
-            ref inode_size,
-            ref stripe_size,
-            ref stripe_width,
-            ref force,
-            ref agcount,
-        } => {
-            let mut arg_list: Vec<String> = Vec::new();
-
-            if (*inode_size).is_some() {
-                arg_list.push("-i".to_string());
-                arg_list.push(format!("size={}", inode_size.unwrap()));
-            }
-
-            if *force {
-                arg_list.push("-f".to_string());
-            }
-
-            if let Some(b) = block_size {
-                arg_list.push("-b".to_string());
-                arg_list.push(b.to_string());
-            }
-
-            if (*stripe_size).is_some() && (*stripe_width).is_some() {
-                arg_list.push("-d".to_string());
-                arg_list.push(format!("su={}", stripe_size.unwrap()));
-                arg_list.push(format!("sw={}", stripe_width.unwrap()));
-                if (*agcount).is_some() {
-                    arg_list.push(format!("agcount={}", agcount.unwrap()));
-                }
-            }
-
-            arg_list.push(device.as_ref().to_string_lossy().to_string());
-
-            // Check if mkfs.xfs is installed
-            if !Path::new("/sbin/mkfs.xfs").exists() {
-                return Err(BlockUtilsError::new("Please install xfsprogs".into()));
-            }
-            let format_handle = Command::new("/sbin/mkfs.xfs").args(&arg_list).spawn()?;
-            Ok(AsyncInit {
-                format_child: format_handle,
-                post_setup_commands: vec![],
-                device: device.as_ref().to_owned(),
-            })
-        }
-        Filesystem::Zfs {
-            ref block_size,
-            ref compression,
-        } => {
-            // Check if zfs is installed
-            if !Path::new("/sbin/zfs").exists() {
-                return Err(BlockUtilsError::new("Please install zfsutils-linux".into()));
-            }
-            let base_name = device.as_ref().file_name();
-            match base_name {
-                Some(name) => {
-                    //Mount at /mnt/{dev_name}
-                    let mut post_setup_commands: Vec<(String, Vec<String>)> = Vec::new();
-                    let arg_list: Vec<String> = vec![
-                        "create".to_string(),
-                        "-f".to_string(),
-                        "-m".to_string(),
-                        format!("/mnt/{}", name.to_string_lossy().into_owned()),
-                        name.to_string_lossy().into_owned(),
-                        device.as_ref().to_string_lossy().into_owned(),
-                    ];
-                    let zpool_create = Command::new("/sbin/zpool").args(&arg_list).spawn()?;
-
-                    if block_size.is_some() {
-                        // If zpool creation is successful then we set these
-                        post_setup_commands.push((
-                            "/sbin/zfs".to_string(),
-                            vec![
-                                "set".to_string(),
-                                format!("recordsize={}", block_size.unwrap()),
-                                name.to_string_lossy().into_owned(),
-                            ],
-                        ));
-                    }
-                    if compression.is_some() {
-                        post_setup_commands.push((
-                            "/sbin/zfs".to_string(),
-                            vec![
-                                "set".to_string(),
-                                "compression=on".to_string(),
-                                name.to_string_lossy().into_owned(),
-                            ],
-                        ));
-                    }
-                    post_setup_commands.push((
-                        "/sbin/zfs".to_string(),
-                        vec![
-                            "set".to_string(),
-                            "acltype=posixacl".to_string(),
-                            name.to_string_lossy().into_owned(),
-                        ],
-                    ));
-                    post_setup_commands.push((
-                        "/sbin/zfs".to_string(),
-                        vec![
-                            "set".to_string(),
-                            "atime=off".to_string(),
-                            name.to_string_lossy().into_owned(),
-                        ],
-                    ));
-                    Ok(AsyncInit {
-                        format_child: zpool_create,
-                        post_setup_commands,
-                        device: device.as_ref().to_owned(),
-                    })
-                }
-                None => Err(BlockUtilsError::new(format!(
-                    "Unable to determine filename for device: {:?}",
-                    device.as_ref()
-                ))),
-            }
-        }
-        Filesystem::Ext4 {
-            ref inode_size,
-            ref reserved_blocks_percentage,
-            ref stride,
-            ref stripe_width,
-        } => {
-            let mut arg_list: Vec<String> =
-                vec!["-m".to_string(), reserved_blocks_percentage.to_string()];
-
-            arg_list.push("-I".to_string());
-            arg_list.push(inode_size.to_string());
-
-            if (*stride).is_some() {
-                arg_list.push("-E".to_string());
-                arg_list.push(format!("stride={}", stride.unwrap()));
-            }
-            if (*stripe_width).is_some() {
-                arg_list.push("-E".to_string());
-                arg_list.push(format!("stripe_width={}", stripe_width.unwrap()));
-            }
-            arg_list.push(device.as_ref().to_string_lossy().into_owned());
+    async_format_block_device_with_preflight(device, filesystem, Preflight::Check)
+}
 
-            Ok(AsyncInit {
-                format_child: Command::new("mkfs.ext4").args(&arg_list).spawn()?,
-                post_setup_commands: vec![],
-                device: device.as_ref().to_owned(),
-            })
-        }
-    }
+/// Same as `async_format_block_device` but `Preflight::Override` skips the in-use checks
+pub fn async_format_block_device_with_preflight(
+    device: impl AsRef<Path>,
+    filesystem: &Filesystem,
+    preflight: Preflight,
+) -> BlockResult<AsyncInit> {
+    safety::preflight(&device, preflight)?;
+    filesystem.mkfs_plan(&device)?.spawn(&device)
 }
 
 #[cfg(target_os = "linux")]
@@ -911,6 +1064,101 @@ fn get_serial(device: &udev::Device) -> Option<String> {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn get_udev_property(device: &udev::Device, property: &str) -> Option<String> {
+    device
+        .property_value(property)
+        .map(|value| value.to_string_lossy().trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(target_os = "linux")]
+fn get_udev_str_attribute(device: &udev::Device, attr_name: &str) -> Option<String> {
+    device
+        .attribute_value(attr_name)
+        .map(|value| value.to_string_lossy().trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(target_os = "linux")]
+fn get_wwn(device: &udev::Device) -> Option<(String, WwnSource)> {
+    get_udev_property(device, "ID_WWN_WITH_EXTENSION")
+        .map(|wwn| (wwn, WwnSource::WwnWithExtension))
+        .or_else(|| get_udev_property(device, "ID_WWN").map(|wwn| (wwn, WwnSource::Wwn)))
+        .or_else(|| get_udev_str_attribute(device, "wwid").map(|wwn| (wwn, WwnSource::Wwid)))
+}
+
+#[cfg(target_os = "linux")]
+fn get_transport(device: &udev::Device) -> Transport {
+    transport_from_udev(
+        &device.sysname().to_string_lossy(),
+        get_udev_property(device, "ID_BUS").as_deref(),
+        get_udev_property(device, "ID_PATH").as_deref(),
+    )
+}
+
+/// Work out the transport from the udev ID_BUS and ID_PATH properties.
+/// ID_PATH describes the route from the pci bus to the disk, ie:
+/// pci-0000:00:1f.2-ata-1, pci-0000:02:00.0-sas-phy3-lun-0 or
+/// ip-10.0.0.1:3260-iscsi-iqn.2003-01.org.example:target-lun-1
+fn transport_from_udev(sysname: &str, id_bus: Option<&str>, id_path: Option<&str>) -> Transport {
+    let id_path = id_path.unwrap_or("");
+    if sysname.starts_with("nvme") || id_path.contains("-nvme-") {
+        Transport::Nvme
+    } else if sysname.starts_with("mmcblk") {
+        Transport::Mmc
+    } else if id_path.contains("-usb-") || id_bus == Some("usb") {
+        Transport::Usb
+    } else if id_path.contains("-iscsi-") {
+        Transport::Iscsi
+    } else if id_path.contains("-fc-") {
+        Transport::Fc
+    } else if id_path.contains("-sas-") {
+        Transport::Sas
+    } else if id_path.contains("-ata-") || id_bus == Some("ata") {
+        Transport::Sata
+    } else if sysname.starts_with("vd") || id_path.starts_with("virtio-") {
+        Transport::Virtio
+    } else if id_bus == Some("scsi") {
+        Transport::Scsi
+    } else {
+        Transport::Unknown
+    }
+}
+
+#[test]
+fn test_transport_from_udev() {
+    assert_eq!(
+        transport_from_udev("sda", Some("ata"), Some("pci-0000:00:1f.2-ata-1")),
+        Transport::Sata
+    );
+    assert_eq!(
+        transport_from_udev("sdb", Some("scsi"), Some("pci-0000:02:00.0-sas-phy3-lun-0")),
+        Transport::Sas
+    );
+    assert_eq!(
+        transport_from_udev(
+            "sdc",
+            Some("usb"),
+            Some("pci-0000:00:14.0-usb-0:2:1.0-scsi-0:0:0:0")
+        ),
+        Transport::Usb
+    );
+    assert_eq!(
+        transport_from_udev(
+            "sdd",
+            Some("scsi"),
+            Some("ip-10.0.0.1:3260-iscsi-iqn.2003-01.org.example:target-lun-1")
+        ),
+        Transport::Iscsi
+    );
+    assert_eq!(transport_from_udev("nvme0n1", None, None), Transport::Nvme);
+    assert_eq!(
+        transport_from_udev("vda", None, Some("pci-0000:00:04.0")),
+        Transport::Virtio
+    );
+}
+
 #[cfg(target_os = "linux")]
 fn get_fs_type(device: &udev::Device) -> BlockResult<FilesystemType> {
     match device.property_value("ID_FS_TYPE") {
@@ -922,63 +1170,228 @@ fn get_fs_type(device: &udev::Device) -> BlockResult<FilesystemType> {
     }
 }
 
+/// The udev and sysfs details used to work out the media type
+#[derive(Debug, Default)]
+struct MediaFacts {
+    sysname: String,
+    dm_name: Option<String>,
+    dm_uuid: Option<String>,
+    id_bus: Option<String>,
+    vendor: Option<String>,
+    model: Option<String>,
+    rotation_rate_rpm: Option<String>,
+    /// queue/rotational
+    rotational: Option<bool>,
+    /// Drivers bound to this device and all its parents
+    drivers: Vec<String>,
+    /// Subsystems of this device and all its parents
+    subsystems: Vec<String>,
+}
+
 #[cfg(target_os = "linux")]
 fn get_media_type(device: &udev::Device) -> MediaType {
-    use regex::Regex;
-    let device_sysname = device.sysname().to_string_lossy();
-
-    // Test for loopback
-    if let Ok(loop_regex) = Regex::new(r"loop\d+") {
-        if loop_regex.is_match(&device_sysname) {
-            return MediaType::Loopback;
+    let mut facts = MediaFacts {
+        sysname: device.sysname().to_string_lossy().into_owned(),
+        dm_name: get_udev_property(device, "DM_NAME"),
+        dm_uuid: get_udev_property(device, "DM_UUID"),
+        id_bus: get_udev_property(device, "ID_BUS"),
+        vendor: get_udev_property(device, "ID_VENDOR")
+            .or_else(|| get_udev_str_attribute(device, "device/vendor")),
+        model: get_udev_property(device, "ID_MODEL")
+            .or_else(|| get_udev_str_attribute(device, "device/model")),
+        rotation_rate_rpm: get_udev_property(device, "ID_ATA_ROTATION_RATE_RPM"),
+        rotational: get_udev_int_val(device, "queue/rotational").map(|r| r == 1),
+        ..Default::default()
+    };
+    // Partitions don't carry the queue attributes, look at the disk
+    if facts.rotational.is_none() {
+        if let Some(parent) = device.parent() {
+            facts.rotational = get_udev_int_val(&parent, "queue/rotational").map(|r| r == 1);
         }
     }
-
-    // Test for ramdisk
-    if let Ok(ramdisk_regex) = Regex::new(r"ram\d+") {
-        if ramdisk_regex.is_match(&device_sysname) {
-            return MediaType::Ram;
+    let mut current = Some(device.clone());
+    while let Some(dev) = current {
+        if let Some(driver) = dev.driver() {
+            facts.drivers.push(driver.to_string_lossy().into_owned());
+        }
+        if let Some(subsystem) = dev.subsystem() {
+            facts
+                .subsystems
+                .push(subsystem.to_string_lossy().into_owned());
         }
+        current = dev.parent();
     }
 
-    // Test for software raid
-    if let Ok(ramdisk_regex) = Regex::new(r"md\d+") {
-        if ramdisk_regex.is_match(&device_sysname) {
-            return MediaType::MdRaid;
+    classify_media(&facts)
+}
+
+const VIRTUAL_NAME_PREFIXES: [(&str, MediaType); 4] = [
+    ("loop", MediaType::Loopback),
+    ("zram", MediaType::Zram),
+    ("ram", MediaType::Ram),
+    ("md", MediaType::MdRaid),
+];
+
+fn classify_media(facts: &MediaFacts) -> MediaType {
+    let name = facts.sysname.as_str();
+    let has_driver = |d: &str| facts.drivers.iter().any(|driver| driver == d);
+    let has_subsystem = |s: &str| facts.subsystems.iter().any(|subsystem| subsystem == s);
+    let vendor = facts.vendor.as_deref().unwrap_or("").trim();
+    let model = facts.model.as_deref().unwrap_or("").trim();
+
+    // Names the kernel hands out for virtual devices: the prefix followed by
+    // a number, ie: loop0 or md127p1
+    for (prefix, media_type) in VIRTUAL_NAME_PREFIXES.iter() {
+        if let Some(rest) = name.strip_prefix(prefix) {
+            if rest.starts_with(|c: char| c.is_ascii_digit()) {
+                return media_type.clone();
+            }
         }
     }
 
-    // Test for nvme
-    if device_sysname.contains("nvme") {
+    // Device-mapper prefixes the uuid with the subsystem that created it
+    if facts.dm_name.is_some() || facts.dm_uuid.is_some() {
+        let dm_uuid = facts.dm_uuid.as_deref().unwrap_or("");
+        return if dm_uuid.starts_with("CRYPT-") {
+            MediaType::DmCrypt
+        } else if dm_uuid.starts_with("mpath-") {
+            MediaType::Multipath
+        } else if dm_uuid.starts_with("LVM-") || dm_uuid.is_empty() {
+            MediaType::LVM
+        } else {
+            MediaType::DeviceMapper
+        };
+    }
+
+    // Cloud block storage identifies itself through vendor and model strings
+    if model.starts_with("Amazon Elastic Block Store") {
+        return MediaType::AwsEbs;
+    }
+    if (vendor == "Google" && model.starts_with("PersistentDisk")) || model == "nvme_card-pd" {
+        return MediaType::GcpPersistentDisk;
+    }
+    if (vendor == "Msft" && model.starts_with("Virtual")) || has_driver("hv_storvsc") {
+        return MediaType::AzureDisk;
+    }
+
+    if name.starts_with("xvd") || has_driver("vbd") || has_subsystem("xen") {
+        return MediaType::Xen;
+    }
+    if name.starts_with("mmcblk") || has_subsystem("mmc") {
+        return MediaType::Mmc;
+    }
+    if name.starts_with("vd") || has_driver("virtio_blk") {
+        return MediaType::VirtioBlk;
+    }
+    if has_driver("virtio_scsi") {
+        return MediaType::VirtioScsi;
+    }
+    if name.starts_with("nvme") {
         return MediaType::NVME;
     }
+    if facts.id_bus.as_deref() == Some("usb") || has_subsystem("usb") {
+        return MediaType::Usb;
+    }
 
-    // Test for LVM
-    if device.property_value("DM_NAME").is_some() {
-        return MediaType::LVM;
+    // Emulated disks from the hypervisors we know about
+    match Vendor::from_str(vendor) {
+        Ok(Vendor::Qemu) | Ok(Vendor::Vbox) | Ok(Vendor::NECVMWar) | Ok(Vendor::VMware) => {
+            return MediaType::Virtual
+        }
+        _ => {}
     }
 
     // That should take care of the tricky ones.  Lets try to identify if it's
     // SSD or rotational now
-    if let Some(rotation) = device.property_value("ID_ATA_ROTATION_RATE_RPM") {
+    if let Some(ref rotation) = facts.rotation_rate_rpm {
         return if rotation == "0" {
             MediaType::SolidState
         } else {
             MediaType::Rotational
         };
     }
-
-    // No rotation rate.  Lets see if it's a virtual qemu disk
-    if let Some(vendor) = device.property_value("ID_VENDOR") {
-        let value = vendor.to_string_lossy();
-        return match value.as_ref() {
-            "QEMU" => MediaType::Virtual,
-            _ => MediaType::Unknown,
-        };
+    match facts.rotational {
+        Some(true) => MediaType::Rotational,
+        Some(false) => MediaType::SolidState,
+        // I give up
+        None => MediaType::Unknown,
     }
+}
+
+#[test]
+fn test_classify_media() {
+    let facts = |name: &str| MediaFacts {
+        sysname: name.to_string(),
+        ..Default::default()
+    };
+    assert_eq!(classify_media(&facts("loop0")), MediaType::Loopback);
+    assert_eq!(classify_media(&facts("zram0")), MediaType::Zram);
+    assert_eq!(classify_media(&facts("ram1")), MediaType::Ram);
+    assert_eq!(classify_media(&facts("md127p1")), MediaType::MdRaid);
+    // Only followed by a number
+    assert_ne!(classify_media(&facts("random")), MediaType::Ram);
+    assert_eq!(classify_media(&facts("xvda")), MediaType::Xen);
+    assert_eq!(classify_media(&facts("vdb")), MediaType::VirtioBlk);
+    assert_eq!(classify_media(&facts("mmcblk0")), MediaType::Mmc);
+    assert_eq!(classify_media(&facts("sda")), MediaType::Unknown);
+
+    let dm = |uuid: &str| MediaFacts {
+        sysname: "dm-0".to_string(),
+        dm_name: Some("vol".to_string()),
+        dm_uuid: Some(uuid.to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        classify_media(&dm("CRYPT-LUKS2-abcd-vol")),
+        MediaType::DmCrypt
+    );
+    assert_eq!(classify_media(&dm("mpath-3600a0b80")), MediaType::Multipath);
+    assert_eq!(classify_media(&dm("LVM-abcd")), MediaType::LVM);
+    assert_eq!(
+        classify_media(&dm("stratis-1-abcd")),
+        MediaType::DeviceMapper
+    );
 
-    // I give up
-    MediaType::Unknown
+    let ebs = MediaFacts {
+        sysname: "nvme1n1".to_string(),
+        model: Some("Amazon Elastic Block Store".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(classify_media(&ebs), MediaType::AwsEbs);
+    let gcp = MediaFacts {
+        sysname: "sdb".to_string(),
+        vendor: Some("Google".to_string()),
+        model: Some("PersistentDisk".to_string()),
+        drivers: vec!["sd".to_string(), "virtio_scsi".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(classify_media(&gcp), MediaType::GcpPersistentDisk);
+    let virtio_scsi = MediaFacts {
+        sysname: "sdb".to_string(),
+        vendor: Some("QEMU".to_string()),
+        drivers: vec!["sd".to_string(), "virtio_scsi".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(classify_media(&virtio_scsi), MediaType::VirtioScsi);
+    let usb = MediaFacts {
+        sysname: "sdc".to_string(),
+        id_bus: Some("usb".to_string()),
+        rotational: Some(false),
+        ..Default::default()
+    };
+    assert_eq!(classify_media(&usb), MediaType::Usb);
+    let vbox = MediaFacts {
+        sysname: "sda".to_string(),
+        vendor: Some("VBOX".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(classify_media(&vbox), MediaType::Virtual);
+    let ssd = MediaFacts {
+        sysname: "sda".to_string(),
+        rotational: Some(false),
+        ..Default::default()
+    };
+    assert_eq!(classify_media(&ssd), MediaType::SolidState);
 }
 
 #[cfg(target_os = "linux")]
@@ -1173,7 +1586,7 @@ pub fn get_block_dev_properties(
 }
 
 /// A raid array enclosure
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Enclosure {
     pub active: Option<String>,
     pub fault: Option<String>,
@@ -1183,8 +1596,9 @@ pub struct Enclosure {
     pub enclosure_type: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq, Display, EnumString)]
+#[derive(Clone, Debug, PartialEq, Display, EnumString, Serialize, Deserialize)]
 #[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum DeviceState {
     Blocked,
     #[strum(serialize = "failfast")]
@@ -1194,7 +1608,7 @@ pub enum DeviceState {
     RunningRta,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ScsiInfo {
     pub block_device: Option<PathBuf>,
     pub enclosure: Option<Enclosure>,
@@ -1212,7 +1626,8 @@ pub struct ScsiInfo {
 }
 
 // Taken from https://github.com/hreinecke/lsscsi/blob/master/src/lsscsi.c
-#[derive(Clone, Copy, Debug, PartialEq, EnumString)]
+#[derive(Clone, Copy, Debug, PartialEq, EnumString, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ScsiDeviceType {
     #[strum(serialize = "0", serialize = "Direct-Access")]
     DirectAccess,
@@ -1721,6 +2136,11 @@ pub fn get_device_info(device_path: impl AsRef<Path>) -> BlockResult<Device> {
         .and_then(Device::from_udev_device)
 }
 
+/// Persist the scheduler for a device by adding an echo command to /etc/rc.local
+#[deprecated(
+    note = "rc.local is keyed on the kernel name which can change between boots. \
+            Use udev_rules::QueueRules instead"
+)]
 pub fn set_elevator(device_path: impl AsRef<Path>, elevator: &Scheduler) -> BlockResult<usize> {
     let device_name = match device_path.as_ref().file_name() {
         Some(name) => name.to_string_lossy().into_owned(),
@@ -1747,6 +2167,8 @@ pub fn set_elevator(device_path: impl AsRef<Path>, elevator: &Scheduler) -> Bloc
     Ok(bytes_written)
 }
 
+/// Add a defrag job for `mount` to root's crontab
+#[deprecated(note = "Use maintenance::MaintenanceScheduler which can also remove and list jobs")]
 pub fn weekly_defrag(
     mount: impl AsRef<Path>,
     fs_type: &FilesystemType,
@@ -1757,7 +2179,12 @@ pub fn weekly_defrag(
         FilesystemType::Ext4 => "e4defrag",
         FilesystemType::Btrfs => "btrfs filesystem defragment -r",
         FilesystemType::Xfs => "xfs_fsr",
-        _ => "",
+        _ => {
+            return Err(BlockUtilsError::new(format!(
+                "Defrag is not supported on {} filesystems",
+                fs_type
+            )))
+        }
     };
     let job = format!(
         "{interval} {cmd} {path}",
@@ -0,0 +1,750 @@
+//! Scheduled filesystem maintenance.
+//!
+//! Installs defrag, fstrim, btrfs scrub/balance and xfs_scrub jobs either as
+//! systemd timer and service units or as entries in a managed cron.d file.
+//! Every job is keyed by its task and mount point so installing the same job
+//! twice replaces it instead of adding a duplicate.
+use crate::{process_output, run_command, BlockResult, BlockUtilsError, FilesystemType};
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+pub const CRON_FILE: &str = "/etc/cron.d/block-utils";
+
+const UNIT_PREFIX: &str = "block-utils-";
+const MARKER: &str = "# block-utils";
+
+/// Maintenance that can be scheduled on a mounted filesystem
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum MaintenanceTask {
+    /// e4defrag, btrfs filesystem defragment or xfs_fsr
+    Defrag,
+    Fstrim,
+    BtrfsScrub,
+    BtrfsBalance,
+    XfsScrub,
+}
+
+impl MaintenanceTask {
+    /// The command line that performs this task on `mount`.  Errors if the
+    /// task doesn't apply to `fs_type`.
+    pub fn command(
+        &self,
+        mount: impl AsRef<Path>,
+        fs_type: &FilesystemType,
+    ) -> BlockResult<Vec<String>> {
+        let mount = mount.as_ref().to_string_lossy().into_owned();
+        let args: Vec<&str> = match (*self, fs_type) {
+            (MaintenanceTask::Defrag, FilesystemType::Ext4) => vec!["e4defrag"],
+            (MaintenanceTask::Defrag, FilesystemType::Btrfs) => {
+                vec!["btrfs", "filesystem", "defragment", "-r"]
+            }
+            (MaintenanceTask::Defrag, FilesystemType::Xfs) => vec!["xfs_fsr"],
+            (MaintenanceTask::Fstrim, _) => vec!["fstrim", "-v"],
+            (MaintenanceTask::BtrfsScrub, FilesystemType::Btrfs) => {
+                vec!["btrfs", "scrub", "start", "-B"]
+            }
+            (MaintenanceTask::BtrfsBalance, FilesystemType::Btrfs) => {
+                vec!["btrfs", "balance", "start", "-dusage=50", "-musage=50"]
+            }
+            (MaintenanceTask::XfsScrub, FilesystemType::Xfs) => vec!["xfs_scrub"],
+            _ => {
+                return Err(BlockUtilsError::new(format!(
+                    "{} is not supported on {} filesystems",
+                    self, fs_type
+                )))
+            }
+        };
+        let mut command: Vec<String> = args.into_iter().map(String::from).collect();
+        command.push(mount);
+        Ok(command)
+    }
+}
+
+/// One field of a calendar spec
+#[derive(Clone, Copy, Debug)]
+struct FieldRange {
+    name: &'static str,
+    min: u32,
+    max: u32,
+}
+
+const MINUTE: FieldRange = FieldRange {
+    name: "minute",
+    min: 0,
+    max: 59,
+};
+const HOUR: FieldRange = FieldRange {
+    name: "hour",
+    min: 0,
+    max: 23,
+};
+const DAY_OF_MONTH: FieldRange = FieldRange {
+    name: "day of month",
+    min: 1,
+    max: 31,
+};
+const MONTH: FieldRange = FieldRange {
+    name: "month",
+    min: 1,
+    max: 12,
+};
+const DAY_OF_WEEK: FieldRange = FieldRange {
+    name: "day of week",
+    min: 0,
+    max: 7,
+};
+
+/// systemd weekday names, Monday first
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// A validated schedule in cron syntax: "minute hour day-of-month month
+/// day-of-week" or one of @hourly, @daily, @weekly and @monthly.
+///
+/// Fields accept `*`, numbers, `a-b` ranges, `*/n` steps and comma separated
+/// lists.  Restricting both day of month and day of week is rejected because
+/// cron ORs them while systemd ANDs them.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CalendarSpec {
+    fields: Vec<String>,
+}
+
+impl CalendarSpec {
+    pub fn hourly() -> Self {
+        CalendarSpec::from_str("@hourly").unwrap()
+    }
+
+    pub fn daily() -> Self {
+        CalendarSpec::from_str("@daily").unwrap()
+    }
+
+    pub fn weekly() -> Self {
+        CalendarSpec::from_str("@weekly").unwrap()
+    }
+
+    pub fn monthly() -> Self {
+        CalendarSpec::from_str("@monthly").unwrap()
+    }
+
+    /// The five cron fields
+    pub fn to_cron(&self) -> String {
+        self.fields.join(" ")
+    }
+
+    /// The equivalent systemd OnCalendar expression
+    pub fn to_on_calendar(&self) -> String {
+        let minute = systemd_field(&self.fields[0], 2, "0");
+        let hour = systemd_field(&self.fields[1], 2, "0");
+        let day = systemd_field(&self.fields[2], 2, "1");
+        let month = systemd_field(&self.fields[3], 2, "1");
+        let date_time = format!("*-{}-{} {}:{}:00", month, day, hour, minute);
+        if self.fields[4] == "*" {
+            date_time
+        } else {
+            format!("{} {}", systemd_weekdays(&self.fields[4]), date_time)
+        }
+    }
+}
+
+impl FromStr for CalendarSpec {
+    type Err = BlockUtilsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expanded = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<String> = expanded.split_whitespace().map(String::from).collect();
+        if fields.len() != 5 {
+            return Err(BlockUtilsError::new(format!(
+                "Invalid calendar spec {:?}.  Expected 5 fields: minute hour day-of-month month day-of-week",
+                s
+            )));
+        }
+        for (field, range) in fields
+            .iter()
+            .zip(&[MINUTE, HOUR, DAY_OF_MONTH, MONTH, DAY_OF_WEEK])
+        {
+            validate_field(field, range)?;
+        }
+        if fields[2] != "*" && fields[4] != "*" {
+            return Err(BlockUtilsError::new(format!(
+                "Invalid calendar spec {:?}.  Only one of day of month and day of week may be set",
+                s
+            )));
+        }
+        Ok(CalendarSpec { fields })
+    }
+}
+
+impl TryFrom<String> for CalendarSpec {
+    type Error = BlockUtilsError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        CalendarSpec::from_str(&s)
+    }
+}
+
+impl From<CalendarSpec> for String {
+    fn from(spec: CalendarSpec) -> String {
+        spec.to_cron()
+    }
+}
+
+impl fmt::Display for CalendarSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_cron())
+    }
+}
+
+fn validate_field(field: &str, range: &FieldRange) -> BlockResult<()> {
+    let invalid =
+        |why: String| BlockUtilsError::new(format!("Invalid {} {:?}: {}", range.name, field, why));
+    let number = |s: &str| -> BlockResult<u32> {
+        let n = s
+            .parse::<u32>()
+            .map_err(|_| invalid(format!("{} is not a number", s)))?;
+        if n < range.min || n > range.max {
+            return Err(invalid(format!(
+                "{} is outside {}-{}",
+                n, range.min, range.max
+            )));
+        }
+        Ok(n)
+    };
+    for item in field.split(',') {
+        if item == "*" {
+            continue;
+        }
+        if let Some(step) = item.strip_prefix("*/") {
+            // systemd can't express steps over weekdays
+            if range.name == DAY_OF_WEEK.name {
+                return Err(invalid("steps are not supported".into()));
+            }
+            let step = step
+                .parse::<u32>()
+                .map_err(|_| invalid(format!("{} is not a number", step)))?;
+            if step == 0 || step > range.max {
+                return Err(invalid(format!("step {} is out of range", step)));
+            }
+            continue;
+        }
+        match item.split_once('-') {
+            Some((start, end)) => {
+                if number(start)? > number(end)? {
+                    return Err(invalid(format!("range {} is backwards", item)));
+                }
+            }
+            None => {
+                number(item)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Convert one validated cron field to systemd syntax
+fn systemd_field(field: &str, width: usize, step_start: &str) -> String {
+    let pad = |n: &str| format!("{:0>width$}", n, width = width);
+    field
+        .split(',')
+        .map(|item| {
+            if item == "*" {
+                "*".to_string()
+            } else if let Some(step) = item.strip_prefix("*/") {
+                format!("{}/{}", pad(step_start), step)
+            } else if let Some((start, end)) = item.split_once('-') {
+                format!("{}..{}", pad(start), pad(end))
+            } else {
+                pad(item)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Convert a validated cron day of week field to systemd syntax.  Cron
+/// counts from Sunday as 0 or 7 while systemd ranges run Monday to Sunday, so
+/// the field is expanded to a set of days and written back as Monday first
+/// runs, ie: cron 0-5 becomes Mon..Fri,Sun
+fn systemd_weekdays(field: &str) -> String {
+    // Monday is 0 and Sunday is 6
+    let mut days = [false; 7];
+    let number = |n: &str| n.parse::<usize>().unwrap_or(0);
+    for item in field.split(',') {
+        let (start, end) = item.split_once('-').unwrap_or((item, item));
+        for day in number(start)..=number(end) {
+            days[(day + 6) % 7] = true;
+        }
+    }
+    let mut runs = Vec::new();
+    let mut day = 0;
+    while day < 7 {
+        if !days[day] {
+            day += 1;
+            continue;
+        }
+        let start = day;
+        while day + 1 < 7 && days[day + 1] {
+            day += 1;
+        }
+        runs.push(if start == day {
+            WEEKDAYS[start].to_string()
+        } else {
+            format!("{}..{}", WEEKDAYS[start], WEEKDAYS[day])
+        });
+        day += 1;
+    }
+    runs.join(",")
+}
+
+/// A scheduled maintenance task on a mount point
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceJob {
+    pub task: MaintenanceTask,
+    pub mount: PathBuf,
+    pub schedule: CalendarSpec,
+}
+
+impl MaintenanceJob {
+    /// The marker comment written next to the job so it can be listed later
+    fn marker(&self) -> String {
+        format!(
+            "{} task={} schedule={} mount={}",
+            MARKER,
+            self.task,
+            self.schedule,
+            self.mount.display()
+        )
+    }
+
+    fn from_marker(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix(MARKER)?.trim_start();
+        let rest = rest.strip_prefix("task=")?;
+        let (task, rest) = rest.split_once(" schedule=")?;
+        let (schedule, mount) = rest.split_once(" mount=")?;
+        Some(MaintenanceJob {
+            task: MaintenanceTask::from_str(task).ok()?,
+            mount: PathBuf::from(mount),
+            schedule: CalendarSpec::from_str(schedule).ok()?,
+        })
+    }
+
+    /// Unit name without the .service or .timer suffix, ie:
+    /// block-utils-fstrim-mnt-data
+    pub fn unit_name(&self) -> String {
+        format!("{}{}-{}", UNIT_PREFIX, self.task, escape_path(&self.mount))
+    }
+}
+
+/// Where jobs are installed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Backend {
+    /// One .service and one .timer unit per job in this directory
+    Systemd { unit_dir: PathBuf },
+    /// Entries in this cron.d style file
+    Cron { path: PathBuf },
+}
+
+/// Installs, lists and removes maintenance jobs
+#[derive(Clone, Debug)]
+pub struct MaintenanceScheduler {
+    backend: Backend,
+}
+
+impl MaintenanceScheduler {
+    /// Systemd timers in /etc/systemd/system
+    pub fn systemd() -> Self {
+        MaintenanceScheduler {
+            backend: Backend::Systemd {
+                unit_dir: PathBuf::from(SYSTEMD_UNIT_DIR),
+            },
+        }
+    }
+
+    /// Cron entries in /etc/cron.d/block-utils
+    pub fn cron() -> Self {
+        MaintenanceScheduler {
+            backend: Backend::Cron {
+                path: PathBuf::from(CRON_FILE),
+            },
+        }
+    }
+
+    pub fn with_backend(backend: Backend) -> Self {
+        MaintenanceScheduler { backend }
+    }
+
+    /// Install `job`, replacing an existing job with the same task and mount
+    pub fn install(&self, job: &MaintenanceJob, fs_type: &FilesystemType) -> BlockResult<()> {
+        self.write_job(job, fs_type)?;
+        if let Backend::Systemd { .. } = self.backend {
+            systemctl(&["daemon-reload"])?;
+            systemctl(&["enable", "--now", &format!("{}.timer", job.unit_name())])?;
+        }
+        Ok(())
+    }
+
+    /// Remove the job for `task` on `mount`.  Returns true if there was one.
+    pub fn remove(&self, task: MaintenanceTask, mount: impl AsRef<Path>) -> BlockResult<bool> {
+        let job = match self
+            .list()?
+            .into_iter()
+            .find(|j| j.task == task && j.mount == mount.as_ref())
+        {
+            Some(job) => job,
+            None => return Ok(false),
+        };
+        if let Backend::Systemd { .. } = self.backend {
+            systemctl(&["disable", "--now", &format!("{}.timer", job.unit_name())])?;
+        }
+        self.delete_job(&job)?;
+        if let Backend::Systemd { .. } = self.backend {
+            systemctl(&["daemon-reload"])?;
+        }
+        Ok(true)
+    }
+
+    /// All jobs installed by this crate
+    pub fn list(&self) -> BlockResult<Vec<MaintenanceJob>> {
+        let mut jobs = Vec::new();
+        match self.backend {
+            Backend::Systemd { ref unit_dir } => {
+                if !unit_dir.exists() {
+                    return Ok(jobs);
+                }
+                for entry in fs::read_dir(unit_dir)? {
+                    let path = entry?.path();
+                    let name = path
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    if name.starts_with(UNIT_PREFIX) && name.ends_with(".timer") {
+                        jobs.extend(
+                            fs::read_to_string(&path)?
+                                .lines()
+                                .filter_map(MaintenanceJob::from_marker),
+                        );
+                    }
+                }
+            }
+            Backend::Cron { ref path } => {
+                if path.exists() {
+                    jobs.extend(
+                        fs::read_to_string(path)?
+                            .lines()
+                            .filter_map(MaintenanceJob::from_marker),
+                    );
+                }
+            }
+        }
+        jobs.sort_by(|a, b| (&a.mount, a.task.to_string()).cmp(&(&b.mount, b.task.to_string())));
+        Ok(jobs)
+    }
+
+    fn write_job(&self, job: &MaintenanceJob, fs_type: &FilesystemType) -> BlockResult<()> {
+        // A newline would end the marker, cron entry or unit setting early
+        if job.mount.to_string_lossy().contains('\n') {
+            return Err(BlockUtilsError::new(format!(
+                "Mount path {:?} contains a newline",
+                job.mount
+            )));
+        }
+        let command = job.task.command(&job.mount, fs_type)?;
+        match self.backend {
+            Backend::Systemd { ref unit_dir } => {
+                let service = format!(
+                    "{marker}\n\
+                     [Unit]\n\
+                     Description=block-utils {task} of {mount}\n\
+                     RequiresMountsFor={mount}\n\
+                     \n\
+                     [Service]\n\
+                     Type=oneshot\n\
+                     ExecStart={exec}\n",
+                    marker = job.marker(),
+                    task = job.task,
+                    mount = quote_systemd(&job.mount.to_string_lossy()),
+                    exec = command
+                        .iter()
+                        .map(|arg| quote_exec_arg(arg))
+                        .collect::<Vec<String>>()
+                        .join(" "),
+                );
+                let timer = format!(
+                    "{marker}\n\
+                     [Unit]\n\
+                     Description=block-utils {task} of {mount} timer\n\
+                     \n\
+                     [Timer]\n\
+                     OnCalendar={calendar}\n\
+                     Persistent=true\n\
+                     \n\
+                     [Install]\n\
+                     WantedBy=timers.target\n",
+                    marker = job.marker(),
+                    task = job.task,
+                    mount = quote_systemd(&job.mount.to_string_lossy()),
+                    calendar = job.schedule.to_on_calendar(),
+                );
+                fs::write(
+                    unit_dir.join(format!("{}.service", job.unit_name())),
+                    service,
+                )?;
+                fs::write(unit_dir.join(format!("{}.timer", job.unit_name())), timer)?;
+            }
+            Backend::Cron { ref path } => {
+                let mut lines = self.cron_lines_without(path, job)?;
+                lines.push(job.marker());
+                lines.push(format!(
+                    "{} root {}",
+                    job.schedule.to_cron(),
+                    command
+                        .iter()
+                        .map(|arg| quote_shell(arg))
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                ));
+                fs::write(path, format!("{}\n", lines.join("\n")))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_job(&self, job: &MaintenanceJob) -> BlockResult<()> {
+        match self.backend {
+            Backend::Systemd { ref unit_dir } => {
+                for suffix in &["service", "timer"] {
+                    let unit = unit_dir.join(format!("{}.{}", job.unit_name(), suffix));
+                    if unit.exists() {
+                        fs::remove_file(unit)?;
+                    }
+                }
+            }
+            Backend::Cron { ref path } => {
+                let lines = self.cron_lines_without(path, job)?;
+                if lines.is_empty() {
+                    fs::remove_file(path)?;
+                } else {
+                    fs::write(path, format!("{}\n", lines.join("\n")))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The cron file minus the marker and entry of any job matching `job`
+    fn cron_lines_without(&self, path: &Path, job: &MaintenanceJob) -> BlockResult<Vec<String>> {
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let mut lines = Vec::new();
+        let mut skip_next = false;
+        for line in fs::read_to_string(path)?.lines() {
+            if skip_next {
+                skip_next = false;
+                continue;
+            }
+            if let Some(existing) = MaintenanceJob::from_marker(line) {
+                if existing.task == job.task && existing.mount == job.mount {
+                    skip_next = true;
+                    continue;
+                }
+            }
+            lines.push(line.to_string());
+        }
+        Ok(lines)
+    }
+}
+
+pub(crate) fn systemctl(args: &[&str]) -> BlockResult<()> {
+    process_output(&run_command("systemctl", args)?)?;
+    Ok(())
+}
+
+/// Escape a path the way `systemd-escape --path` does
+pub(crate) fn escape_path(path: &Path) -> String {
+    let s = path.to_string_lossy();
+    let trimmed = s.trim_matches('/');
+    if trimmed.is_empty() {
+        return "-".to_string();
+    }
+    let mut out = String::new();
+    for (i, b) in trimmed.bytes().enumerate() {
+        match b {
+            b'/' => out.push('-'),
+            b'.' if i == 0 => out.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out
+}
+
+/// Quote `arg` for a systemd unit setting.  % starts a specifier so it's
+/// doubled
+pub(crate) fn quote_systemd(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+/// ExecStart= also expands $ as environment variables
+fn quote_exec_arg(arg: &str) -> String {
+    quote_systemd(&arg.replace('$', "$$"))
+}
+
+fn quote_shell(arg: &str) -> String {
+    if arg
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/-_.=:".contains(c))
+    {
+        arg.to_string()
+    } else {
+        // cron treats % as a newline so it needs escaping too
+        format!("'{}'", arg.replace('\'', "'\\''").replace('%', "\\%"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_calendar_spec() {
+        assert_eq!(
+            CalendarSpec::weekly().to_on_calendar(),
+            "Sun *-*-* 00:00:00"
+        );
+        assert_eq!(CalendarSpec::monthly().to_on_calendar(), "*-*-01 00:00:00");
+        let spec = CalendarSpec::from_str("*/15 2-4 * * 1-5").unwrap();
+        assert_eq!(spec.to_on_calendar(), "Mon..Fri *-*-* 02..04:00/15:00");
+        assert_eq!(spec.to_cron(), "*/15 2-4 * * 1-5");
+
+        // Cron weeks start on Sunday, systemd ranges on Monday
+        let on_calendar = |s: &str| CalendarSpec::from_str(s).unwrap().to_on_calendar();
+        assert_eq!(on_calendar("0 0 * * 0-5"), "Mon..Fri,Sun *-*-* 00:00:00");
+        assert_eq!(on_calendar("0 0 * * 0-7"), "Mon..Sun *-*-* 00:00:00");
+        assert_eq!(on_calendar("0 0 * * 5-7"), "Fri..Sun *-*-* 00:00:00");
+        assert_eq!(on_calendar("0 0 * * 7,3"), "Wed,Sun *-*-* 00:00:00");
+
+        assert!(CalendarSpec::from_str("60 * * * *").is_err());
+        assert!(CalendarSpec::from_str("0 0 * *").is_err());
+        assert!(CalendarSpec::from_str("0 0 1 * 1").is_err());
+        assert!(CalendarSpec::from_str("0 5-2 * * *").is_err());
+        assert!(CalendarSpec::from_str("0 0 * * */2").is_err());
+    }
+
+    #[test]
+    fn test_task_command() {
+        assert_eq!(
+            MaintenanceTask::Defrag
+                .command("/mnt/data", &FilesystemType::Xfs)
+                .unwrap(),
+            vec!["xfs_fsr", "/mnt/data"]
+        );
+        assert!(MaintenanceTask::Defrag
+            .command("/mnt/data", &FilesystemType::Vfat)
+            .is_err());
+        assert!(MaintenanceTask::BtrfsScrub
+            .command("/mnt/data", &FilesystemType::Ext4)
+            .is_err());
+    }
+
+    #[test]
+    fn test_systemd_units() {
+        let tmp_dir = TempDir::new().unwrap();
+        let scheduler = MaintenanceScheduler::with_backend(Backend::Systemd {
+            unit_dir: tmp_dir.path().to_path_buf(),
+        });
+        let job = MaintenanceJob {
+            task: MaintenanceTask::Fstrim,
+            mount: PathBuf::from("/mnt/my-data"),
+            schedule: CalendarSpec::weekly(),
+        };
+        assert_eq!(job.unit_name(), "block-utils-fstrim-mnt-my\\x2ddata");
+        scheduler.write_job(&job, &FilesystemType::Ext4).unwrap();
+        let timer = fs::read_to_string(
+            tmp_dir
+                .path()
+                .join("block-utils-fstrim-mnt-my\\x2ddata.timer"),
+        )
+        .unwrap();
+        assert!(timer.contains("OnCalendar=Sun *-*-* 00:00:00\n"));
+        assert_eq!(scheduler.list().unwrap(), vec![job.clone()]);
+        scheduler.delete_job(&job).unwrap();
+        assert!(scheduler.list().unwrap().is_empty());
+
+        let expanding = MaintenanceJob {
+            mount: PathBuf::from("/mnt/100%$HOME"),
+            ..job.clone()
+        };
+        scheduler
+            .write_job(&expanding, &FilesystemType::Ext4)
+            .unwrap();
+        let service = fs::read_to_string(
+            tmp_dir
+                .path()
+                .join(format!("{}.service", expanding.unit_name())),
+        )
+        .unwrap();
+        assert!(service.contains("RequiresMountsFor=/mnt/100%%$HOME\n"));
+        assert!(service.contains(" /mnt/100%%$$HOME\n"));
+
+        let newline = MaintenanceJob {
+            mount: PathBuf::from("/mnt/a\nb"),
+            ..job
+        };
+        assert!(scheduler
+            .write_job(&newline, &FilesystemType::Ext4)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cron_entries() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("block-utils");
+        let scheduler = MaintenanceScheduler::with_backend(Backend::Cron { path: path.clone() });
+        let scrub = MaintenanceJob {
+            task: MaintenanceTask::BtrfsScrub,
+            mount: PathBuf::from("/srv/my data"),
+            schedule: CalendarSpec::monthly(),
+        };
+        let defrag = MaintenanceJob {
+            task: MaintenanceTask::Defrag,
+            mount: PathBuf::from("/srv/my data"),
+            schedule: CalendarSpec::weekly(),
+        };
+        scheduler.install(&scrub, &FilesystemType::Btrfs).unwrap();
+        scheduler.install(&defrag, &FilesystemType::Btrfs).unwrap();
+        // Installing again replaces instead of duplicating
+        scheduler.install(&scrub, &FilesystemType::Btrfs).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+        assert!(contents.contains("0 0 1 * * root btrfs scrub start -B '/srv/my data'\n"));
+        assert_eq!(scheduler.list().unwrap().len(), 2);
+
+        assert!(scheduler
+            .remove(MaintenanceTask::Defrag, "/srv/my data")
+            .unwrap());
+        assert!(!scheduler.remove(MaintenanceTask::Defrag, "/srv").unwrap());
+        assert_eq!(scheduler.list().unwrap(), vec![scrub]);
+    }
+}
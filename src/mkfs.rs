@@ -0,0 +1,1675 @@
+//! The commands that create a filesystem.
+//!
+//! `Filesystem::mkfs_plan` turns the options into a `MkfsPlan`: the mkfs
+//! program, its arguments and whatever has to run after it succeeds.  Both
+//! `format_block_device` and `async_format_block_device` execute the same
+//! plan so the two can't disagree about arguments.
+//!
+//! Filesystems spanning several devices are described with `BtrfsSpec` and
+//! `ZpoolSpec` instead, which produce the same kind of plan.
+use crate::safety::{self, Preflight};
+use crate::{
+    process_output, run_command, AsyncInit, BlockResult, BlockUtilsError, BtrfsChecksum,
+    Filesystem, MetadataProfile,
+};
+
+use log::warn;
+use uuid::Uuid;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where mkfs programs live when they aren't in PATH, ie: for non root users
+const SBIN_DIRS: &[&str] = &["/sbin", "/usr/sbin"];
+
+/// Everything needed to create a filesystem on a device
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MkfsPlan {
+    /// Program that creates the filesystem, ie: mkfs.xfs
+    pub program: String,
+    pub args: Vec<String>,
+    /// Commands to run once the program succeeded, ie: zfs set
+    pub post_steps: Vec<(String, Vec<String>)>,
+    /// Package that provides the programs
+    pub package: &'static str,
+}
+
+impl MkfsPlan {
+    fn new(program: &str, package: &'static str) -> Self {
+        MkfsPlan {
+            program: program.to_string(),
+            args: vec![],
+            post_steps: vec![],
+            package,
+        }
+    }
+
+    fn arg(&mut self, arg: impl Into<String>) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    fn opt(&mut self, flag: &str, value: impl ToString) -> &mut Self {
+        self.arg(flag).arg(value.to_string())
+    }
+
+    /// The plan as a shell like command line, for logging
+    pub fn command_line(&self) -> String {
+        std::iter::once(&self.program)
+            .chain(self.args.iter())
+            .map(|s| s.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    /// Run the program and then the post steps, stopping at the first failure
+    pub fn run(&self) -> BlockResult<()> {
+        let program = self.find_program(&self.program)?;
+        process_output(&run_command(&program.to_string_lossy(), &self.args)?)?;
+        for (step, args) in &self.post_steps {
+            let step = self.find_program(step)?;
+            process_output(&run_command(&step.to_string_lossy(), args)?)?;
+        }
+        Ok(())
+    }
+
+    /// Start the program without waiting for it.  The post steps are handed
+    /// back in the `AsyncInit` for the caller to run after it exits
+    pub fn spawn(&self, device: impl AsRef<Path>) -> BlockResult<AsyncInit> {
+        let program = self.find_program(&self.program)?;
+        let post_setup_commands = self
+            .post_steps
+            .iter()
+            .map(|(step, args)| {
+                let step = self.find_program(step)?;
+                Ok((step.to_string_lossy().into_owned(), args.clone()))
+            })
+            .collect::<BlockResult<Vec<_>>>()?;
+        Ok(AsyncInit {
+            format_child: Command::new(program).args(&self.args).spawn()?,
+            post_setup_commands,
+            device: device.as_ref().to_owned(),
+        })
+    }
+
+    /// Look for `name` in PATH and the sbin directories
+    fn find_program(&self, name: &str) -> BlockResult<PathBuf> {
+        let path_dirs = env::var_os("PATH")
+            .map(|p| env::split_paths(&p).collect::<Vec<PathBuf>>())
+            .unwrap_or_default();
+        path_dirs
+            .into_iter()
+            .chain(SBIN_DIRS.iter().map(PathBuf::from))
+            .map(|dir| dir.join(name))
+            .find(|p| p.is_file())
+            .ok_or_else(|| {
+                BlockUtilsError::new(format!(
+                    "{} not found.  Please install {}",
+                    name, self.package
+                ))
+            })
+    }
+}
+
+/// XFS block sizes from the man page: "The default value is 4096 bytes
+/// (4 KiB), the minimum is 512, and the maximum is 65536 (64 KiB).  XFS on
+/// Linux currently only supports pagesize or smaller blocks."
+fn clamp_xfs_block_size(size: u64) -> u64 {
+    if size < 512 {
+        warn!("xfs block size must be 512 bytes minimum.  Correcting");
+        512
+    } else if size > 65536 {
+        warn!("xfs block size must be 65536 bytes maximum.  Correcting");
+        65536
+    } else {
+        size
+    }
+}
+
+/// mkfs truncates or rejects labels that are too long, depending on the
+/// filesystem.  Refuse them up front instead
+fn check_label(label: &Option<String>, max_len: usize, fs: &str) -> BlockResult<()> {
+    match label {
+        Some(label) if label.len() > max_len => Err(BlockUtilsError::new(format!(
+            "{} labels can be at most {} bytes, {} is {}",
+            fs,
+            max_len,
+            label,
+            label.len()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// An ext4 -O feature, prefixed with ^ to turn it off
+fn ext_feature(name: &str, enabled: Option<bool>) -> Option<String> {
+    enabled.map(|on| {
+        if on {
+            name.to_string()
+        } else {
+            format!("^{}", name)
+        }
+    })
+}
+
+/// The 1/0 booleans of the mkfs.xfs suboptions
+fn xfs_bool(name: &str, enabled: Option<bool>) -> Option<String> {
+    enabled.map(|on| format!("{}={}", name, on as u8))
+}
+
+/// The options mkfs.ext2, mkfs.ext3 and mkfs.ext4 share
+fn ext_plan(
+    program: &str,
+    inode_size: u64,
+    reserved_blocks_percentage: u8,
+    label: &Option<String>,
+    uuid: Option<Uuid>,
+) -> BlockResult<MkfsPlan> {
+    check_label(label, 16, program)?;
+    let mut plan = MkfsPlan::new(program, "e2fsprogs");
+    plan.opt("-I", inode_size)
+        .opt("-m", reserved_blocks_percentage);
+    if let Some(label) = label {
+        plan.opt("-L", label);
+    }
+    if let Some(uuid) = uuid {
+        plan.opt("-U", uuid.hyphenated());
+    }
+    Ok(plan)
+}
+
+impl Filesystem {
+    /// The commands that create this filesystem on `device`
+    pub fn mkfs_plan(&self, device: impl AsRef<Path>) -> BlockResult<MkfsPlan> {
+        let device_arg = device.as_ref().to_string_lossy().into_owned();
+        let mut plan = match *self {
+            Filesystem::Btrfs {
+                ref metadata_profile,
+                leaf_size,
+                node_size,
+                ref label,
+                uuid,
+                ref data_profile,
+                checksum,
+            } => {
+                check_label(label, 255, "btrfs")?;
+                let mut plan = MkfsPlan::new("mkfs.btrfs", "btrfs-progs");
+                plan.opt("-m", metadata_profile)
+                    .opt("-l", leaf_size)
+                    .opt("-n", node_size);
+                if let Some(data_profile) = data_profile {
+                    plan.opt("-d", data_profile);
+                }
+                if let Some(checksum) = checksum {
+                    plan.opt("--csum", checksum);
+                }
+                if let Some(label) = label {
+                    plan.opt("-L", label);
+                }
+                if let Some(uuid) = uuid {
+                    plan.opt("-U", uuid.hyphenated());
+                }
+                plan
+            }
+            Filesystem::Xfs {
+                block_size,
+                force,
+                inode_size,
+                stripe_size,
+                stripe_width,
+                agcount,
+                ref label,
+                uuid,
+                reflink,
+                crc,
+                rmapbt,
+                log_size,
+                ref realtime_device,
+            } => {
+                check_label(label, 12, "xfs")?;
+                let mut plan = MkfsPlan::new("mkfs.xfs", "xfsprogs");
+                if let Some(b) = block_size {
+                    plan.opt("-b", format!("size={}", clamp_xfs_block_size(b)));
+                }
+                if let Some(i) = inode_size {
+                    plan.opt("-i", format!("size={}", i));
+                }
+                if force {
+                    plan.arg("-f");
+                }
+                let mut data = vec![];
+                // agcount only goes with a stripe geometry, otherwise
+                // mkfs.xfs picks it from the device size
+                if let (Some(su), Some(sw)) = (stripe_size, stripe_width) {
+                    data.push(format!("su={}", su));
+                    data.push(format!("sw={}", sw));
+                    if let Some(ag) = agcount {
+                        data.push(format!("agcount={}", ag));
+                    }
+                }
+                if !data.is_empty() {
+                    plan.opt("-d", data.join(","));
+                }
+                let mut metadata: Vec<String> = vec![
+                    xfs_bool("crc", crc),
+                    xfs_bool("reflink", reflink),
+                    xfs_bool("rmapbt", rmapbt),
+                ]
+                .into_iter()
+                .flatten()
+                .collect();
+                if let Some(uuid) = uuid {
+                    metadata.push(format!("uuid={}", uuid.hyphenated()));
+                }
+                if !metadata.is_empty() {
+                    plan.opt("-m", metadata.join(","));
+                }
+                if let Some(log_size) = log_size {
+                    plan.opt("-l", format!("size={}", log_size));
+                }
+                if let Some(rtdev) = realtime_device {
+                    plan.opt("-r", format!("rtdev={}", rtdev.display()));
+                }
+                if let Some(label) = label {
+                    plan.opt("-L", label);
+                }
+                plan
+            }
+            Filesystem::Ext4 {
+                inode_size,
+                reserved_blocks_percentage,
+                stride,
+                stripe_width,
+                ref label,
+                uuid,
+                journal_size,
+                lazy_itable_init,
+                metadata_csum,
+                sixty_four_bit,
+                bigalloc,
+            } => {
+                let mut plan = ext_plan(
+                    "mkfs.ext4",
+                    inode_size,
+                    reserved_blocks_percentage,
+                    label,
+                    uuid,
+                )?;
+                if let Some(journal_size) = journal_size {
+                    plan.opt("-J", format!("size={}", journal_size));
+                }
+                let features: Vec<String> = vec![
+                    ext_feature("metadata_csum", metadata_csum),
+                    ext_feature("64bit", sixty_four_bit),
+                    ext_feature("bigalloc", bigalloc),
+                ]
+                .into_iter()
+                .flatten()
+                .collect();
+                if !features.is_empty() {
+                    plan.opt("-O", features.join(","));
+                }
+                let mut extended = vec![];
+                if let Some(stride) = stride {
+                    extended.push(format!("stride={}", stride));
+                }
+                if let Some(stripe_width) = stripe_width {
+                    extended.push(format!("stripe_width={}", stripe_width));
+                }
+                if let Some(lazy) = lazy_itable_init {
+                    extended.push(format!("lazy_itable_init={}", lazy as u8));
+                }
+                if !extended.is_empty() {
+                    plan.opt("-E", extended.join(","));
+                }
+                plan
+            }
+            Filesystem::Zfs {
+                block_size,
+                compression,
+                ref label,
+            } => {
+                let name = match label {
+                    Some(label) => label.clone(),
+                    None => device
+                        .as_ref()
+                        .file_name()
+                        .ok_or_else(|| {
+                            BlockUtilsError::new(format!(
+                                "Unable to determine filename for device: {:?}",
+                                device.as_ref()
+                            ))
+                        })?
+                        .to_string_lossy()
+                        .into_owned(),
+                };
+                let mut plan = MkfsPlan::new("zpool", "zfsutils-linux");
+                // The pool is named after the device unless labelled and
+                // mounted at /mnt/{pool}
+                plan.arg("create")
+                    .arg("-f")
+                    .opt("-m", format!("/mnt/{}", name))
+                    .arg(name.clone());
+                let mut properties = vec![];
+                if let Some(b) = block_size {
+                    properties.push(format!("recordsize={}", b));
+                }
+                if let Some(c) = compression {
+                    properties.push(format!("compression={}", if c { "on" } else { "off" }));
+                }
+                properties.push("acltype=posixacl".to_string());
+                properties.push("atime=off".to_string());
+                plan.post_steps = properties
+                    .into_iter()
+                    .map(|p| ("zfs".to_string(), vec!["set".to_string(), p, name.clone()]))
+                    .collect();
+                plan
+            }
+            Filesystem::Ext2 {
+                inode_size,
+                reserved_blocks_percentage,
+                ref label,
+                uuid,
+            } => ext_plan(
+                "mkfs.ext2",
+                inode_size,
+                reserved_blocks_percentage,
+                label,
+                uuid,
+            )?,
+            Filesystem::Ext3 {
+                inode_size,
+                reserved_blocks_percentage,
+                ref label,
+                uuid,
+                journal_size,
+            } => {
+                let mut plan = ext_plan(
+                    "mkfs.ext3",
+                    inode_size,
+                    reserved_blocks_percentage,
+                    label,
+                    uuid,
+                )?;
+                if let Some(journal_size) = journal_size {
+                    plan.opt("-J", format!("size={}", journal_size));
+                }
+                plan
+            }
+            Filesystem::Vfat {
+                fat_size,
+                sectors_per_cluster,
+                ref label,
+                volume_id,
+            } => {
+                check_label(label, 11, "vfat")?;
+                let mut plan = MkfsPlan::new("mkfs.vfat", "dosfstools");
+                if let Some(fat_size) = fat_size {
+                    if ![12, 16, 32].contains(&fat_size) {
+                        return Err(BlockUtilsError::new(format!(
+                            "FAT size must be 12, 16 or 32, not {}",
+                            fat_size
+                        )));
+                    }
+                    plan.opt("-F", fat_size);
+                }
+                if let Some(sectors) = sectors_per_cluster {
+                    plan.opt("-s", sectors);
+                }
+                if let Some(label) = label {
+                    plan.opt("-n", label);
+                }
+                if let Some(id) = volume_id {
+                    plan.opt("-i", format!("{:08X}", id));
+                }
+                plan
+            }
+            Filesystem::Exfat {
+                cluster_size,
+                ref label,
+                uuid,
+            } => {
+                check_label(label, 15, "exfat")?;
+                let mut plan = MkfsPlan::new("mkfs.exfat", "exfatprogs");
+                if let Some(cluster_size) = cluster_size {
+                    plan.opt("-c", cluster_size);
+                }
+                if let Some(label) = label {
+                    plan.opt("-L", label);
+                }
+                if let Some(uuid) = uuid {
+                    plan.opt("-U", uuid.hyphenated());
+                }
+                plan
+            }
+            Filesystem::Ntfs {
+                quick,
+                force,
+                cluster_size,
+                ref label,
+            } => {
+                check_label(label, 128, "ntfs")?;
+                let mut plan = MkfsPlan::new("mkfs.ntfs", "ntfs-3g");
+                if quick {
+                    plan.arg("-Q");
+                }
+                if force {
+                    plan.arg("-F");
+                }
+                if let Some(cluster_size) = cluster_size {
+                    plan.opt("-c", cluster_size);
+                }
+                if let Some(label) = label {
+                    plan.opt("-L", label);
+                }
+                plan
+            }
+            Filesystem::F2fs {
+                force,
+                overprovision,
+                ref features,
+                ref label,
+                uuid,
+            } => {
+                check_label(label, 512, "f2fs")?;
+                let mut plan = MkfsPlan::new("mkfs.f2fs", "f2fs-tools");
+                if force {
+                    plan.arg("-f");
+                }
+                if let Some(overprovision) = overprovision {
+                    plan.opt("-o", overprovision);
+                }
+                if !features.is_empty() {
+                    plan.opt("-O", features.join(","));
+                }
+                if let Some(label) = label {
+                    plan.opt("-l", label);
+                }
+                if let Some(uuid) = uuid {
+                    plan.opt("-U", uuid.hyphenated());
+                }
+                plan
+            }
+            Filesystem::Swap {
+                page_size,
+                ref label,
+                uuid,
+            } => {
+                check_label(label, 16, "swap")?;
+                let mut plan = MkfsPlan::new("mkswap", "util-linux");
+                if let Some(page_size) = page_size {
+                    plan.opt("-p", page_size);
+                }
+                if let Some(label) = label {
+                    plan.opt("-L", label);
+                }
+                if let Some(uuid) = uuid {
+                    plan.opt("-U", uuid.hyphenated());
+                }
+                plan
+            }
+            Filesystem::Bcachefs {
+                ref compression,
+                replicas,
+                ref label,
+                uuid,
+            } => {
+                let mut plan = MkfsPlan::new("bcachefs", "bcachefs-tools");
+                plan.arg("format");
+                if let Some(compression) = compression {
+                    plan.arg(format!("--compression={}", compression));
+                }
+                if let Some(replicas) = replicas {
+                    plan.arg(format!("--replicas={}", replicas));
+                }
+                if let Some(label) = label {
+                    plan.arg(format!("--fs_label={}", label));
+                }
+                if let Some(uuid) = uuid {
+                    plan.arg(format!("--uuid={}", uuid.hyphenated()));
+                }
+                plan
+            }
+        };
+        plan.arg(device_arg);
+        Ok(plan)
+    }
+}
+
+/// A btrfs filesystem spanning several devices
+///
+/// ```no_run
+/// use block_utils::mkfs::BtrfsSpec;
+/// use block_utils::MetadataProfile;
+///
+/// BtrfsSpec::new(&["/dev/sdb", "/dev/sdc", "/dev/sdd"])
+///     .data_profile(MetadataProfile::Raid1)
+///     .metadata_profile(MetadataProfile::Raid1c3)
+///     .label("data")
+///     .create()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct BtrfsSpec {
+    devices: Vec<PathBuf>,
+    data_profile: Option<MetadataProfile>,
+    metadata_profile: Option<MetadataProfile>,
+    node_size: Option<u64>,
+    checksum: Option<BtrfsChecksum>,
+    label: Option<String>,
+    uuid: Option<Uuid>,
+    force: bool,
+}
+
+impl BtrfsSpec {
+    pub fn new<P: AsRef<Path>>(devices: &[P]) -> Self {
+        BtrfsSpec {
+            devices: devices.iter().map(|d| d.as_ref().to_path_buf()).collect(),
+            data_profile: None,
+            metadata_profile: None,
+            node_size: None,
+            checksum: None,
+            label: None,
+            uuid: None,
+            force: false,
+        }
+    }
+
+    /// How data is spread over the devices.  mkfs.btrfs defaults to single
+    pub fn data_profile(mut self, profile: MetadataProfile) -> Self {
+        self.data_profile = Some(profile);
+        self
+    }
+
+    /// How metadata is spread over the devices.  mkfs.btrfs defaults to raid1
+    /// for multiple devices
+    pub fn metadata_profile(mut self, profile: MetadataProfile) -> Self {
+        self.metadata_profile = Some(profile);
+        self
+    }
+
+    pub fn node_size(mut self, node_size: u64) -> Self {
+        self.node_size = Some(node_size);
+        self
+    }
+
+    pub fn checksum(mut self, checksum: BtrfsChecksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// Overwrite existing filesystems on the devices
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn mkfs_plan(&self) -> BlockResult<MkfsPlan> {
+        if self.devices.is_empty() {
+            return Err(BlockUtilsError::new(
+                "btrfs needs at least one device".to_string(),
+            ));
+        }
+        for profile in self.data_profile.iter().chain(self.metadata_profile.iter()) {
+            let needed = btrfs_min_devices(profile);
+            if self.devices.len() < needed {
+                return Err(BlockUtilsError::new(format!(
+                    "btrfs {} needs at least {} devices, got {}",
+                    profile,
+                    needed,
+                    self.devices.len()
+                )));
+            }
+        }
+        check_label(&self.label, 255, "btrfs")?;
+        let mut plan = MkfsPlan::new("mkfs.btrfs", "btrfs-progs");
+        if self.force {
+            plan.arg("-f");
+        }
+        if let Some(ref profile) = self.data_profile {
+            plan.opt("-d", profile);
+        }
+        if let Some(ref profile) = self.metadata_profile {
+            plan.opt("-m", profile);
+        }
+        if let Some(node_size) = self.node_size {
+            plan.opt("-n", node_size);
+        }
+        if let Some(checksum) = self.checksum {
+            plan.opt("--csum", checksum);
+        }
+        if let Some(ref label) = self.label {
+            plan.opt("-L", label);
+        }
+        if let Some(uuid) = self.uuid {
+            plan.opt("-U", uuid.hyphenated());
+        }
+        for device in &self.devices {
+            plan.arg(device.to_string_lossy());
+        }
+        Ok(plan)
+    }
+
+    /// Create the filesystem.  Refuses if any of the devices is in use, see
+    /// `safety::check_not_in_use`
+    pub fn create(&self) -> BlockResult<()> {
+        self.create_with_preflight(Preflight::Check)
+    }
+
+    /// Same as `create` but `Preflight::Override` skips the in-use checks
+    pub fn create_with_preflight(&self, preflight: Preflight) -> BlockResult<()> {
+        let plan = self.mkfs_plan()?;
+        for device in &self.devices {
+            safety::preflight(device, preflight)?;
+        }
+        plan.run()
+    }
+}
+
+/// Fewest devices mkfs.btrfs accepts for `profile`
+fn btrfs_min_devices(profile: &MetadataProfile) -> usize {
+    match profile {
+        MetadataProfile::Single | MetadataProfile::Dup => 1,
+        MetadataProfile::Raid0
+        | MetadataProfile::Raid1
+        | MetadataProfile::Raid5
+        | MetadataProfile::Raid10 => 2,
+        MetadataProfile::Raid6 | MetadataProfile::Raid1c3 => 3,
+        MetadataProfile::Raid1c4 => 4,
+    }
+}
+
+/// Redundancy of a group of devices in a zfs pool
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VdevKind {
+    /// Devices striped without redundancy
+    Stripe,
+    Mirror,
+    Raidz1,
+    Raidz2,
+    Raidz3,
+    /// Distributed spare raidz
+    Draid {
+        /// 1 to 3
+        parity: u8,
+        /// Data devices per redundancy group
+        data: Option<u32>,
+        /// Distributed spares
+        spares: Option<u32>,
+    },
+}
+
+/// A group of devices in a zfs pool
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Vdev {
+    pub kind: VdevKind,
+    pub devices: Vec<PathBuf>,
+}
+
+impl Vdev {
+    pub fn new<P: AsRef<Path>>(kind: VdevKind, devices: &[P]) -> Self {
+        Vdev {
+            kind,
+            devices: devices.iter().map(|d| d.as_ref().to_path_buf()).collect(),
+        }
+    }
+
+    fn validate(&self) -> BlockResult<()> {
+        let needed = match self.kind {
+            VdevKind::Stripe => 1,
+            VdevKind::Mirror | VdevKind::Raidz1 => 2,
+            VdevKind::Raidz2 => 3,
+            VdevKind::Raidz3 => 4,
+            VdevKind::Draid {
+                parity,
+                data,
+                spares,
+            } => {
+                if !(1..=3).contains(&parity) {
+                    return Err(BlockUtilsError::new(format!(
+                        "draid parity must be 1 to 3, not {}",
+                        parity
+                    )));
+                }
+                parity as usize + data.unwrap_or(1) as usize + spares.unwrap_or(0) as usize
+            }
+        };
+        if self.devices.len() < needed {
+            return Err(BlockUtilsError::new(format!(
+                "{:?} vdev needs at least {} devices, got {}",
+                self.kind,
+                needed,
+                self.devices.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// The vdev as zpool create arguments, ie: raidz2 /dev/sdb /dev/sdc /dev/sdd
+    fn args(&self) -> Vec<String> {
+        let kind = match self.kind {
+            VdevKind::Stripe => None,
+            VdevKind::Mirror => Some("mirror".to_string()),
+            VdevKind::Raidz1 => Some("raidz1".to_string()),
+            VdevKind::Raidz2 => Some("raidz2".to_string()),
+            VdevKind::Raidz3 => Some("raidz3".to_string()),
+            VdevKind::Draid {
+                parity,
+                data,
+                spares,
+            } => {
+                let mut spec = format!("draid{}", parity);
+                if let Some(data) = data {
+                    spec.push_str(&format!(":{}d", data));
+                }
+                spec.push_str(&format!(":{}c", self.devices.len()));
+                if let Some(spares) = spares {
+                    spec.push_str(&format!(":{}s", spares));
+                }
+                Some(spec)
+            }
+        };
+        kind.into_iter()
+            .chain(
+                self.devices
+                    .iter()
+                    .map(|d| d.to_string_lossy().into_owned()),
+            )
+            .collect()
+    }
+}
+
+/// Pool names may not begin with these vdev types
+const ZPOOL_RESERVED_PREFIXES: &[&str] = &["mirror", "raidz", "draid", "spare"];
+
+/// A zfs pool to create
+///
+/// ```no_run
+/// use block_utils::mkfs::{Vdev, VdevKind, ZpoolSpec};
+///
+/// ZpoolSpec::new("tank")
+///     .vdev(Vdev::new(VdevKind::Raidz2, &["/dev/sdb", "/dev/sdc", "/dev/sdd", "/dev/sde"]))
+///     .log(Vdev::new(VdevKind::Mirror, &["/dev/nvme0n1p1", "/dev/nvme1n1p1"]))
+///     .cache("/dev/nvme0n1p2")
+///     .ashift(12)
+///     .mountpoint("/srv/tank")
+///     .create()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ZpoolSpec {
+    name: String,
+    mountpoint: Option<PathBuf>,
+    ashift: Option<u8>,
+    force: bool,
+    vdevs: Vec<Vdev>,
+    log: Vec<Vdev>,
+    special: Vec<Vdev>,
+    cache: Vec<PathBuf>,
+    spares: Vec<PathBuf>,
+    pool_properties: Vec<(String, String)>,
+    fs_properties: Vec<(String, String)>,
+}
+
+impl ZpoolSpec {
+    pub fn new(name: &str) -> Self {
+        ZpoolSpec {
+            name: name.to_string(),
+            mountpoint: None,
+            ashift: None,
+            force: false,
+            vdevs: vec![],
+            log: vec![],
+            special: vec![],
+            cache: vec![],
+            spares: vec![],
+            pool_properties: vec![],
+            fs_properties: vec![],
+        }
+    }
+
+    /// Where the root dataset is mounted.  Defaults to /<name>
+    pub fn mountpoint(mut self, mountpoint: impl AsRef<Path>) -> Self {
+        self.mountpoint = Some(mountpoint.as_ref().to_path_buf());
+        self
+    }
+
+    /// log2 of the sector size, ie: 12 for 4K sectors
+    pub fn ashift(mut self, ashift: u8) -> Self {
+        self.ashift = Some(ashift);
+        self
+    }
+
+    /// Use devices even if they seem to be in use by another pool
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Add a data vdev.  Data is striped across all of them
+    pub fn vdev(mut self, vdev: Vdev) -> Self {
+        self.vdevs.push(vdev);
+        self
+    }
+
+    /// Add a separate intent log (SLOG) vdev
+    pub fn log(mut self, vdev: Vdev) -> Self {
+        self.log.push(vdev);
+        self
+    }
+
+    /// Add a special allocation class vdev for metadata and small blocks
+    pub fn special(mut self, vdev: Vdev) -> Self {
+        self.special.push(vdev);
+        self
+    }
+
+    /// Add an L2ARC cache device
+    pub fn cache(mut self, device: impl AsRef<Path>) -> Self {
+        self.cache.push(device.as_ref().to_path_buf());
+        self
+    }
+
+    /// Add a hot spare
+    pub fn spare(mut self, device: impl AsRef<Path>) -> Self {
+        self.spares.push(device.as_ref().to_path_buf());
+        self
+    }
+
+    /// A pool property set with -o, ie: autotrim=on
+    pub fn pool_property(mut self, name: &str, value: &str) -> Self {
+        self.pool_properties
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// A property of the root dataset set with -O, ie: compression=lz4
+    pub fn fs_property(mut self, name: &str, value: &str) -> Self {
+        self.fs_properties
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn validate(&self) -> BlockResult<()> {
+        let valid_name = self
+            .name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic())
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+            && !ZPOOL_RESERVED_PREFIXES
+                .iter()
+                .any(|reserved| self.name.starts_with(reserved))
+            && self.name != "log";
+        if !valid_name {
+            return Err(BlockUtilsError::new(format!(
+                "Invalid pool name {}",
+                self.name
+            )));
+        }
+        if self.vdevs.is_empty() {
+            return Err(BlockUtilsError::new(format!(
+                "Pool {} needs at least one data vdev",
+                self.name
+            )));
+        }
+        if let Some(ashift) = self.ashift {
+            if !(9..=16).contains(&ashift) {
+                return Err(BlockUtilsError::new(format!(
+                    "ashift must be 9 to 16, not {}",
+                    ashift
+                )));
+            }
+        }
+        for vdev in self.vdevs.iter().chain(&self.log).chain(&self.special) {
+            vdev.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Every device the pool will use
+    pub fn devices(&self) -> Vec<&Path> {
+        self.vdevs
+            .iter()
+            .chain(&self.log)
+            .chain(&self.special)
+            .flat_map(|v| v.devices.iter())
+            .chain(self.cache.iter())
+            .chain(self.spares.iter())
+            .map(|d| d.as_path())
+            .collect()
+    }
+
+    pub fn mkfs_plan(&self) -> BlockResult<MkfsPlan> {
+        self.validate()?;
+        let mut plan = MkfsPlan::new("zpool", "zfsutils-linux");
+        plan.arg("create");
+        if self.force {
+            plan.arg("-f");
+        }
+        if let Some(ashift) = self.ashift {
+            plan.opt("-o", format!("ashift={}", ashift));
+        }
+        for (name, value) in &self.pool_properties {
+            plan.opt("-o", format!("{}={}", name, value));
+        }
+        for (name, value) in &self.fs_properties {
+            plan.opt("-O", format!("{}={}", name, value));
+        }
+        if let Some(ref mountpoint) = self.mountpoint {
+            plan.opt("-m", mountpoint.to_string_lossy());
+        }
+        plan.arg(self.name.clone());
+        for vdev in &self.vdevs {
+            plan.args.extend(vdev.args());
+        }
+        for (class, vdevs) in &[("log", &self.log), ("special", &self.special)] {
+            if !vdevs.is_empty() {
+                plan.arg(*class);
+                for vdev in vdevs.iter() {
+                    plan.args.extend(vdev.args());
+                }
+            }
+        }
+        for (class, devices) in &[("cache", &self.cache), ("spare", &self.spares)] {
+            if !devices.is_empty() {
+                plan.arg(*class);
+                for device in devices.iter() {
+                    plan.arg(device.to_string_lossy());
+                }
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Create the pool.  Refuses if any of the devices is in use, see
+    /// `safety::check_not_in_use`
+    pub fn create(&self) -> BlockResult<()> {
+        self.create_with_preflight(Preflight::Check)
+    }
+
+    /// Same as `create` but `Preflight::Override` skips the in-use checks
+    pub fn create_with_preflight(&self, preflight: Preflight) -> BlockResult<()> {
+        let plan = self.mkfs_plan()?;
+        for device in self.devices() {
+            safety::preflight(device, preflight)?;
+        }
+        plan.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BtrfsChecksum, MetadataProfile};
+
+    fn argv(plan: &MkfsPlan) -> Vec<&str> {
+        std::iter::once(&plan.program)
+            .chain(plan.args.iter())
+            .map(|s| s.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_btrfs_plan() {
+        let plan = Filesystem::Btrfs {
+            leaf_size: 32768,
+            metadata_profile: MetadataProfile::Raid1,
+            node_size: 16384,
+            label: None,
+            uuid: None,
+            data_profile: None,
+            checksum: None,
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.btrfs",
+                "-m",
+                "raid1",
+                "-l",
+                "32768",
+                "-n",
+                "16384",
+                "/dev/sdb"
+            ]
+        );
+        assert!(plan.post_steps.is_empty());
+    }
+
+    #[test]
+    fn test_xfs_plan() {
+        let plan = Filesystem::Xfs {
+            block_size: Some(128),
+            force: true,
+            inode_size: Some(512),
+            stripe_size: Some(65536),
+            stripe_width: Some(4),
+            agcount: Some(32),
+            label: None,
+            uuid: None,
+            reflink: None,
+            crc: None,
+            rmapbt: None,
+            log_size: None,
+            realtime_device: None,
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.xfs",
+                "-b",
+                "size=512",
+                "-i",
+                "size=512",
+                "-f",
+                "-d",
+                "su=65536,sw=4,agcount=32",
+                "/dev/sdb"
+            ]
+        );
+
+        let plan = Filesystem::Xfs {
+            block_size: Some(1 << 20),
+            force: false,
+            inode_size: None,
+            stripe_size: Some(65536),
+            stripe_width: None,
+            agcount: None,
+            label: None,
+            uuid: None,
+            reflink: None,
+            crc: None,
+            rmapbt: None,
+            log_size: None,
+            realtime_device: None,
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec!["mkfs.xfs", "-b", "size=65536", "/dev/sdb"]
+        );
+        assert_eq!(
+            argv(
+                &Filesystem::new("xfs")
+                    .unwrap()
+                    .mkfs_plan("/dev/sdb")
+                    .unwrap()
+            ),
+            vec!["mkfs.xfs", "-i", "size=512", "/dev/sdb"]
+        );
+    }
+
+    #[test]
+    fn test_ext4_plan() {
+        let plan = Filesystem::Ext4 {
+            inode_size: 512,
+            reserved_blocks_percentage: 1,
+            stride: Some(16),
+            stripe_width: Some(64),
+            label: None,
+            uuid: None,
+            journal_size: None,
+            lazy_itable_init: None,
+            metadata_csum: None,
+            sixty_four_bit: None,
+            bigalloc: None,
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.ext4",
+                "-I",
+                "512",
+                "-m",
+                "1",
+                "-E",
+                "stride=16,stripe_width=64",
+                "/dev/sdb"
+            ]
+        );
+        let plan = Filesystem::Ext4 {
+            inode_size: 256,
+            reserved_blocks_percentage: 0,
+            stride: None,
+            stripe_width: Some(64),
+            label: None,
+            uuid: None,
+            journal_size: None,
+            lazy_itable_init: None,
+            metadata_csum: None,
+            sixty_four_bit: None,
+            bigalloc: None,
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.ext4",
+                "-I",
+                "256",
+                "-m",
+                "0",
+                "-E",
+                "stripe_width=64",
+                "/dev/sdb"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zfs_plan() {
+        let plan = Filesystem::Zfs {
+            block_size: Some(131072),
+            compression: Some(true),
+            label: None,
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec!["zpool", "create", "-f", "-m", "/mnt/sdb", "sdb", "/dev/sdb"]
+        );
+        let set = |p: &str| {
+            (
+                "zfs".to_string(),
+                vec!["set".to_string(), p.to_string(), "sdb".to_string()],
+            )
+        };
+        assert_eq!(
+            plan.post_steps,
+            vec![
+                set("recordsize=131072"),
+                set("compression=on"),
+                set("acltype=posixacl"),
+                set("atime=off")
+            ]
+        );
+        assert_eq!(
+            Filesystem::new("zfs")
+                .unwrap()
+                .mkfs_plan("/dev/sdb")
+                .unwrap()
+                .post_steps,
+            vec![set("acltype=posixacl"), set("atime=off")]
+        );
+        assert!(Filesystem::new("zfs").unwrap().mkfs_plan("/").is_err());
+    }
+
+    #[test]
+    fn test_label_uuid_and_features() {
+        let uuid = Uuid::parse_str("5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b").unwrap();
+        let plan = Filesystem::Btrfs {
+            leaf_size: 16384,
+            metadata_profile: MetadataProfile::Dup,
+            node_size: 16384,
+            label: Some("data".to_string()),
+            uuid: Some(uuid),
+            data_profile: Some(MetadataProfile::Single),
+            checksum: Some(BtrfsChecksum::Xxhash),
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.btrfs",
+                "-m",
+                "dup",
+                "-l",
+                "16384",
+                "-n",
+                "16384",
+                "-d",
+                "single",
+                "--csum",
+                "xxhash",
+                "-L",
+                "data",
+                "-U",
+                "5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "/dev/sdb"
+            ]
+        );
+
+        let plan = Filesystem::Xfs {
+            block_size: None,
+            force: false,
+            inode_size: None,
+            stripe_size: None,
+            stripe_width: None,
+            agcount: None,
+            label: Some("scratch".to_string()),
+            uuid: Some(uuid),
+            reflink: Some(true),
+            crc: Some(true),
+            rmapbt: Some(false),
+            log_size: Some(67108864),
+            realtime_device: Some(PathBuf::from("/dev/sdc")),
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.xfs",
+                "-m",
+                "crc=1,reflink=1,rmapbt=0,uuid=5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "-l",
+                "size=67108864",
+                "-r",
+                "rtdev=/dev/sdc",
+                "-L",
+                "scratch",
+                "/dev/sdb"
+            ]
+        );
+
+        let plan = Filesystem::Ext4 {
+            inode_size: 256,
+            reserved_blocks_percentage: 5,
+            stride: Some(16),
+            stripe_width: None,
+            label: Some("home".to_string()),
+            uuid: Some(uuid),
+            journal_size: Some(128),
+            lazy_itable_init: Some(false),
+            metadata_csum: Some(true),
+            sixty_four_bit: Some(true),
+            bigalloc: Some(false),
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.ext4",
+                "-I",
+                "256",
+                "-m",
+                "5",
+                "-L",
+                "home",
+                "-U",
+                "5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "-J",
+                "size=128",
+                "-O",
+                "metadata_csum,64bit,^bigalloc",
+                "-E",
+                "stride=16,lazy_itable_init=0",
+                "/dev/sdb"
+            ]
+        );
+
+        let plan = Filesystem::Zfs {
+            block_size: None,
+            compression: None,
+            label: Some("tank".to_string()),
+        }
+        .mkfs_plan("/dev/sdb")
+        .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "zpool",
+                "create",
+                "-f",
+                "-m",
+                "/mnt/tank",
+                "tank",
+                "/dev/sdb"
+            ]
+        );
+        assert_eq!(plan.post_steps[0].1[2], "tank");
+
+        let too_long = Filesystem::Xfs {
+            block_size: None,
+            force: false,
+            inode_size: None,
+            stripe_size: None,
+            stripe_width: None,
+            agcount: None,
+            label: Some("thirteen-byte".to_string()),
+            uuid: None,
+            reflink: None,
+            crc: None,
+            rmapbt: None,
+            log_size: None,
+            realtime_device: None,
+        };
+        assert!(too_long.mkfs_plan("/dev/sdb").is_err());
+    }
+
+    #[test]
+    fn test_other_formats() {
+        let plan = |name: &str| {
+            Filesystem::new(name)
+                .unwrap()
+                .mkfs_plan("/dev/sdb")
+                .unwrap()
+        };
+        assert_eq!(
+            argv(&plan("ext2")),
+            vec!["mkfs.ext2", "-I", "256", "-m", "5", "/dev/sdb"]
+        );
+        assert_eq!(
+            argv(&plan("ext3")),
+            vec!["mkfs.ext3", "-I", "256", "-m", "5", "/dev/sdb"]
+        );
+        assert_eq!(argv(&plan("vfat")), vec!["mkfs.vfat", "/dev/sdb"]);
+        assert_eq!(argv(&plan("exfat")), vec!["mkfs.exfat", "/dev/sdb"]);
+        assert_eq!(argv(&plan("ntfs")), vec!["mkfs.ntfs", "-Q", "/dev/sdb"]);
+        assert_eq!(argv(&plan("f2fs")), vec!["mkfs.f2fs", "/dev/sdb"]);
+        assert_eq!(argv(&plan("swap")), vec!["mkswap", "/dev/sdb"]);
+        assert_eq!(
+            argv(&plan("bcachefs")),
+            vec!["bcachefs", "format", "/dev/sdb"]
+        );
+        assert!(Filesystem::new("reiserfs").is_err());
+
+        let uuid = Uuid::parse_str("5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b").unwrap();
+        let fs = Filesystem::Ext3 {
+            inode_size: 128,
+            reserved_blocks_percentage: 1,
+            label: Some("boot".to_string()),
+            uuid: Some(uuid),
+            journal_size: Some(32),
+        };
+        assert_eq!(
+            argv(&fs.mkfs_plan("/dev/sdb").unwrap()),
+            vec![
+                "mkfs.ext3",
+                "-I",
+                "128",
+                "-m",
+                "1",
+                "-L",
+                "boot",
+                "-U",
+                "5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "-J",
+                "size=32",
+                "/dev/sdb"
+            ]
+        );
+        let fs = Filesystem::Vfat {
+            fat_size: Some(32),
+            sectors_per_cluster: Some(8),
+            label: Some("EFI".to_string()),
+            volume_id: Some(0xdead_beef),
+        };
+        assert_eq!(
+            argv(&fs.mkfs_plan("/dev/sdb1").unwrap()),
+            vec![
+                "mkfs.vfat",
+                "-F",
+                "32",
+                "-s",
+                "8",
+                "-n",
+                "EFI",
+                "-i",
+                "DEADBEEF",
+                "/dev/sdb1"
+            ]
+        );
+        let fs = Filesystem::Vfat {
+            fat_size: Some(24),
+            sectors_per_cluster: None,
+            label: None,
+            volume_id: None,
+        };
+        assert!(fs.mkfs_plan("/dev/sdb1").is_err());
+        let fs = Filesystem::Exfat {
+            cluster_size: Some(131072),
+            label: Some("camera".to_string()),
+            uuid: Some(uuid),
+        };
+        assert_eq!(
+            argv(&fs.mkfs_plan("/dev/sdb").unwrap()),
+            vec![
+                "mkfs.exfat",
+                "-c",
+                "131072",
+                "-L",
+                "camera",
+                "-U",
+                "5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "/dev/sdb"
+            ]
+        );
+        let fs = Filesystem::Ntfs {
+            quick: false,
+            force: true,
+            cluster_size: Some(4096),
+            label: Some("shared".to_string()),
+        };
+        assert_eq!(
+            argv(&fs.mkfs_plan("/dev/sdb").unwrap()),
+            vec!["mkfs.ntfs", "-F", "-c", "4096", "-L", "shared", "/dev/sdb"]
+        );
+        let fs = Filesystem::F2fs {
+            force: true,
+            overprovision: Some(5),
+            features: vec!["extra_attr".to_string(), "compression".to_string()],
+            label: Some("sd".to_string()),
+            uuid: Some(uuid),
+        };
+        assert_eq!(
+            argv(&fs.mkfs_plan("/dev/sdb").unwrap()),
+            vec![
+                "mkfs.f2fs",
+                "-f",
+                "-o",
+                "5",
+                "-O",
+                "extra_attr,compression",
+                "-l",
+                "sd",
+                "-U",
+                "5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "/dev/sdb"
+            ]
+        );
+        let fs = Filesystem::Swap {
+            page_size: Some(65536),
+            label: Some("swap0".to_string()),
+            uuid: Some(uuid),
+        };
+        assert_eq!(
+            argv(&fs.mkfs_plan("/dev/sdb2").unwrap()),
+            vec![
+                "mkswap",
+                "-p",
+                "65536",
+                "-L",
+                "swap0",
+                "-U",
+                "5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "/dev/sdb2"
+            ]
+        );
+        let fs = Filesystem::Bcachefs {
+            compression: Some("zstd".to_string()),
+            replicas: Some(2),
+            label: Some("pool".to_string()),
+            uuid: Some(uuid),
+        };
+        assert_eq!(
+            argv(&fs.mkfs_plan("/dev/sdb").unwrap()),
+            vec![
+                "bcachefs",
+                "format",
+                "--compression=zstd",
+                "--replicas=2",
+                "--fs_label=pool",
+                "--uuid=5b1e8c4a-2f3d-4c1e-9a7b-0c6d2e8f1a3b",
+                "/dev/sdb"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_device_plans() {
+        let plan = BtrfsSpec::new(&["/dev/sdb", "/dev/sdc", "/dev/sdd"])
+            .data_profile(MetadataProfile::Raid1)
+            .metadata_profile(MetadataProfile::Raid1c3)
+            .label("data")
+            .force(true)
+            .mkfs_plan()
+            .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "mkfs.btrfs",
+                "-f",
+                "-d",
+                "raid1",
+                "-m",
+                "raid1c3",
+                "-L",
+                "data",
+                "/dev/sdb",
+                "/dev/sdc",
+                "/dev/sdd"
+            ]
+        );
+        assert!(BtrfsSpec::new(&["/dev/sdb", "/dev/sdc"])
+            .data_profile(MetadataProfile::Raid6)
+            .mkfs_plan()
+            .is_err());
+
+        // Each draid group needs its data disks on top of parity and spares
+        assert!(ZpoolSpec::new("tank")
+            .vdev(Vdev::new(
+                VdevKind::Draid {
+                    parity: 1,
+                    data: Some(8),
+                    spares: None,
+                },
+                &["/dev/sdf", "/dev/sdg", "/dev/sdh", "/dev/sdi"],
+            ))
+            .mkfs_plan()
+            .is_err());
+
+        let plan = ZpoolSpec::new("tank")
+            .vdev(Vdev::new(
+                VdevKind::Raidz2,
+                &["/dev/sdb", "/dev/sdc", "/dev/sdd", "/dev/sde"],
+            ))
+            .vdev(Vdev::new(
+                VdevKind::Draid {
+                    parity: 1,
+                    data: Some(2),
+                    spares: Some(1),
+                },
+                &["/dev/sdf", "/dev/sdg", "/dev/sdh", "/dev/sdi"],
+            ))
+            .log(Vdev::new(
+                VdevKind::Mirror,
+                &["/dev/nvme0n1p1", "/dev/nvme1n1p1"],
+            ))
+            .special(Vdev::new(
+                VdevKind::Mirror,
+                &["/dev/nvme0n1p3", "/dev/nvme1n1p3"],
+            ))
+            .cache("/dev/nvme0n1p2")
+            .spare("/dev/sdj")
+            .ashift(12)
+            .fs_property("compression", "lz4")
+            .mountpoint("/srv/tank")
+            .mkfs_plan()
+            .unwrap();
+        assert_eq!(
+            argv(&plan),
+            vec![
+                "zpool",
+                "create",
+                "-o",
+                "ashift=12",
+                "-O",
+                "compression=lz4",
+                "-m",
+                "/srv/tank",
+                "tank",
+                "raidz2",
+                "/dev/sdb",
+                "/dev/sdc",
+                "/dev/sdd",
+                "/dev/sde",
+                "draid1:2d:4c:1s",
+                "/dev/sdf",
+                "/dev/sdg",
+                "/dev/sdh",
+                "/dev/sdi",
+                "log",
+                "mirror",
+                "/dev/nvme0n1p1",
+                "/dev/nvme1n1p1",
+                "special",
+                "mirror",
+                "/dev/nvme0n1p3",
+                "/dev/nvme1n1p3",
+                "cache",
+                "/dev/nvme0n1p2",
+                "spare",
+                "/dev/sdj"
+            ]
+        );
+
+        let stripe = Vdev::new(VdevKind::Stripe, &["/dev/sdb"]);
+        assert!(ZpoolSpec::new("mirror1")
+            .vdev(stripe.clone())
+            .mkfs_plan()
+            .is_err());
+        assert!(ZpoolSpec::new("1tank")
+            .vdev(stripe.clone())
+            .mkfs_plan()
+            .is_err());
+        assert!(ZpoolSpec::new("log")
+            .vdev(stripe.clone())
+            .mkfs_plan()
+            .is_err());
+        // Only "log" itself is reserved, not names starting with it or
+        // with the other vdev classes
+        for name in &["logs", "cachepool", "special1"] {
+            assert!(ZpoolSpec::new(name)
+                .vdev(stripe.clone())
+                .mkfs_plan()
+                .is_ok());
+        }
+        assert!(ZpoolSpec::new("tank").mkfs_plan().is_err());
+        assert!(ZpoolSpec::new("tank")
+            .vdev(stripe)
+            .ashift(20)
+            .mkfs_plan()
+            .is_err());
+        assert!(ZpoolSpec::new("tank")
+            .vdev(Vdev::new(VdevKind::Mirror, &["/dev/sdb"]))
+            .mkfs_plan()
+            .is_err());
+    }
+}
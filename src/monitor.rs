@@ -0,0 +1,309 @@
+//! Hotplug events for block devices.
+//!
+//! `DeviceMonitor` listens on the udev netlink socket so callers can react
+//! to disks being inserted, removed or resized instead of polling
+//! `get_block_devices`.
+use crate::{
+    BlockResult, DevNum, Device, DeviceType, FilesystemType, MediaType, Transport, ZonedModel,
+};
+
+use log::debug;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// A change to a block device along with the freshly gathered device info
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    Added(Device),
+    /// The device is gone so only information carried in the event itself
+    /// is available.  Sysfs attributes like capacity will be empty.
+    Removed(Device),
+    Changed(Device),
+    /// Media was inserted or ejected, ie: a cdrom or card reader
+    MediaChange(Device),
+    /// The capacity of the device changed
+    Resized(Device),
+}
+
+impl DeviceEvent {
+    /// The device this event is about
+    pub fn device(&self) -> &Device {
+        match *self {
+            DeviceEvent::Added(ref d)
+            | DeviceEvent::Removed(ref d)
+            | DeviceEvent::Changed(ref d)
+            | DeviceEvent::MediaChange(ref d)
+            | DeviceEvent::Resized(ref d) => d,
+        }
+    }
+}
+
+/// Listens for udev events on the block subsystem
+pub struct DeviceMonitor {
+    socket: udev::MonitorSocket,
+    media_types: Vec<MediaType>,
+    device_types: Vec<DeviceType>,
+}
+
+impl DeviceMonitor {
+    /// Start listening for block device events.  Events that happen before
+    /// this is called are not seen.
+    pub fn new() -> BlockResult<Self> {
+        let socket = udev::MonitorBuilder::new()?
+            .match_subsystem("block")?
+            .listen()?;
+        Ok(DeviceMonitor {
+            socket,
+            media_types: vec![],
+            device_types: vec![],
+        })
+    }
+
+    /// Only report devices with one of these media types.  Removed devices
+    /// whose media type can't be worked out anymore are still reported.
+    pub fn media_types(mut self, media_types: &[MediaType]) -> Self {
+        self.media_types = media_types.to_vec();
+        self
+    }
+
+    /// Only report devices with one of these device types
+    pub fn device_types(mut self, device_types: &[DeviceType]) -> Self {
+        self.device_types = device_types.to_vec();
+        self
+    }
+
+    /// Return the next pending event without blocking.  Use this together
+    /// with `as_raw_fd` in your own poll loop.
+    pub fn try_next(&mut self) -> Option<BlockResult<DeviceEvent>> {
+        while let Some(event) = self.socket.next() {
+            match self.build_event(event) {
+                Ok(Some(e)) => return Some(Ok(e)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    /// Wait up to `timeout_ms` milliseconds for the next event.  A negative
+    /// timeout waits forever.  Returns `None` if the timeout expired.
+    pub fn next_timeout(&mut self, timeout_ms: i32) -> Option<BlockResult<DeviceEvent>> {
+        loop {
+            if let Some(event) = self.try_next() {
+                return Some(event);
+            }
+            let mut fds = [PollFd::new(self.socket.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, timeout_ms) {
+                Ok(0) => return None,
+                Ok(_) => continue,
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+
+    /// Blocking iterator over events.  It never ends on its own.
+    pub fn iter(&mut self) -> impl Iterator<Item = BlockResult<DeviceEvent>> + '_ {
+        std::iter::from_fn(move || self.next_timeout(-1))
+    }
+
+    fn build_event(&self, event: udev::Event) -> BlockResult<Option<DeviceEvent>> {
+        let event_type = event.event_type();
+        let media_change = event.property_value("DISK_MEDIA_CHANGE").is_some();
+        let resize = event.property_value("RESIZE").is_some();
+        let removed = event_type == udev::EventType::Remove;
+        let device = match Device::from_udev_device(event.device()) {
+            Ok(device) => device,
+            Err(e) if removed => {
+                debug!("Using the remove event's properties, sysfs is gone: {}", e);
+                device_from_properties(|name| {
+                    event
+                        .property_value(name)
+                        .map(|v| v.to_string_lossy().into_owned())
+                })
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !passes_filters(
+            &self.media_types,
+            &self.device_types,
+            &device.media_type,
+            &device.device_type,
+            removed,
+        ) {
+            return Ok(None);
+        }
+
+        Ok(match event_type {
+            udev::EventType::Add => Some(DeviceEvent::Added(device)),
+            udev::EventType::Remove => Some(DeviceEvent::Removed(device)),
+            udev::EventType::Change if media_change => Some(DeviceEvent::MediaChange(device)),
+            udev::EventType::Change if resize => Some(DeviceEvent::Resized(device)),
+            udev::EventType::Change => Some(DeviceEvent::Changed(device)),
+            // Driver bind/unbind isn't interesting for block devices
+            _ => None,
+        })
+    }
+}
+
+/// Whether a device with these types should be reported.  The device type
+/// comes from DEVTYPE in the event itself but the media type partly relies on
+/// sysfs attributes like queue/rotational, which are already gone when a
+/// device is removed.  A removed device that can no longer be classified is
+/// reported rather than silently dropped.
+fn passes_filters(
+    media_types: &[MediaType],
+    device_types: &[DeviceType],
+    media_type: &MediaType,
+    device_type: &DeviceType,
+    removed: bool,
+) -> bool {
+    let media_ok = media_types.is_empty()
+        || media_types.contains(media_type)
+        || (removed && *media_type == MediaType::Unknown);
+    let device_ok = device_types.is_empty() || device_types.contains(device_type);
+    media_ok && device_ok
+}
+
+/// Build a `Device` from udev event properties alone, for removed devices
+/// whose sysfs attributes can't be read anymore
+fn device_from_properties<F>(property: F) -> Device
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let devnum = match (property("MAJOR"), property("MINOR")) {
+        (Some(major), Some(minor)) => match (major.parse(), minor.parse()) {
+            (Ok(major), Ok(minor)) => Some(DevNum { major, minor }),
+            _ => None,
+        },
+        _ => None,
+    };
+    Device {
+        id: property("ID_FS_UUID").and_then(|id| Uuid::parse_str(&id).ok()),
+        name: property("DEVNAME")
+            .and_then(|path| {
+                Path::new(&path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default(),
+        media_type: MediaType::Unknown,
+        device_type: property("DEVTYPE")
+            .and_then(|t| DeviceType::from_str(&t).ok())
+            .unwrap_or(DeviceType::Unknown),
+        capacity: 0,
+        fs_type: property("ID_FS_TYPE")
+            .and_then(|t| FilesystemType::from_str(&t).ok())
+            .unwrap_or(FilesystemType::Unknown),
+        label: property("ID_FS_LABEL"),
+        serial_number: property("ID_SERIAL_SHORT").or_else(|| property("ID_SERIAL")),
+        logical_block_size: None,
+        physical_block_size: None,
+        model: property("ID_MODEL"),
+        vendor: property("ID_VENDOR"),
+        wwn: None,
+        wwn_source: None,
+        firmware_revision: property("ID_REVISION"),
+        transport: Transport::Unknown,
+        rotation_rate_rpm: None,
+        removable: false,
+        read_only: false,
+        hidden: false,
+        zoned: ZonedModel::None,
+        partition_number: property("PARTN").and_then(|n| n.parse().ok()),
+        partition_label: property("ID_PART_ENTRY_NAME"),
+        devnum,
+        links: property("DEVLINKS")
+            .map(|links| links.split_whitespace().map(Into::into).collect())
+            .unwrap_or_default(),
+    }
+}
+
+impl AsRawFd for DeviceMonitor {
+    /// The netlink socket.  It becomes readable when events are pending.
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passes_filters() {
+        let ssd_disks = ([MediaType::SolidState], [DeviceType::Disk]);
+        let passes = |media_type: MediaType, device_type: DeviceType, removed: bool| {
+            passes_filters(
+                &ssd_disks.0,
+                &ssd_disks.1,
+                &media_type,
+                &device_type,
+                removed,
+            )
+        };
+        assert!(passes(MediaType::SolidState, DeviceType::Disk, false));
+        assert!(!passes(MediaType::Rotational, DeviceType::Disk, false));
+        assert!(!passes(MediaType::SolidState, DeviceType::Partition, false));
+        // Without sysfs a removed disk can't be classified
+        assert!(!passes(MediaType::Unknown, DeviceType::Disk, false));
+        assert!(passes(MediaType::Unknown, DeviceType::Disk, true));
+        assert!(!passes(MediaType::Unknown, DeviceType::Partition, true));
+        assert!(!passes(MediaType::Rotational, DeviceType::Disk, true));
+
+        assert!(passes_filters(
+            &[],
+            &[],
+            &MediaType::Loopback,
+            &DeviceType::Unknown,
+            false
+        ));
+    }
+
+    #[test]
+    fn test_device_from_properties() {
+        let properties = [
+            ("DEVNAME", "/dev/sdb1"),
+            ("DEVTYPE", "partition"),
+            ("MAJOR", "8"),
+            ("MINOR", "17"),
+            ("PARTN", "1"),
+            ("ID_FS_TYPE", "xfs"),
+            (
+                "DEVLINKS",
+                "/dev/disk/by-label/data /dev/disk/by-partuuid/1c2d-01",
+            ),
+        ];
+        let device = device_from_properties(|name| {
+            properties
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        });
+        assert_eq!(device.name, "sdb1");
+        assert_eq!(device.device_type, DeviceType::Partition);
+        assert_eq!(
+            device.devnum,
+            Some(DevNum {
+                major: 8,
+                minor: 17
+            })
+        );
+        assert_eq!(device.partition_number, Some(1));
+        assert_eq!(device.fs_type, FilesystemType::Xfs);
+        assert_eq!(device.media_type, MediaType::Unknown);
+        assert_eq!(device.links.len(), 2);
+        assert!(passes_filters(
+            &[MediaType::SolidState],
+            &[DeviceType::Partition],
+            &device.media_type,
+            &device.device_type,
+            true
+        ));
+    }
+}
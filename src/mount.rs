@@ -0,0 +1,797 @@
+//! Mounting filesystems with mount(2).
+//!
+//! `MountOptions` collects the filesystem type, generic mount flags and
+//! filesystem specific data options and mounts with a direct syscall so
+//! failures come back as a `MountErrorKind` instead of mount's exit status.
+//! Filesystems that need a userspace helper, like nfs or ntfs-3g, are handed
+//! to the mount binary instead, the same way mount(8) would.
+//!
+//! `UnmountOptions` does the reverse with umount2(2).  When an unmount fails
+//! because the filesystem is busy the error names the processes using it.
+use crate::mountinfo::MountTable;
+use crate::{BlockResult, BlockUtilsError, DevNum, Device, FilesystemType};
+
+use log::debug;
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Directories searched for mount.<fstype> helpers
+const HELPER_DIRS: &[&str] = &["/sbin", "/usr/sbin", "/sbin/fs.d", "/sbin/fs"];
+
+/// Why a mount failed
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountErrorKind {
+    /// The source is already mounted or the target is busy
+    Busy,
+    /// The source or target doesn't exist
+    NotFound,
+    NotADirectory,
+    NotABlockDevice,
+    PermissionDenied,
+    /// The kernel doesn't support the filesystem type
+    UnknownFilesystem,
+    /// Bad superblock, bad options or the filesystem type doesn't match
+    InvalidArgument,
+    /// A read-write mount of a write protected device
+    ReadOnly,
+    /// The device is gone
+    NoDevice,
+    /// An expire unmount marked the mount.  Repeat it to unmount
+    Expiring,
+    /// A mount helper exited with an error
+    HelperFailed {
+        stderr: String,
+    },
+    Other {
+        errno: i32,
+    },
+}
+
+impl MountErrorKind {
+    pub(crate) fn from_errno(errno: Errno) -> Self {
+        match errno {
+            Errno::EBUSY => MountErrorKind::Busy,
+            Errno::ENOENT => MountErrorKind::NotFound,
+            Errno::ENOTDIR => MountErrorKind::NotADirectory,
+            Errno::ENOTBLK => MountErrorKind::NotABlockDevice,
+            Errno::EPERM | Errno::EACCES => MountErrorKind::PermissionDenied,
+            Errno::ENODEV => MountErrorKind::UnknownFilesystem,
+            Errno::EINVAL => MountErrorKind::InvalidArgument,
+            Errno::EROFS => MountErrorKind::ReadOnly,
+            Errno::ENXIO => MountErrorKind::NoDevice,
+            Errno::EAGAIN => MountErrorKind::Expiring,
+            other => MountErrorKind::Other {
+                errno: other as i32,
+            },
+        }
+    }
+}
+
+impl fmt::Display for MountErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MountErrorKind::Busy => write!(f, "already mounted or mount point busy"),
+            MountErrorKind::NotFound => write!(f, "source or mount point does not exist"),
+            MountErrorKind::NotADirectory => write!(f, "mount point is not a directory"),
+            MountErrorKind::NotABlockDevice => write!(f, "source is not a block device"),
+            MountErrorKind::PermissionDenied => write!(f, "permission denied"),
+            MountErrorKind::UnknownFilesystem => write!(f, "unknown filesystem type"),
+            MountErrorKind::InvalidArgument => {
+                write!(f, "wrong fs type, bad option or bad superblock")
+            }
+            MountErrorKind::ReadOnly => write!(f, "device is write protected"),
+            MountErrorKind::NoDevice => write!(f, "device does not exist"),
+            MountErrorKind::Expiring => write!(f, "marked as expired"),
+            MountErrorKind::HelperFailed { stderr } => write!(f, "{}", stderr.trim()),
+            MountErrorKind::Other { errno } => write!(f, "{}", Errno::from_i32(*errno).desc()),
+        }
+    }
+}
+
+/// Mount propagation type.  See mount_namespaces(7)
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Propagation {
+    Shared,
+    Slave,
+    Private,
+    Unbindable,
+}
+
+impl Propagation {
+    fn flag(self) -> MsFlags {
+        match self {
+            Propagation::Shared => MsFlags::MS_SHARED,
+            Propagation::Slave => MsFlags::MS_SLAVE,
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::Unbindable => MsFlags::MS_UNBINDABLE,
+        }
+    }
+}
+
+/// Change the propagation type of the mount at `target`.  With `recursive`
+/// every mount below it changes too.
+pub fn set_propagation(
+    target: impl AsRef<Path>,
+    propagation: Propagation,
+    recursive: bool,
+) -> BlockResult<()> {
+    let mut flags = propagation.flag();
+    if recursive {
+        flags |= MsFlags::MS_REC;
+    }
+    mount::<str, Path, str, str>(None, target.as_ref(), None, flags, None).map_err(|e| {
+        BlockUtilsError::MountFailed {
+            device: PathBuf::new(),
+            target: target.as_ref().to_path_buf(),
+            kind: MountErrorKind::from_errno(e),
+        }
+    })
+}
+
+/// Builder for a mount.
+///
+/// ```no_run
+/// use block_utils::mount::MountOptions;
+/// MountOptions::new()
+///     .fs_type("btrfs")
+///     .noatime()
+///     .subvol("@data")
+///     .mount("/dev/sdb1", "/mnt/data")
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountOptions {
+    fs_type: Option<String>,
+    flags: MsFlags,
+    data: Vec<String>,
+    propagation: Option<(Propagation, bool)>,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions {
+            fs_type: None,
+            flags: MsFlags::empty(),
+            data: vec![],
+            propagation: None,
+        }
+    }
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Filesystem type, ie: "xfs".  When unset the type udev detected on
+    /// the source is used.
+    pub fn fs_type(mut self, fs_type: &str) -> Self {
+        self.fs_type = Some(fs_type.to_string());
+        self
+    }
+
+    pub fn read_only(self, read_only: bool) -> Self {
+        self.flag(MsFlags::MS_RDONLY, read_only)
+    }
+
+    pub fn noatime(self) -> Self {
+        self.flag(MsFlags::MS_NOATIME, true)
+    }
+
+    pub fn nodiratime(self) -> Self {
+        self.flag(MsFlags::MS_NODIRATIME, true)
+    }
+
+    pub fn relatime(self) -> Self {
+        self.flag(MsFlags::MS_RELATIME, true)
+    }
+
+    pub fn nodev(self) -> Self {
+        self.flag(MsFlags::MS_NODEV, true)
+    }
+
+    pub fn nosuid(self) -> Self {
+        self.flag(MsFlags::MS_NOSUID, true)
+    }
+
+    pub fn noexec(self) -> Self {
+        self.flag(MsFlags::MS_NOEXEC, true)
+    }
+
+    pub fn sync(self) -> Self {
+        self.flag(MsFlags::MS_SYNCHRONOUS, true)
+    }
+
+    /// Issue discards as files are freed.  Supported by ext4, xfs and btrfs
+    pub fn discard(self) -> Self {
+        self.data("discard")
+    }
+
+    /// Mount a btrfs subvolume by path
+    pub fn subvol(self, subvol: &str) -> Self {
+        self.data(&format!("subvol={}", subvol))
+    }
+
+    /// A filesystem specific option, ie: "inode64" or "compress=zstd"
+    pub fn data(mut self, option: &str) -> Self {
+        self.data.push(option.to_string());
+        self
+    }
+
+    /// Propagation type to set on the new mount
+    pub fn propagation(mut self, propagation: Propagation, recursive: bool) -> Self {
+        self.propagation = Some((propagation, recursive));
+        self
+    }
+
+    fn flag(mut self, flag: MsFlags, on: bool) -> Self {
+        self.flags.set(flag, on);
+        self
+    }
+
+    /// Mount `device` at `target`.  The device is found through its
+    /// filesystem UUID when it has one.  The device's detected filesystem
+    /// type is used unless one was set.  If udev hasn't probed the type it's
+    /// probed now and, failing that, left to the mount binary to detect.
+    pub fn mount_device(&self, device: &Device, target: impl AsRef<Path>) -> BlockResult<()> {
+        let target = target.as_ref();
+        let source = device
+            .id
+            .map(|id| PathBuf::from("/dev/disk/by-uuid").join(id.hyphenated().to_string()))
+            .filter(|link| link.exists())
+            .unwrap_or_else(|| PathBuf::from("/dev").join(&device.name));
+        let detected: FilesystemType;
+        let fs_type = match (&self.fs_type, &device.fs_type) {
+            (Some(fs_type), _) => fs_type.as_str(),
+            (None, FilesystemType::Unknown) | (None, FilesystemType::Lvm) => {
+                detected = crate::get_device_info(Path::new("/dev").join(&device.name))
+                    .map(|info| info.fs_type)
+                    .unwrap_or(FilesystemType::Unknown);
+                detected.to_str()
+            }
+            (None, fs_type) => fs_type.to_str(),
+        };
+        if ["unknown", "lvm"].contains(&fs_type) {
+            self.mount_with_helper(&source, target, None)?;
+            if let Some((propagation, recursive)) = self.propagation {
+                set_propagation(target, propagation, recursive)?;
+            }
+            return Ok(());
+        }
+        self.mount_as(&source, target, fs_type)
+    }
+
+    /// Mount `source` at `target`
+    pub fn mount(&self, source: impl AsRef<Path>, target: impl AsRef<Path>) -> BlockResult<()> {
+        let detected: FilesystemType;
+        let fs_type = match self.fs_type {
+            Some(ref fs_type) => fs_type.as_str(),
+            None => {
+                detected = crate::get_device_info(&source)?.fs_type;
+                detected.to_str()
+            }
+        };
+        self.mount_as(source.as_ref(), target.as_ref(), fs_type)
+    }
+
+    /// Bind mount `source` at `target`.  With `recursive` the mounts below
+    /// `source` are bound too.  Flags like `read_only` or `nodev` only apply
+    /// to the new mount, the kernel needs a second remount for them which
+    /// doesn't propagate to the recursively bound mounts.
+    pub fn bind(
+        &self,
+        source: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+        recursive: bool,
+    ) -> BlockResult<()> {
+        let (source, target) = (source.as_ref(), target.as_ref());
+        let failed = |e| BlockUtilsError::MountFailed {
+            device: source.to_path_buf(),
+            target: target.to_path_buf(),
+            kind: MountErrorKind::from_errno(e),
+        };
+        let mut flags = MsFlags::MS_BIND;
+        if recursive {
+            flags |= MsFlags::MS_REC;
+        }
+        debug!(
+            "mount({}, {}, {:?})",
+            source.display(),
+            target.display(),
+            flags
+        );
+        mount::<Path, Path, str, str>(Some(source), target, None, flags, None).map_err(failed)?;
+        if !self.flags.is_empty() {
+            mount::<str, Path, str, str>(
+                None,
+                target,
+                None,
+                MsFlags::MS_REMOUNT | MsFlags::MS_BIND | self.flags,
+                None,
+            )
+            .map_err(failed)?;
+        }
+        if let Some((propagation, recursive)) = self.propagation {
+            set_propagation(target, propagation, recursive)?;
+        }
+        Ok(())
+    }
+
+    /// Change the flags and data options of the existing mount at `target`,
+    /// ie: `MountOptions::new().read_only(true).remount("/mnt/data")`.
+    /// Flags that aren't set are cleared.
+    pub fn remount(&self, target: impl AsRef<Path>) -> BlockResult<()> {
+        let target = target.as_ref();
+        let data = self.data.join(",");
+        mount::<str, Path, str, str>(
+            None,
+            target,
+            None,
+            MsFlags::MS_REMOUNT | self.flags,
+            if data.is_empty() {
+                None
+            } else {
+                Some(data.as_str())
+            },
+        )
+        .map_err(|e| BlockUtilsError::MountFailed {
+            device: PathBuf::new(),
+            target: target.to_path_buf(),
+            kind: MountErrorKind::from_errno(e),
+        })
+    }
+
+    pub(crate) fn mount_as(&self, source: &Path, target: &Path, fs_type: &str) -> BlockResult<()> {
+        let failed = |kind| BlockUtilsError::MountFailed {
+            device: source.to_path_buf(),
+            target: target.to_path_buf(),
+            kind,
+        };
+        if ["", "unknown", "lvm"].contains(&fs_type) {
+            return Err(failed(MountErrorKind::UnknownFilesystem));
+        }
+
+        if needs_helper(fs_type) {
+            self.mount_with_helper(source, target, Some(fs_type))?;
+        } else {
+            let data = self.data.join(",");
+            debug!(
+                "mount({}, {}, {}, {:?}, {})",
+                source.display(),
+                target.display(),
+                fs_type,
+                self.flags,
+                data
+            );
+            mount(
+                Some(source),
+                target,
+                Some(fs_type),
+                self.flags,
+                if data.is_empty() {
+                    None
+                } else {
+                    Some(data.as_str())
+                },
+            )
+            .map_err(|e| failed(MountErrorKind::from_errno(e)))?;
+        }
+
+        if let Some((propagation, recursive)) = self.propagation {
+            set_propagation(target, propagation, recursive)?;
+        }
+        Ok(())
+    }
+
+    /// Mount through the mount binary.  Without `fs_type` it detects the
+    /// filesystem itself
+    fn mount_with_helper(
+        &self,
+        source: &Path,
+        target: &Path,
+        fs_type: Option<&str>,
+    ) -> BlockResult<()> {
+        let args = self.helper_args(source, target, fs_type);
+        debug!("mount: {:?}", args);
+        let output = crate::run_command("mount", &args)?;
+        if !output.status.success() {
+            return Err(BlockUtilsError::MountFailed {
+                device: source.to_path_buf(),
+                target: target.to_path_buf(),
+                kind: MountErrorKind::HelperFailed {
+                    stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// The -o option list the mount binary takes for these options
+    fn option_string(&self) -> String {
+        let mut options: Vec<String> = [
+            (MsFlags::MS_RDONLY, "ro"),
+            (MsFlags::MS_NOATIME, "noatime"),
+            (MsFlags::MS_NODIRATIME, "nodiratime"),
+            (MsFlags::MS_RELATIME, "relatime"),
+            (MsFlags::MS_NODEV, "nodev"),
+            (MsFlags::MS_NOSUID, "nosuid"),
+            (MsFlags::MS_NOEXEC, "noexec"),
+            (MsFlags::MS_SYNCHRONOUS, "sync"),
+        ]
+        .iter()
+        .filter(|(flag, _)| self.flags.contains(*flag))
+        .map(|(_, name)| name.to_string())
+        .collect();
+        options.extend(self.data.iter().cloned());
+        options.join(",")
+    }
+
+    fn helper_args(&self, source: &Path, target: &Path, fs_type: Option<&str>) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(fs_type) = fs_type {
+            args.push("-t".to_string());
+            args.push(fs_type.to_string());
+        }
+        let options = self.option_string();
+        if !options.is_empty() {
+            args.push("-o".to_string());
+            args.push(options);
+        }
+        args.push(source.to_string_lossy().into_owned());
+        args.push(target.to_string_lossy().into_owned());
+        args
+    }
+}
+
+/// Filesystems mounted through a userspace helper like mount.nfs or a fuse
+/// daemon can't be mounted with the syscall alone
+fn needs_helper(fs_type: &str) -> bool {
+    fs_type.starts_with("fuse")
+        || HELPER_DIRS
+            .iter()
+            .any(|dir| Path::new(dir).join(format!("mount.{}", fs_type)).exists())
+}
+
+/// How a process holds a filesystem busy
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "path", rename_all = "snake_case")]
+pub enum FileAccess {
+    /// The working directory is on the filesystem
+    Cwd(PathBuf),
+    /// The process is chrooted into the filesystem
+    Root(PathBuf),
+    /// An open file descriptor
+    Open(PathBuf),
+    /// A memory mapped file, ie: an executable or shared library
+    Mapped(PathBuf),
+}
+
+/// A process using files on a filesystem
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BusyProcess {
+    pub pid: i32,
+    pub command: String,
+    pub access: Vec<FileAccess>,
+}
+
+impl fmt::Display for BusyProcess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.command, self.pid)
+    }
+}
+
+/// Suffix for the unmount error listing the busy processes
+pub(crate) fn describe_busy(processes: &[BusyProcess]) -> String {
+    if processes.is_empty() {
+        return String::new();
+    }
+    format!(
+        ".  In use by {}",
+        processes
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    )
+}
+
+/// Builder for an unmount with umount2(2).
+///
+/// ```no_run
+/// use block_utils::mount::UnmountOptions;
+/// UnmountOptions::new().recursive().unmount("/mnt/data").unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnmountOptions {
+    flags: MntFlags,
+    recursive: bool,
+}
+
+impl Default for UnmountOptions {
+    fn default() -> Self {
+        UnmountOptions {
+            flags: MntFlags::empty(),
+            recursive: false,
+        }
+    }
+}
+
+impl UnmountOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Detach the mount now and clean it up once it's no longer busy (MNT_DETACH)
+    pub fn lazy(mut self) -> Self {
+        self.flags |= MntFlags::MNT_DETACH;
+        self
+    }
+
+    /// Abort pending requests.  Only network filesystems like nfs support this (MNT_FORCE)
+    pub fn force(mut self) -> Self {
+        self.flags |= MntFlags::MNT_FORCE;
+        self
+    }
+
+    /// Mark the mount as expired.  The first call fails with
+    /// `MountErrorKind::Expiring`, a second one unmounts it if nothing used
+    /// it in between (MNT_EXPIRE)
+    pub fn expire(mut self) -> Self {
+        self.flags |= MntFlags::MNT_EXPIRE;
+        self
+    }
+
+    /// Unmount everything mounted below the target first
+    pub fn recursive(mut self) -> Self {
+        self.recursive = true;
+        self
+    }
+
+    /// Unmount `target`.  If it's busy the error lists the processes using it.
+    pub fn unmount(&self, target: impl AsRef<Path>) -> BlockResult<()> {
+        let target = target.as_ref();
+        if self.recursive {
+            for mountpoint in mounts_below(&MountTable::read()?, target) {
+                self.unmount_one(&mountpoint)?;
+            }
+            Ok(())
+        } else {
+            self.unmount_one(target)
+        }
+    }
+
+    fn unmount_one(&self, target: &Path) -> BlockResult<()> {
+        debug!("umount2({}, {:?})", target.display(), self.flags);
+        umount2(target, self.flags).map_err(|e| {
+            let kind = MountErrorKind::from_errno(e);
+            // Take the filesystem from mountinfo, stat on the target can
+            // hang when a network server is gone
+            let processes = match kind {
+                MountErrorKind::Busy => MountTable::read()
+                    .ok()
+                    .and_then(|table| table.by_mount_point(target).map(|m| m.devnum))
+                    .and_then(|devnum| find_busy_processes_on(devnum).ok())
+                    .unwrap_or_default(),
+                _ => vec![],
+            };
+            BlockUtilsError::UnmountFailed {
+                target: target.to_path_buf(),
+                kind,
+                processes,
+            }
+        })
+    }
+}
+
+/// Processes with open files, memory mappings, a working directory or
+/// root directory on the filesystem mounted at `mount_point`
+pub fn find_busy_processes(mount_point: impl AsRef<Path>) -> BlockResult<Vec<BusyProcess>> {
+    let devnum = DevNum::from_dev_t(fs::metadata(mount_point)?.dev());
+    find_busy_processes_on(devnum)
+}
+
+fn find_busy_processes_on(devnum: DevNum) -> BlockResult<Vec<BusyProcess>> {
+    let on_fs = |path: &Path| {
+        fs::metadata(path)
+            .map(|m| DevNum::from_dev_t(m.dev()) == devnum)
+            .unwrap_or(false)
+    };
+    let mut processes = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_string_lossy().parse::<i32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        let proc_dir = entry.path();
+        let mut access = Vec::new();
+        // Processes can exit or deny access while we look, skip what can't be read
+        if on_fs(&proc_dir.join("cwd")) {
+            if let Ok(cwd) = fs::read_link(proc_dir.join("cwd")) {
+                access.push(FileAccess::Cwd(cwd));
+            }
+        }
+        if on_fs(&proc_dir.join("root")) {
+            if let Ok(root) = fs::read_link(proc_dir.join("root")) {
+                access.push(FileAccess::Root(root));
+            }
+        }
+        if let Ok(fds) = fs::read_dir(proc_dir.join("fd")) {
+            for fd in fds.flatten() {
+                if on_fs(&fd.path()) {
+                    if let Ok(path) = fs::read_link(fd.path()) {
+                        access.push(FileAccess::Open(path));
+                    }
+                }
+            }
+        }
+        if let Ok(maps) = fs::read_to_string(proc_dir.join("maps")) {
+            for path in parse_maps(&maps, devnum) {
+                let mapped = FileAccess::Mapped(path);
+                if !access.contains(&mapped) {
+                    access.push(mapped);
+                }
+            }
+        }
+        if !access.is_empty() {
+            let command = fs::read_to_string(proc_dir.join("comm"))
+                .map(|c| c.trim().to_string())
+                .unwrap_or_default();
+            processes.push(BusyProcess {
+                pid,
+                command,
+                access,
+            });
+        }
+    }
+    Ok(processes)
+}
+
+/// Files in a /proc/<pid>/maps listing that live on `devnum`, ie:
+/// "7f1c2a000000-7f1c2a021000 r-xp 00000000 08:01 1234 /usr/lib/libc.so.6"
+fn parse_maps(maps: &str, devnum: DevNum) -> Vec<PathBuf> {
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(6, char::is_whitespace);
+            let dev = fields.nth(3)?;
+            let inode = fields.next()?;
+            let path = fields.next()?.trim();
+            if inode == "0" || !path.starts_with('/') {
+                return None;
+            }
+            let (major, minor) = dev.split_once(':')?;
+            let map_devnum = DevNum {
+                major: u64::from_str_radix(major, 16).ok()?,
+                minor: u64::from_str_radix(minor, 16).ok()?,
+            };
+            if map_devnum == devnum {
+                Some(PathBuf::from(path))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// `target` and every mount below it, deepest and most recently mounted
+/// first so they can be unmounted in order
+fn mounts_below(table: &MountTable, target: &Path) -> Vec<PathBuf> {
+    table
+        .below(target)
+        .into_iter()
+        .rev()
+        .map(|m| m.mount_point.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_options() {
+        let options = MountOptions::new()
+            .fs_type("btrfs")
+            .read_only(true)
+            .noatime()
+            .nodev()
+            .discard()
+            .subvol("@data")
+            .data("compress=zstd");
+        assert_eq!(
+            options.flags,
+            MsFlags::MS_RDONLY | MsFlags::MS_NOATIME | MsFlags::MS_NODEV
+        );
+        assert_eq!(
+            options.data,
+            vec!["discard", "subvol=@data", "compress=zstd"]
+        );
+        assert_eq!(
+            options.helper_args(Path::new("/dev/sdb1"), Path::new("/mnt"), Some("btrfs")),
+            vec![
+                "-t",
+                "btrfs",
+                "-o",
+                "ro,noatime,nodev,discard,subvol=@data,compress=zstd",
+                "/dev/sdb1",
+                "/mnt"
+            ]
+        );
+        // Without a type the mount binary detects it
+        assert_eq!(
+            MountOptions::new().helper_args(Path::new("/dev/sdb1"), Path::new("/mnt"), None),
+            vec!["/dev/sdb1", "/mnt"]
+        );
+        assert!(!options.read_only(false).option_string().starts_with("ro"));
+        assert!(needs_helper("fuse.sshfs"));
+    }
+
+    #[test]
+    fn test_unmount_helpers() {
+        let maps =
+            "55d0c0a00000-55d0c0a21000 r-xp 00000000 08:01 1234                       /usr/bin/cat
+7f1c2a000000-7f1c2a021000 rw-p 00000000 fd:00 5678                       /mnt/my data/lib.so
+7ffd4b9e0000-7ffd4ba01000 rw-p 00000000 00:00 0                          [stack]
+";
+        assert_eq!(
+            parse_maps(
+                maps,
+                DevNum {
+                    major: 253,
+                    minor: 0
+                }
+            ),
+            vec![PathBuf::from("/mnt/my data/lib.so")]
+        );
+        assert!(parse_maps(maps, DevNum { major: 0, minor: 0 }).is_empty());
+
+        let mountinfo = "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 8:16 / /mnt rw,relatime shared:20 - xfs /dev/sdb rw
+41 40 0:45 / /mnt/a\\040b rw - tmpfs tmpfs rw
+42 41 0:46 / /mnt/a\\040b/c rw - tmpfs tmpfs rw
+43 22 8:32 / /mnt2 rw - xfs /dev/sdc rw
+";
+        assert_eq!(
+            mounts_below(&MountTable::parse(mountinfo).unwrap(), Path::new("/mnt")),
+            vec![
+                PathBuf::from("/mnt/a b/c"),
+                PathBuf::from("/mnt/a b"),
+                PathBuf::from("/mnt")
+            ]
+        );
+
+        let busy = vec![BusyProcess {
+            pid: 42,
+            command: "bash".to_string(),
+            access: vec![FileAccess::Cwd(PathBuf::from("/mnt"))],
+        }];
+        assert_eq!(describe_busy(&busy), ".  In use by bash(42)");
+        assert_eq!(describe_busy(&[]), "");
+    }
+
+    #[test]
+    fn test_mount_error_kind() {
+        assert_eq!(
+            MountErrorKind::from_errno(Errno::EBUSY),
+            MountErrorKind::Busy
+        );
+        assert_eq!(
+            MountErrorKind::from_errno(Errno::EIO),
+            MountErrorKind::Other {
+                errno: Errno::EIO as i32
+            }
+        );
+        assert_eq!(
+            MountErrorKind::InvalidArgument.to_string(),
+            "wrong fs type, bad option or bad superblock"
+        );
+    }
+}
@@ -0,0 +1,524 @@
+//! Parser for `/proc/self/mountinfo`.
+//!
+//! Unlike `/etc/mtab`, mountinfo lists every mount of the current mount
+//! namespace including bind mounts, records the major:minor of the mounted
+//! filesystem and escapes whitespace in paths as octal, ie: `\040` for a
+//! space.  See proc(5) for the format.
+#[cfg(target_os = "linux")]
+use crate::graph::DeviceGraph;
+use crate::{BlockResult, BlockUtilsError, DevNum, Device};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+pub(crate) const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+#[cfg(target_os = "linux")]
+const SYS_FS_BTRFS: &str = "/sys/fs/btrfs";
+
+/// Optional fields describing how mount events propagate
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PropagationField {
+    /// shared:N, a member of peer group N
+    Shared(u32),
+    /// master:N, receives events from peer group N
+    Master(u32),
+    /// propagate_from:N, the closest dominant peer group
+    PropagateFrom(u32),
+    Unbindable,
+    /// A field added by a newer kernel
+    Other(String),
+}
+
+impl PropagationField {
+    fn parse(field: &str) -> Self {
+        let tagged = |prefix: &str| field.strip_prefix(prefix).and_then(|n| n.parse().ok());
+        if let Some(n) = tagged("shared:") {
+            PropagationField::Shared(n)
+        } else if let Some(n) = tagged("master:") {
+            PropagationField::Master(n)
+        } else if let Some(n) = tagged("propagate_from:") {
+            PropagationField::PropagateFrom(n)
+        } else if field == "unbindable" {
+            PropagationField::Unbindable
+        } else {
+            PropagationField::Other(field.to_string())
+        }
+    }
+}
+
+/// One line of mountinfo
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MountInfo {
+    pub mount_id: u32,
+    pub parent_id: u32,
+    /// st_dev of files on this filesystem
+    pub devnum: DevNum,
+    /// Directory of the filesystem that forms the root of this mount.
+    /// Anything but "/" is a bind mount or a btrfs subvolume
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// Per mount options, ie: rw, noatime
+    pub mount_options: Vec<String>,
+    pub propagation: Vec<PropagationField>,
+    pub fs_type: String,
+    /// Filesystem specific source, ie: /dev/sda1, tmpfs or a zfs dataset
+    pub source: String,
+    /// Per superblock options, ie: inode64 or subvol=/@
+    pub super_options: Vec<String>,
+}
+
+impl MountInfo {
+    /// Parse a single mountinfo line
+    pub fn parse_line(line: &str) -> BlockResult<Self> {
+        let invalid = || BlockUtilsError::new(format!("Invalid mountinfo line: {}", line));
+        let fields: Vec<&str> = line.split(' ').collect();
+        // The optional fields end with a lone "-"
+        let separator = fields
+            .iter()
+            .skip(6)
+            .position(|f| *f == "-")
+            .map(|pos| pos + 6)
+            .ok_or_else(invalid)?;
+        if fields.len() < separator + 3 {
+            return Err(invalid());
+        }
+        let options = |s: &str| -> Vec<String> {
+            s.split(',')
+                .filter(|o| !o.is_empty())
+                .map(unescape_str)
+                .collect()
+        };
+        Ok(MountInfo {
+            mount_id: fields[0].parse()?,
+            parent_id: fields[1].parse()?,
+            devnum: fields[2].parse()?,
+            root: unescape_path(fields[3]),
+            mount_point: unescape_path(fields[4]),
+            mount_options: options(fields[5]),
+            propagation: fields[6..separator]
+                .iter()
+                .filter(|f| !f.is_empty())
+                .map(|f| PropagationField::parse(f))
+                .collect(),
+            fs_type: unescape_str(fields[separator + 1]),
+            source: unescape_str(fields[separator + 2]),
+            super_options: fields
+                .get(separator + 3)
+                .map(|o| options(o))
+                .unwrap_or_default(),
+        })
+    }
+
+    /// True if the mount is read only
+    pub fn read_only(&self) -> bool {
+        self.mount_options.iter().any(|o| o == "ro")
+    }
+
+    /// The source as a path if it names a device node, ie: /dev/sda1
+    pub fn device_path(&self) -> Option<PathBuf> {
+        if self.source.starts_with("/dev/") {
+            Some(PathBuf::from(&self.source))
+        } else {
+            None
+        }
+    }
+}
+
+/// All mounts in a mount namespace, in the order they were mounted
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MountTable {
+    pub entries: Vec<MountInfo>,
+}
+
+impl MountTable {
+    /// Read the mounts of the current process
+    pub fn read() -> BlockResult<Self> {
+        Self::read_from(MOUNTINFO_PATH)
+    }
+
+    /// Read a mountinfo file, ie: /proc/<pid>/mountinfo for the mounts in
+    /// another process's namespace
+    pub fn read_from(path: impl AsRef<Path>) -> BlockResult<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(input: &str) -> BlockResult<Self> {
+        Ok(MountTable {
+            entries: input
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(MountInfo::parse_line)
+                .collect::<BlockResult<Vec<MountInfo>>>()?,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MountInfo> {
+        self.entries.iter()
+    }
+
+    /// The mount visible at `mount_point`.  When several filesystems are
+    /// stacked on the same directory the last one mounted is returned.
+    pub fn by_mount_point(&self, mount_point: impl AsRef<Path>) -> Option<&MountInfo> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|m| m.mount_point == mount_point.as_ref())
+    }
+
+    /// The mount `path` lives on, the one with the longest mount point that
+    /// is a parent of `path`.  `path` should be canonical
+    pub fn containing(&self, path: impl AsRef<Path>) -> Option<&MountInfo> {
+        // max_by_key picks the last of equal keys, the mount on top
+        self.entries
+            .iter()
+            .filter(|m| path.as_ref().starts_with(&m.mount_point))
+            .max_by_key(|m| m.mount_point.components().count())
+    }
+
+    /// Every mount of the filesystem with device number `devnum`, including
+    /// bind mounts
+    pub fn by_devnum(&self, devnum: DevNum) -> Vec<&MountInfo> {
+        self.entries.iter().filter(|m| m.devnum == devnum).collect()
+    }
+
+    /// Every mount of the block device at `device`, ie: "/dev/sda1".
+    /// Mounts match on the device number of the node or, for filesystems
+    /// like btrfs that report an anonymous device number, on the source
+    /// after resolving symlinks like /dev/disk/by-uuid/* and /dev/mapper/*.
+    pub fn by_device(&self, device: impl AsRef<Path>) -> Vec<&MountInfo> {
+        let devnum = fs::metadata(&device)
+            .ok()
+            .filter(|meta| meta.file_type().is_block_device())
+            .map(|meta| DevNum::from_dev_t(meta.rdev()));
+        let canonical = fs::canonicalize(&device).ok();
+        self.entries
+            .iter()
+            .filter(|m| {
+                Some(m.devnum) == devnum
+                    || Path::new(&m.source) == device.as_ref()
+                    || (canonical.is_some()
+                        && m.source.starts_with("/dev/")
+                        && fs::canonicalize(&m.source).ok() == canonical)
+            })
+            .collect()
+    }
+
+    /// `mount_point` and every mount below it
+    pub fn below(&self, mount_point: impl AsRef<Path>) -> Vec<&MountInfo> {
+        self.entries
+            .iter()
+            .filter(|m| m.mount_point.starts_with(&mount_point))
+            .collect()
+    }
+}
+
+/// A block device holding data of a mount.  A mount spread over several
+/// devices, like a multi-device btrfs or a zfs pool, has one record per device.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MountedDevice {
+    pub device: Device,
+    pub mount: MountInfo,
+    /// Physical disks under the device, ie: the disks of an LVM volume group
+    pub backing_disks: Vec<String>,
+}
+
+#[cfg(target_os = "linux")]
+impl MountTable {
+    /// Resolve every mount to the block devices it lives on.  Device mapper,
+    /// LVM and md devices are found through the major:minor of the mount,
+    /// btrfs through /sys/fs/btrfs, zfs datasets through the udev labels of
+    /// their pool members and overlay mounts through the filesystems of their
+    /// lower and upper directories.  Mounts without any block device, like
+    /// tmpfs or proc, are left out.
+    pub fn mounted_devices(&self) -> BlockResult<Vec<MountedDevice>> {
+        let graph = DeviceGraph::new()?;
+        let mut devices: HashMap<String, Device> = HashMap::new();
+        let mut mounted = Vec::new();
+        for mount in &self.entries {
+            for name in self.resolve_mount(mount, 0) {
+                let device = match devices.get(&name) {
+                    Some(device) => device.clone(),
+                    None => match crate::get_device_info(Path::new("/dev").join(&name)) {
+                        Ok(device) => {
+                            devices.insert(name.clone(), device.clone());
+                            device
+                        }
+                        // Devices can disappear while a lazy unmount finishes
+                        Err(_) => continue,
+                    },
+                };
+                mounted.push(MountedDevice {
+                    backing_disks: graph.backing_disks(&name).unwrap_or_default(),
+                    device,
+                    mount: mount.clone(),
+                });
+            }
+        }
+        Ok(mounted)
+    }
+
+    /// Kernel names of the block devices under `mount`
+    fn resolve_mount(&self, mount: &MountInfo, depth: usize) -> Vec<String> {
+        let mut names = match mount.fs_type.as_str() {
+            "btrfs" => source_name(&mount.source)
+                .map(|name| btrfs_devices(Path::new(SYS_FS_BTRFS), &name))
+                .unwrap_or_default(),
+            "zfs" => zfs_pool_devices(mount.source.split('/').next().unwrap_or_default()),
+            // Overlays of overlays are possible but never deep
+            "overlay" if depth < 4 => overlay_dirs(&mount.super_options)
+                .iter()
+                .filter_map(|dir| fs::metadata(dir).ok())
+                .map(|meta| DevNum::from_dev_t(meta.dev()))
+                .filter_map(|devnum| self.by_devnum(devnum).into_iter().next())
+                .flat_map(|lower| self.resolve_mount(lower, depth + 1))
+                .collect(),
+            _ => vec![],
+        };
+        if names.is_empty() {
+            if let Some(name) = mount
+                .devnum
+                .kernel_name()
+                .or_else(|| source_name(&mount.source))
+            {
+                names.push(name);
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// Kernel name of a /dev source after following symlinks like /dev/mapper/*
+#[cfg(target_os = "linux")]
+fn source_name(source: &str) -> Option<String> {
+    if !source.starts_with("/dev/") {
+        return None;
+    }
+    fs::canonicalize(source)
+        .ok()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+}
+
+/// Every device of the btrfs filesystem `name` belongs to
+#[cfg(target_os = "linux")]
+fn btrfs_devices(sys_fs_btrfs: &Path, name: &str) -> Vec<String> {
+    let filesystems = match fs::read_dir(sys_fs_btrfs) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    for fs_dir in filesystems.flatten() {
+        let devices: Vec<String> = match fs::read_dir(fs_dir.path().join("devices")) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => continue,
+        };
+        if devices.iter().any(|d| d == name) {
+            return devices;
+        }
+    }
+    vec![]
+}
+
+/// Devices udev labelled as members of zfs pool `pool`
+#[cfg(target_os = "linux")]
+fn zfs_pool_devices(pool: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut enumerator = match udev::Enumerator::new() {
+        Ok(e) => e,
+        Err(_) => return names,
+    };
+    if enumerator.match_subsystem("block").is_err()
+        || enumerator
+            .match_property("ID_FS_TYPE", "zfs_member")
+            .is_err()
+    {
+        return names;
+    }
+    if let Ok(devices) = enumerator.scan_devices() {
+        for device in devices {
+            if crate::get_udev_property(&device, "ID_FS_LABEL").as_deref() == Some(pool) {
+                names.push(device.sysname().to_string_lossy().into_owned());
+            }
+        }
+    }
+    names
+}
+
+/// The lowerdir and upperdir directories of an overlay mount.  Multiple
+/// lower directories are separated by ':' and literal colons are escaped
+#[cfg(target_os = "linux")]
+fn overlay_dirs(super_options: &[String]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for option in super_options {
+        let value = match option.split_once('=') {
+            Some(("lowerdir", v)) | Some(("upperdir", v)) => v,
+            _ => continue,
+        };
+        let mut current = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => current.extend(chars.next()),
+                ':' => dirs.push(PathBuf::from(std::mem::take(&mut current))),
+                c => current.push(c),
+            }
+        }
+        dirs.push(PathBuf::from(current));
+    }
+    dirs.retain(|d| !d.as_os_str().is_empty());
+    dirs
+}
+
+/// Decode the octal escapes the kernel uses for space, tab, newline and
+/// backslash
+fn unescape(field: &str) -> Vec<u8> {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = &bytes[i + 1..i + 4];
+            if octal.iter().all(|b| (b'0'..=b'7').contains(b)) {
+                let value = octal
+                    .iter()
+                    .fold(0u32, |acc, b| acc * 8 + u32::from(b - b'0'));
+                if let Ok(byte) = u8::try_from(value) {
+                    out.push(byte);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+fn unescape_path(field: &str) -> PathBuf {
+    PathBuf::from(OsString::from_vec(unescape(field)))
+}
+
+pub(crate) fn unescape_str(field: &str) -> String {
+    String::from_utf8_lossy(&unescape(field)).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = r"22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw,errors=remount-ro
+40 22 8:16 / /mnt/my\040data rw,noatime shared:20 master:3 - xfs /dev/sdb rw,inode64
+41 22 8:16 /exports /srv/nfs ro,relatime - xfs /dev/sdb rw,inode64
+42 22 0:45 /@home /home rw,relatime shared:30 - btrfs /dev/mapper/luks-1 rw,space_cache,subvol=/@home
+43 22 0:46 / /tmp rw,nosuid,nodev - tmpfs tmpfs rw,size=1024k
+44 22 8:32 / /mnt/my\040data rw - ext4 /dev/sdc rw
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let table = MountTable::parse(MOUNTINFO).unwrap();
+        assert_eq!(table.entries.len(), 6);
+
+        let root = &table.entries[0];
+        assert_eq!(root.mount_id, 22);
+        assert_eq!(root.parent_id, 1);
+        assert_eq!(root.devnum, DevNum { major: 8, minor: 1 });
+        assert_eq!(root.propagation, vec![PropagationField::Shared(1)]);
+        assert_eq!(root.super_options, vec!["rw", "errors=remount-ro"]);
+
+        let data = &table.entries[1];
+        assert_eq!(data.mount_point, PathBuf::from("/mnt/my data"));
+        assert_eq!(
+            data.propagation,
+            vec![PropagationField::Shared(20), PropagationField::Master(3)]
+        );
+        assert_eq!(data.mount_options, vec!["rw", "noatime"]);
+
+        // A bind mount of a directory on the same filesystem
+        let bind = &table.entries[2];
+        assert_eq!(bind.root, PathBuf::from("/exports"));
+        assert!(bind.read_only());
+        assert!(bind.propagation.is_empty());
+
+        assert_eq!(table.entries[4].device_path(), None);
+        assert!(MountInfo::parse_line("22 1 8:1 / / rw").is_err());
+    }
+
+    #[test]
+    fn test_mount_table_lookup() {
+        let table = MountTable::parse(MOUNTINFO).unwrap();
+        // The most recent mount on a directory hides the older one
+        assert_eq!(
+            table.by_mount_point("/mnt/my data").unwrap().source,
+            "/dev/sdc"
+        );
+        assert!(table.by_mount_point("/mnt/my").is_none());
+        assert_eq!(
+            table.containing("/mnt/my data/a/b").unwrap().source,
+            "/dev/sdc"
+        );
+        assert_eq!(
+            table.containing("/mnt/my").unwrap().mount_point,
+            PathBuf::from("/")
+        );
+        assert_eq!(
+            table
+                .by_devnum(DevNum {
+                    major: 8,
+                    minor: 16
+                })
+                .len(),
+            2
+        );
+        assert_eq!(
+            table.by_device("/dev/mapper/luks-1")[0].mount_point,
+            PathBuf::from("/home")
+        );
+        assert_eq!(table.below("/mnt").len(), 2);
+        assert_eq!(unescape_str(r"a\011b\134c\08"), "a\tb\\c\\08");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_mount_resolution_helpers() {
+        let options = vec![
+            "rw".to_string(),
+            r"lowerdir=/var/lib/a\:b:/var/lib/c".to_string(),
+            "upperdir=/var/lib/upper".to_string(),
+            "workdir=/var/lib/work".to_string(),
+        ];
+        assert_eq!(
+            overlay_dirs(&options),
+            vec![
+                PathBuf::from("/var/lib/a:b"),
+                PathBuf::from("/var/lib/c"),
+                PathBuf::from("/var/lib/upper")
+            ]
+        );
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        for dev in &["sdb", "sdc"] {
+            fs::create_dir_all(tmp_dir.path().join("3f2a/devices").join(dev)).unwrap();
+        }
+        fs::create_dir_all(tmp_dir.path().join("features")).unwrap();
+        let mut devices = btrfs_devices(tmp_dir.path(), "sdc");
+        devices.sort();
+        assert_eq!(devices, vec!["sdb", "sdc"]);
+        assert!(btrfs_devices(tmp_dir.path(), "sdd").is_empty());
+        assert_eq!(source_name("tmpfs"), None);
+    }
+}
@@ -0,0 +1,270 @@
+//! Mounting inside another process's mount namespace.
+//!
+//! setns(2) can't move a multithreaded process into another mount namespace,
+//! so `MountNamespace::run` does the work on a short lived thread that first
+//! stops sharing its filesystem attributes with the rest of the process.
+//! The calling thread and the rest of the process stay where they are.
+//!
+//! Containers rarely have the host's device nodes.  `mount_device` resolves
+//! the device on the host, by UUID where possible, and creates a temporary
+//! node with the same major:minor inside the namespace when it's missing.
+use crate::mount::MountOptions;
+use crate::mountinfo::MountTable;
+use crate::{BlockResult, BlockUtilsError, DevNum, Device};
+
+use log::debug;
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+
+use std::fs::{self, File};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use uuid::Uuid;
+
+const DEV: &str = "/dev";
+
+/// A mount namespace, identified by a namespace file like /proc/<pid>/ns/mnt
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MountNamespace {
+    ns_path: PathBuf,
+    /// Used to read the namespace's mountinfo
+    pid: Option<i32>,
+}
+
+impl MountNamespace {
+    /// The mount namespace `pid` lives in, ie: a container's init process
+    pub fn of_pid(pid: i32) -> Self {
+        MountNamespace {
+            ns_path: PathBuf::from(format!("/proc/{}/ns/mnt", pid)),
+            pid: Some(pid),
+        }
+    }
+
+    /// A namespace file that was bind mounted somewhere to keep it alive
+    pub fn from_path(ns_path: impl AsRef<Path>) -> Self {
+        MountNamespace {
+            ns_path: ns_path.as_ref().to_path_buf(),
+            pid: None,
+        }
+    }
+
+    /// Run `f` inside the namespace on a separate thread and return its result
+    pub fn run<F, T>(&self, f: F) -> BlockResult<T>
+    where
+        F: FnOnce() -> BlockResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let ns = File::open(&self.ns_path)?;
+        thread::spawn(move || {
+            unshare(CloneFlags::CLONE_FS)?;
+            setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWNS)?;
+            f()
+        })
+        .join()
+        .map_err(|_| BlockUtilsError::new("Mount namespace thread panicked".to_string()))?
+    }
+
+    /// The mounts visible inside the namespace.  Only available for
+    /// namespaces created with `of_pid`
+    pub fn mount_table(&self) -> BlockResult<MountTable> {
+        match self.pid {
+            Some(pid) => MountTable::read_from(format!("/proc/{}/mountinfo", pid)),
+            None => self.run(MountTable::read),
+        }
+    }
+
+    /// Mount `device` at `target`, a path inside the namespace
+    pub fn mount_device(
+        &self,
+        device: &Device,
+        target: impl AsRef<Path>,
+        options: &MountOptions,
+    ) -> BlockResult<()> {
+        let (host_path, devnum) = resolve_device(Path::new(DEV), &device.name, device.id)?;
+        let fs_type = device.fs_type.to_str().to_string();
+        let name = device.name.clone();
+        let target = target.as_ref().to_path_buf();
+        let options = options.clone();
+        debug!(
+            "mounting {} ({}) at {} in {}",
+            host_path.display(),
+            devnum,
+            target.display(),
+            self.ns_path.display()
+        );
+        self.run(move || {
+            let node = DeviceNode::ensure(Path::new(DEV), &host_path, devnum, &name)?;
+            options.mount_as(node.path(), &target, &fs_type)
+        })
+    }
+
+    /// Bind mount `source` at `target`, both paths inside the namespace
+    pub fn bind(
+        &self,
+        source: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+        recursive: bool,
+        options: &MountOptions,
+    ) -> BlockResult<()> {
+        let source = source.as_ref().to_path_buf();
+        let target = target.as_ref().to_path_buf();
+        let options = options.clone();
+        self.run(move || options.bind(&source, &target, recursive))
+    }
+}
+
+/// The device node of a device named `name` under `dev` and its device
+/// number.  The disk/by-uuid link of filesystem `id` is preferred since kernel
+/// names can be reused after a disk is removed.
+fn resolve_device(dev: &Path, name: &str, id: Option<Uuid>) -> BlockResult<(PathBuf, DevNum)> {
+    let by_name = dev.join(name);
+    let path = match id {
+        Some(id) => {
+            let by_uuid = dev.join("disk/by-uuid").join(id.hyphenated().to_string());
+            fs::canonicalize(&by_uuid).unwrap_or(by_name)
+        }
+        None => by_name,
+    };
+    let meta = fs::metadata(&path)?;
+    if !meta.file_type().is_block_device() {
+        return Err(BlockUtilsError::new(format!(
+            "{} is not a block device",
+            path.display()
+        )));
+    }
+    Ok((path, DevNum::from_dev_t(meta.rdev())))
+}
+
+/// A device node inside a mount namespace.  Temporary nodes are removed on drop.
+struct DeviceNode {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl DeviceNode {
+    /// Use `path` if it is the right device in the current namespace,
+    /// otherwise create a node in `dev`
+    fn ensure(dev: &Path, path: &Path, devnum: DevNum, name: &str) -> BlockResult<Self> {
+        let matches = fs::metadata(path)
+            .map(|m| m.file_type().is_block_device() && DevNum::from_dev_t(m.rdev()) == devnum)
+            .unwrap_or(false);
+        if matches {
+            return Ok(DeviceNode {
+                path: path.to_path_buf(),
+                temporary: false,
+            });
+        }
+        let tmp = dev.join(format!(".block-utils-{}-{}", name, std::process::id()));
+        mknod(
+            &tmp,
+            SFlag::S_IFBLK,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+            makedev(devnum.major, devnum.minor),
+        )?;
+        Ok(DeviceNode {
+            path: tmp,
+            temporary: true,
+        })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DeviceNode {
+    fn drop(&mut self) {
+        // The mount keeps a reference to the device, the node isn't needed anymore
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// A /dev with sdb, sdc and a by-uuid link pointing at sdc
+    fn fake_dev(id: Uuid) -> TempDir {
+        let tmp_dir = TempDir::new().unwrap();
+        let dev = tmp_dir.path();
+        fs::write(dev.join("sdb"), "").unwrap();
+        fs::write(dev.join("sdc"), "").unwrap();
+        fs::create_dir_all(dev.join("disk/by-uuid")).unwrap();
+        symlink("../../sdc", dev.join("disk/by-uuid").join(id.to_string())).unwrap();
+        tmp_dir
+    }
+
+    #[test]
+    fn test_resolve_device() {
+        let id = Uuid::parse_str("0b8e5f6a-7d37-4a57-9f0c-6ad9a7a6b0f1").unwrap();
+        let tmp_dir = fake_dev(id);
+        let dev = tmp_dir.path();
+        let resolved = |name: &str, id: Option<Uuid>| {
+            // The fake nodes are regular files so every lookup ends in an
+            // error naming the path that was picked
+            resolve_device(dev, name, id).unwrap_err().to_string()
+        };
+        let sdb = dev.join("sdb").display().to_string();
+        let sdc = fs::canonicalize(dev.join("sdc"))
+            .unwrap()
+            .display()
+            .to_string();
+
+        // The uuid wins over a kernel name that was reused
+        assert!(resolved("sdb", Some(id)).contains(&sdc));
+        assert!(resolved("sdb", None).contains(&sdb));
+        let other = Uuid::parse_str("6a3c1f2e-8d4b-4e5a-9f10-2b3c4d5e6f70").unwrap();
+        assert!(resolved("sdb", Some(other)).contains(&sdb));
+        assert!(resolve_device(dev, "sdd", None).is_err());
+    }
+
+    #[test]
+    fn test_device_node() {
+        let tmp_dir = TempDir::new().unwrap();
+        let devnum = DevNum { major: 7, minor: 0 };
+        let stale = tmp_dir.path().join("loop0");
+        fs::write(&stale, "").unwrap();
+        // A regular file is never the device so a node is made next to it.
+        // mknod needs CAP_MKNOD so only check the result when it worked.
+        let expected = tmp_dir
+            .path()
+            .join(format!(".block-utils-loop0-{}", std::process::id()));
+        if let Ok(node) = DeviceNode::ensure(tmp_dir.path(), &stale, devnum, "loop0") {
+            assert!(node.temporary);
+            assert_eq!(node.path(), expected);
+            let meta = fs::metadata(node.path()).unwrap();
+            assert!(meta.file_type().is_block_device());
+            assert_eq!(DevNum::from_dev_t(meta.rdev()), devnum);
+            drop(node);
+        }
+        assert!(!expected.exists());
+
+        // An existing node with the right number is used as is
+        if let Ok((path, devnum)) = resolve_device(Path::new(DEV), "loop0", None) {
+            let node = DeviceNode::ensure(tmp_dir.path(), &path, devnum, "loop0").unwrap();
+            assert!(!node.temporary);
+            assert_eq!(node.path(), path);
+        }
+    }
+
+    #[test]
+    fn test_run_in_own_namespace() {
+        let ns = MountNamespace::of_pid(std::process::id() as i32);
+        assert!(ns.mount_table().unwrap().by_mount_point("/").is_some());
+        // Joining even the current mount namespace needs CAP_SYS_ADMIN
+        match ns.run(|| Ok(std::thread::current().id())) {
+            Ok(id) => assert_ne!(id, std::thread::current().id()),
+            Err(BlockUtilsError::NixError(e)) => assert_eq!(e, nix::errno::Errno::EPERM),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+        assert!(MountNamespace::from_path("/nonexistent/ns/mnt")
+            .run(|| Ok(()))
+            .is_err());
+    }
+}
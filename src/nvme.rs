@@ -1,5 +1,7 @@
+use crate::safety::{self, Preflight};
 use crate::{BlockResult, BlockUtilsError};
 use serde::{Deserialize, Serialize};
+use std::fs::read_dir;
 use std::path::Path;
 use std::process::Command;
 
@@ -94,7 +96,16 @@ pub fn get_log(dev: &Path, id: u8, len: u16) -> BlockResult<Vec<u8>> {
 }
 
 // Format an nvme block device
+// Refuses if the device or any of its namespaces are in use
 pub fn format(dev: &Path) -> BlockResult<()> {
+    format_with_preflight(dev, Preflight::Check)
+}
+
+/// Same as `format` but `Preflight::Override` skips the in-use checks
+pub fn format_with_preflight(dev: &Path, preflight: Preflight) -> BlockResult<()> {
+    if preflight == Preflight::Check {
+        check_namespaces_not_in_use(dev)?;
+    }
     let out = Command::new("nvme")
         .args(&["format", &dev.to_string_lossy()])
         .output()?;
@@ -106,6 +117,24 @@ pub fn format(dev: &Path) -> BlockResult<()> {
     Ok(())
 }
 
+/// `dev` may be a namespace (/dev/nvme0n1) or a controller (/dev/nvme0).
+/// Formatting a controller wipes every namespace on it so check them all.
+fn check_namespaces_not_in_use(dev: &Path) -> BlockResult<()> {
+    safety::check_not_in_use(dev)?;
+    let name = match dev.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Ok(()),
+    };
+    let namespace_prefix = format!("{}n", name);
+    for entry in read_dir("/sys/class/block")? {
+        let ns_name = entry?.file_name().to_string_lossy().into_owned();
+        if ns_name.starts_with(&namespace_prefix) && !ns_name.contains('p') {
+            safety::check_not_in_use(Path::new("/dev").join(ns_name))?;
+        }
+    }
+    Ok(())
+}
+
 pub fn list_nvme_namespaces(dev: &Path) -> BlockResult<Vec<String>> {
     let out = Command::new("nvme")
         .args(&["list-ns", &dev.to_string_lossy(), "-o", "json"])
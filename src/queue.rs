@@ -0,0 +1,291 @@
+//! Block queue tuning through sysfs.
+//!
+//! Every request queue exposes its knobs under `/sys/block/<dev>/queue`.
+//! `QueueSettings` reads and writes the commonly tuned ones.  Fields left as
+//! `None` are not touched when applying.
+use crate::{BlockResult, BlockUtilsError};
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// I/O schedulers.  blk-mq kernels offer mq-deadline, bfq, kyber and none.
+/// The legacy single queue schedulers only exist on kernels older than 5.0
+#[derive(Clone, Debug, Eq, PartialEq, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum IoScheduler {
+    MqDeadline,
+    Bfq,
+    Kyber,
+    None,
+    Cfq,
+    Deadline,
+    Noop,
+    /// A scheduler this crate doesn't know about
+    #[strum(default)]
+    Other(String),
+}
+
+/// Tunable attributes of a block device request queue
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QueueSettings {
+    pub scheduler: Option<IoScheduler>,
+    /// Number of requests the scheduler may queue
+    pub nr_requests: Option<u64>,
+    pub read_ahead_kb: Option<u64>,
+    /// Largest request the block layer will build
+    pub max_sectors_kb: Option<u64>,
+    /// 0: complete on any cpu, 1: same cpu group, 2: the submitting cpu
+    pub rq_affinity: Option<u8>,
+    /// 0: all merges, 1: only simple one-hit merges, 2: no merges
+    pub nomerges: Option<u8>,
+    /// Writeback throttling target latency.  0 disables it, -1 restores the default
+    pub wbt_lat_usec: Option<i64>,
+    /// Account I/O statistics for the device
+    pub iostats: Option<bool>,
+    /// Contribute I/O timings to the entropy pool
+    pub add_random: Option<bool>,
+}
+
+impl QueueSettings {
+    /// Read the current queue settings of `device` (like "/dev/sda").
+    /// Attributes the kernel doesn't expose are left as `None`.
+    pub fn read(device: impl AsRef<Path>) -> BlockResult<Self> {
+        Self::read_from(&queue_dir(device)?)
+    }
+
+    /// Write every attribute that is set to `device`
+    pub fn apply(&self, device: impl AsRef<Path>) -> BlockResult<()> {
+        self.apply_to(&queue_dir(device)?)
+    }
+
+    /// The sysfs attribute names and values this would write, in the order
+    /// they are applied.  The scheduler goes first because changing it
+    /// resets nr_requests.
+    pub fn attributes(&self) -> Vec<(&'static str, String)> {
+        let bool_str = |b: bool| if b { "1" } else { "0" }.to_string();
+        let mut attrs = Vec::new();
+        if let Some(ref scheduler) = self.scheduler {
+            attrs.push(("scheduler", scheduler.to_string()));
+        }
+        if let Some(n) = self.nr_requests {
+            attrs.push(("nr_requests", n.to_string()));
+        }
+        if let Some(n) = self.read_ahead_kb {
+            attrs.push(("read_ahead_kb", n.to_string()));
+        }
+        if let Some(n) = self.max_sectors_kb {
+            attrs.push(("max_sectors_kb", n.to_string()));
+        }
+        if let Some(n) = self.rq_affinity {
+            attrs.push(("rq_affinity", n.to_string()));
+        }
+        if let Some(n) = self.nomerges {
+            attrs.push(("nomerges", n.to_string()));
+        }
+        if let Some(n) = self.wbt_lat_usec {
+            attrs.push(("wbt_lat_usec", n.to_string()));
+        }
+        if let Some(b) = self.iostats {
+            attrs.push(("iostats", bool_str(b)));
+        }
+        if let Some(b) = self.add_random {
+            attrs.push(("add_random", bool_str(b)));
+        }
+        attrs
+    }
+
+    fn read_from(queue: &Path) -> BlockResult<Self> {
+        let scheduler = match read_attr(queue, "scheduler")? {
+            Some(s) => parse_schedulers(&s).0,
+            None => None,
+        };
+        Ok(QueueSettings {
+            scheduler,
+            nr_requests: read_num(queue, "nr_requests")?,
+            read_ahead_kb: read_num(queue, "read_ahead_kb")?,
+            max_sectors_kb: read_num(queue, "max_sectors_kb")?,
+            rq_affinity: read_num(queue, "rq_affinity")?,
+            nomerges: read_num(queue, "nomerges")?,
+            wbt_lat_usec: read_num(queue, "wbt_lat_usec")?,
+            iostats: read_num::<u8>(queue, "iostats")?.map(|v| v == 1),
+            add_random: read_num::<u8>(queue, "add_random")?.map(|v| v == 1),
+        })
+    }
+
+    fn apply_to(&self, queue: &Path) -> BlockResult<()> {
+        self.validate()?;
+        if let Some(ref scheduler) = self.scheduler {
+            let available = match read_attr(queue, "scheduler")? {
+                Some(s) => parse_schedulers(&s).1,
+                None => vec![],
+            };
+            if !available.contains(scheduler) {
+                return Err(BlockUtilsError::new(format!(
+                    "Scheduler {} is not available for {}.  Available: {:?}",
+                    scheduler,
+                    queue.display(),
+                    available
+                )));
+            }
+        }
+        for (attr, value) in self.attributes() {
+            fs::write(queue.join(attr), value)?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> BlockResult<()> {
+        for (name, value) in &[
+            ("rq_affinity", self.rq_affinity),
+            ("nomerges", self.nomerges),
+        ] {
+            if let Some(v) = value {
+                if *v > 2 {
+                    return Err(BlockUtilsError::new(format!(
+                        "{} must be 0, 1 or 2.  Got {}",
+                        name, v
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The schedulers the kernel offers for `device`
+pub fn available_schedulers(device: impl AsRef<Path>) -> BlockResult<Vec<IoScheduler>> {
+    let queue = queue_dir(device)?;
+    Ok(match read_attr(&queue, "scheduler")? {
+        Some(s) => parse_schedulers(&s).1,
+        None => vec![],
+    })
+}
+
+/// The scheduler currently active for `device`
+pub fn get_scheduler(device: impl AsRef<Path>) -> BlockResult<Option<IoScheduler>> {
+    Ok(QueueSettings::read(device)?.scheduler)
+}
+
+/// Switch `device` to `scheduler` if the kernel offers it
+pub fn set_scheduler(device: impl AsRef<Path>, scheduler: &IoScheduler) -> BlockResult<()> {
+    QueueSettings {
+        scheduler: Some(scheduler.clone()),
+        ..Default::default()
+    }
+    .apply(device)
+}
+
+/// Parse the scheduler attribute, ie: "[mq-deadline] kyber bfq none"
+/// into the active scheduler and all the available ones
+fn parse_schedulers(s: &str) -> (Option<IoScheduler>, Vec<IoScheduler>) {
+    let mut active = None;
+    let mut available = Vec::new();
+    for word in s.split_whitespace() {
+        let name = word.trim_start_matches('[').trim_end_matches(']');
+        // FromStr can't fail thanks to the Other variant
+        let scheduler = IoScheduler::from_str(name).unwrap_or(IoScheduler::Other(name.into()));
+        if word.starts_with('[') {
+            active = Some(scheduler.clone());
+        }
+        available.push(scheduler);
+    }
+    // Devices without a choice of scheduler only print "none"
+    if active.is_none() && available.len() == 1 {
+        active = available.first().cloned();
+    }
+    (active, available)
+}
+
+/// `/sys/class/block/<dev>/queue`.  Partitions share the queue of their disk.
+pub(crate) fn queue_dir(device: impl AsRef<Path>) -> BlockResult<PathBuf> {
+    let sys_path = crate::dev_path_to_sys_path(&device)?;
+    let queue = sys_path.join("queue");
+    if queue.exists() {
+        return Ok(queue);
+    }
+    if sys_path.join("partition").exists() {
+        if let Some(disk) = fs::canonicalize(&sys_path)?.parent() {
+            return Ok(disk.join("queue"));
+        }
+    }
+    Err(BlockUtilsError::new(format!(
+        "{} has no request queue",
+        device.as_ref().display()
+    )))
+}
+
+fn read_attr(queue: &Path, attr: &str) -> BlockResult<Option<String>> {
+    let path = queue.join(attr);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?.trim().to_string()))
+}
+
+fn read_num<T: FromStr>(queue: &Path, attr: &str) -> BlockResult<Option<T>> {
+    Ok(read_attr(queue, attr)?.and_then(|v| v.parse::<T>().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_schedulers() {
+        let (active, available) = parse_schedulers("[mq-deadline] kyber bfq none");
+        assert_eq!(active, Some(IoScheduler::MqDeadline));
+        assert_eq!(
+            available,
+            vec![
+                IoScheduler::MqDeadline,
+                IoScheduler::Kyber,
+                IoScheduler::Bfq,
+                IoScheduler::None
+            ]
+        );
+        assert_eq!(parse_schedulers("none").0, Some(IoScheduler::None));
+    }
+
+    #[test]
+    fn test_queue_settings() {
+        let tmp_dir = TempDir::new().unwrap();
+        let queue = tmp_dir.path();
+        fs::write(queue.join("scheduler"), "[mq-deadline] bfq none\n").unwrap();
+        fs::write(queue.join("nr_requests"), "64\n").unwrap();
+        fs::write(queue.join("read_ahead_kb"), "128\n").unwrap();
+        fs::write(queue.join("iostats"), "1\n").unwrap();
+
+        let settings = QueueSettings::read_from(queue).unwrap();
+        assert_eq!(settings.scheduler, Some(IoScheduler::MqDeadline));
+        assert_eq!(settings.nr_requests, Some(64));
+        assert_eq!(settings.iostats, Some(true));
+        assert_eq!(settings.max_sectors_kb, None);
+
+        let new_settings = QueueSettings {
+            scheduler: Some(IoScheduler::Bfq),
+            read_ahead_kb: Some(4096),
+            add_random: Some(false),
+            ..Default::default()
+        };
+        new_settings.apply_to(queue).unwrap();
+        assert_eq!(fs::read_to_string(queue.join("scheduler")).unwrap(), "bfq");
+        assert_eq!(
+            fs::read_to_string(queue.join("read_ahead_kb")).unwrap(),
+            "4096"
+        );
+        assert_eq!(fs::read_to_string(queue.join("add_random")).unwrap(), "0");
+
+        let kyber = QueueSettings {
+            scheduler: Some(IoScheduler::Kyber),
+            ..Default::default()
+        };
+        fs::write(queue.join("scheduler"), "[mq-deadline] bfq none\n").unwrap();
+        assert!(kyber.apply_to(queue).is_err());
+    }
+}
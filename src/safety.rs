@@ -0,0 +1,353 @@
+//! Pre-flight checks for destructive operations.
+//!
+//! Before erasing or formatting a device make sure nothing on the system is
+//! using it: no mounted filesystems, swap, raid, LVM, ZFS or bcache built on
+//! the device or any of its partitions, and nobody holding it open
+//! exclusively.
+use crate::graph::{DeviceGraph, DeviceNode, NodeKind};
+use crate::{BlockResult, BlockUtilsError};
+
+use nix::fcntl::OFlag;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+const SWAPS_PATH: &str = "/proc/swaps";
+const ZFS_KSTAT_PATH: &str = "/proc/spl/kstat/zfs";
+
+/// Whether a destructive operation should check the device is unused first
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Preflight {
+    /// Refuse to operate on a device that is in use
+    Check,
+    /// Skip the in-use checks.  You're on your own
+    Override,
+}
+
+/// Something that is using a block device
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Blocker {
+    /// `device` has a filesystem mounted at `mountpoint`
+    Mounted { device: String, mountpoint: PathBuf },
+    /// `device` is active swap
+    Swap { device: String },
+    /// `device` is a member of the software raid `array`
+    MdMember { device: String, array: String },
+    /// `device` is a physical volume backing the logical volume `volume`
+    LvmMember { device: String, volume: String },
+    /// `device` is a member of the imported zfs pool `pool`
+    ZfsMember { device: String, pool: String },
+    /// `device` is a backing or cache device of `bcache`
+    BcacheMember { device: String, bcache: String },
+    /// `device` has some other device stacked on top of it
+    Holder { device: String, holder: String },
+    /// Somebody holds `device` open with O_EXCL
+    ExclusivelyOpened { device: String },
+}
+
+impl fmt::Display for Blocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Blocker::Mounted {
+                ref device,
+                ref mountpoint,
+            } => write!(f, "{} is mounted at {}", device, mountpoint.display()),
+            Blocker::Swap { ref device } => write!(f, "{} is in use as swap", device),
+            Blocker::MdMember {
+                ref device,
+                ref array,
+            } => write!(f, "{} is a member of raid {}", device, array),
+            Blocker::LvmMember {
+                ref device,
+                ref volume,
+            } => write!(f, "{} backs logical volume {}", device, volume),
+            Blocker::ZfsMember {
+                ref device,
+                ref pool,
+            } => write!(f, "{} is a member of zfs pool {}", device, pool),
+            Blocker::BcacheMember {
+                ref device,
+                ref bcache,
+            } => write!(f, "{} is a member of {}", device, bcache),
+            Blocker::Holder {
+                ref device,
+                ref holder,
+            } => write!(f, "{} is held by {}", device, holder),
+            Blocker::ExclusivelyOpened { ref device } => {
+                write!(f, "{} is opened exclusively", device)
+            }
+        }
+    }
+}
+
+/// Join blockers into a single human readable line
+pub(crate) fn describe_blockers(blockers: &[Blocker]) -> String {
+    blockers
+        .iter()
+        .map(|b| b.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Find everything that is using `device` or one of its partitions.
+/// Paths that aren't block devices, like image files, have no blockers.
+pub fn find_blockers(device: impl AsRef<Path>) -> BlockResult<Vec<Blocker>> {
+    let graph = DeviceGraph::new()?;
+    let device = fs::canonicalize(&device).unwrap_or_else(|_| device.as_ref().to_path_buf());
+    graph_blockers(&graph, &device)
+}
+
+/// `find_blockers` against an already built `graph`
+pub(crate) fn graph_blockers(graph: &DeviceGraph, device: &Path) -> BlockResult<Vec<Blocker>> {
+    let swaps = match fs::read_to_string(SWAPS_PATH) {
+        Ok(s) => parse_swaps(&s),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    collect_blockers(graph, &swaps, device, is_opened_exclusively)
+}
+
+fn collect_blockers<F>(
+    graph: &DeviceGraph,
+    swaps: &[String],
+    device: &Path,
+    opened_exclusively: F,
+) -> BlockResult<Vec<Blocker>>
+where
+    F: Fn(&str) -> BlockResult<bool>,
+{
+    let node = match graph.node(device) {
+        Some(node) => node,
+        None => return Ok(vec![]),
+    };
+
+    let mut blockers = Vec::new();
+    let mut unexplained = Vec::new();
+    let mut nodes = vec![node];
+    nodes.extend(
+        node.partitions
+            .iter()
+            .filter_map(|partition| graph.node(partition)),
+    );
+    for n in nodes {
+        let found = blockers.len();
+        node_blockers(graph, swaps, n, &mut blockers);
+        // A mounted or stacked device is always held open exclusively so
+        // only mention it when nothing else explains it
+        if blockers.len() == found {
+            unexplained.push(n);
+        }
+    }
+    for n in unexplained {
+        // Claiming a partition claims its whole disk as well so the disk
+        // is explained by anything using its partitions
+        if n.name == node.name && !blockers.is_empty() {
+            continue;
+        }
+        if opened_exclusively(&n.name)? {
+            blockers.push(Blocker::ExclusivelyOpened {
+                device: n.name.clone(),
+            });
+        }
+    }
+    Ok(blockers)
+}
+
+/// Refuse with `BlockUtilsError::DeviceInUse` if anything is using `device`
+pub fn check_not_in_use(device: impl AsRef<Path>) -> BlockResult<()> {
+    let blockers = find_blockers(&device)?;
+    if blockers.is_empty() {
+        Ok(())
+    } else {
+        Err(BlockUtilsError::DeviceInUse {
+            device: device.as_ref().to_path_buf(),
+            blockers,
+        })
+    }
+}
+
+/// Run the in-use check unless the caller explicitly overrode it
+pub(crate) fn preflight(device: impl AsRef<Path>, preflight: Preflight) -> BlockResult<()> {
+    match preflight {
+        Preflight::Check => check_not_in_use(device),
+        Preflight::Override => Ok(()),
+    }
+}
+
+fn node_blockers(graph: &DeviceGraph, swaps: &[String], node: &DeviceNode, out: &mut Vec<Blocker>) {
+    for mountpoint in &node.mountpoints {
+        out.push(Blocker::Mounted {
+            device: node.name.clone(),
+            mountpoint: mountpoint.clone(),
+        });
+    }
+    if swaps.contains(&node.name) {
+        out.push(Blocker::Swap {
+            device: node.name.clone(),
+        });
+    }
+    if let Some(pool) = active_zfs_pool(&node.name) {
+        out.push(Blocker::ZfsMember {
+            device: node.name.clone(),
+            pool,
+        });
+    }
+    for holder in &node.holders {
+        let device = node.name.clone();
+        let blocker = match graph.node(holder) {
+            Some(h) if h.kind == NodeKind::MdRaid => Blocker::MdMember {
+                device,
+                array: holder.clone(),
+            },
+            Some(h) if h.kind == NodeKind::Lvm => Blocker::LvmMember {
+                device,
+                volume: h.dm_name.clone().unwrap_or_else(|| holder.clone()),
+            },
+            Some(h) if h.kind == NodeKind::Bcache => Blocker::BcacheMember {
+                device,
+                bcache: holder.clone(),
+            },
+            _ => Blocker::Holder {
+                device,
+                holder: holder.clone(),
+            },
+        };
+        out.push(blocker);
+    }
+}
+
+/// Device names of the active swap devices in /proc/swaps
+fn parse_swaps(swaps: &str) -> Vec<String> {
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|path| {
+            // Swap on /dev/mapper/* is listed by its symlink
+            let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+            path.file_name().map(|n| n.to_string_lossy().into_owned())
+        })
+        .collect()
+}
+
+/// The kernel refuses an O_EXCL open of a block device that is already
+/// claimed by a filesystem, raid, device-mapper or another O_EXCL opener
+fn is_opened_exclusively(name: &str) -> BlockResult<bool> {
+    let dev_path = Path::new("/dev").join(name);
+    match fs::metadata(&dev_path) {
+        Ok(meta) if meta.file_type().is_block_device() => {}
+        _ => return Ok(false),
+    }
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_EXCL.bits())
+        .open(&dev_path)
+    {
+        Ok(_) => Ok(false),
+        Err(ref e) if e.raw_os_error() == Some(nix::libc::EBUSY) => Ok(true),
+        // Not being allowed to look isn't proof the device is busy
+        Err(ref e) if e.kind() == ErrorKind::PermissionDenied => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The pool name if `name` is a zfs member of a currently imported pool
+#[cfg(target_os = "linux")]
+fn active_zfs_pool(name: &str) -> Option<String> {
+    let dev_path = Path::new("/dev").join(name);
+    match crate::get_block_dev_property(&dev_path, "ID_FS_TYPE") {
+        Ok(Some(ref fs_type)) if fs_type == "zfs_member" => {}
+        _ => return None,
+    }
+    let pool = crate::get_block_dev_property(&dev_path, "ID_FS_LABEL").ok()??;
+    if Path::new(ZFS_KSTAT_PATH).join(&pool).exists() {
+        Some(pool)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn active_zfs_pool(_name: &str) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::tests::add_device;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_swaps() {
+        let swaps = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n\
+                     /dev/sdz2                               partition\t8388604\t\t0\t\t-2\n\
+                     /swapfile                               file\t\t2097148\t\t0\t\t-3\n";
+        assert_eq!(parse_swaps(swaps), vec!["sdz2", "swapfile"]);
+    }
+
+    #[test]
+    fn test_describe_blockers() {
+        let blockers = vec![
+            Blocker::Mounted {
+                device: "sda1".to_string(),
+                mountpoint: PathBuf::from("/"),
+            },
+            Blocker::MdMember {
+                device: "sda2".to_string(),
+                array: "md0".to_string(),
+            },
+        ];
+        assert_eq!(
+            describe_blockers(&blockers),
+            "sda1 is mounted at /, sda2 is a member of raid md0"
+        );
+    }
+
+    #[test]
+    fn test_exclusive_open_explained_by_partition() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("class")).unwrap();
+        add_device(root, "vdzz", "252:0");
+        for (number, devnum) in &[("1", "252:1"), ("2", "252:2")] {
+            let partition = add_device(root, &format!("vdzz/vdzz{}", number), devnum);
+            fs::write(partition.join("partition"), format!("{}\n", number)).unwrap();
+        }
+        add_device(root, "vdzy", "252:16");
+        let mountinfo = root.join("mountinfo");
+        fs::write(
+            &mountinfo,
+            "22 1 252:1 / /srv rw,relatime shared:1 - xfs /dev/vdzz1 rw\n",
+        )
+        .unwrap();
+        let graph = DeviceGraph::from_paths(&root.join("class"), &mountinfo).unwrap();
+
+        // The kernel reports every claimed device and the disk under it as busy
+        let busy = |name: &str| Ok(name != "vdzz2");
+        assert_eq!(
+            collect_blockers(&graph, &[], Path::new("/dev/vdzz"), busy).unwrap(),
+            vec![Blocker::Mounted {
+                device: "vdzz1".to_string(),
+                mountpoint: PathBuf::from("/srv"),
+            }]
+        );
+        // Nothing explains an exclusive open of an unused disk
+        assert_eq!(
+            collect_blockers(&graph, &[], Path::new("/dev/vdzy"), busy).unwrap(),
+            vec![Blocker::ExclusivelyOpened {
+                device: "vdzy".to_string(),
+            }]
+        );
+        assert!(
+            collect_blockers(&graph, &[], Path::new("/dev/vdzy"), |_| Ok(false))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
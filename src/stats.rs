@@ -0,0 +1,293 @@
+//! Disk I/O statistics from `/proc/diskstats` and `/sys/block/<dev>/stat`.
+//!
+//! The kernel only exposes ever increasing counters.  Take two samples with
+//! `get_disk_stats` some time apart and hand them to `IoRates::between` to get
+//! IOPS, throughput, latency and utilization.
+use crate::{BlockResult, BlockUtilsError, DevNum, Device};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const DISKSTATS_PATH: &str = "/proc/diskstats";
+
+/// The kernel counts sectors in 512 byte units regardless of the device
+const SECTOR_SIZE: u64 = 512;
+
+/// Raw counters for one device.  See Documentation/admin-guide/iostats.rst
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DiskStats {
+    /// Kernel name, ie: sda
+    pub name: String,
+    pub devnum: Option<DevNum>,
+    pub reads_completed: u64,
+    pub reads_merged: u64,
+    pub sectors_read: u64,
+    /// Milliseconds spent reading
+    pub read_ticks: u64,
+    pub writes_completed: u64,
+    pub writes_merged: u64,
+    pub sectors_written: u64,
+    /// Milliseconds spent writing
+    pub write_ticks: u64,
+    pub in_flight: u64,
+    /// Milliseconds the device had I/O in flight
+    pub io_ticks: u64,
+    /// Weighted milliseconds spent doing I/O
+    pub time_in_queue: u64,
+    /// Discard counters, kernel 4.18+
+    pub discards_completed: u64,
+    pub discards_merged: u64,
+    pub sectors_discarded: u64,
+    pub discard_ticks: u64,
+    /// Flush counters, kernel 5.5+
+    pub flushes_completed: u64,
+    pub flush_ticks: u64,
+}
+
+impl DiskStats {
+    /// Parse the counters as they appear in a sysfs stat file or after the
+    /// name column of /proc/diskstats.  Missing trailing counters from older
+    /// kernels are left at 0.
+    fn from_counters(name: String, devnum: Option<DevNum>, counters: &[&str]) -> BlockResult<Self> {
+        if counters.len() < 11 {
+            return Err(BlockUtilsError::new(format!(
+                "Expected at least 11 io counters for {}, found {}",
+                name,
+                counters.len()
+            )));
+        }
+        let mut c = counters
+            .iter()
+            .map(|v| v.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()?;
+        c.resize(17, 0);
+        Ok(DiskStats {
+            name,
+            devnum,
+            reads_completed: c[0],
+            reads_merged: c[1],
+            sectors_read: c[2],
+            read_ticks: c[3],
+            writes_completed: c[4],
+            writes_merged: c[5],
+            sectors_written: c[6],
+            write_ticks: c[7],
+            in_flight: c[8],
+            io_ticks: c[9],
+            time_in_queue: c[10],
+            discards_completed: c[11],
+            discards_merged: c[12],
+            sectors_discarded: c[13],
+            discard_ticks: c[14],
+            flushes_completed: c[15],
+            flush_ticks: c[16],
+        })
+    }
+}
+
+/// Counters for every device taken at one moment
+#[derive(Clone, Debug)]
+pub struct StatsSample {
+    pub taken: Instant,
+    pub stats: HashMap<String, DiskStats>,
+}
+
+impl StatsSample {
+    /// Counters for `device` (like "sda" or "/dev/sda")
+    pub fn get(&self, device: impl AsRef<Path>) -> Option<&DiskStats> {
+        let name = device.as_ref().file_name()?.to_string_lossy();
+        self.stats.get(name.as_ref())
+    }
+
+    /// Counters for a `Device` returned by `get_device_info`
+    pub fn for_device(&self, device: &Device) -> Option<&DiskStats> {
+        self.stats.get(&device.name)
+    }
+
+    /// Rates for every device present in both samples
+    pub fn rates_since(&self, earlier: &StatsSample) -> HashMap<String, IoRates> {
+        let interval = self.taken.saturating_duration_since(earlier.taken);
+        self.stats
+            .iter()
+            .filter_map(|(name, now)| {
+                earlier
+                    .stats
+                    .get(name)
+                    .map(|before| (name.clone(), IoRates::between(before, now, interval)))
+            })
+            .collect()
+    }
+}
+
+/// Rates computed from two samples of the same device
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct IoRates {
+    pub read_iops: f64,
+    pub write_iops: f64,
+    pub discard_iops: f64,
+    pub flush_iops: f64,
+    pub read_bytes_per_sec: f64,
+    pub write_bytes_per_sec: f64,
+    pub discard_bytes_per_sec: f64,
+    /// Average milliseconds per completed read
+    pub read_latency_ms: f64,
+    /// Average milliseconds per completed write
+    pub write_latency_ms: f64,
+    pub discard_latency_ms: f64,
+    pub flush_latency_ms: f64,
+    /// Average number of requests waiting or in service
+    pub avg_queue_depth: f64,
+    /// Percentage of the interval the device was busy
+    pub utilization: f64,
+}
+
+impl IoRates {
+    /// Compute rates from two samples of the same device taken `interval` apart.
+    /// Counters that went backwards, ie: the device was removed and re-added,
+    /// count as 0.
+    pub fn between(before: &DiskStats, after: &DiskStats, interval: Duration) -> IoRates {
+        let secs = interval.as_secs_f64();
+        let ms = secs * 1000.0;
+        let delta = |a: u64, b: u64| b.saturating_sub(a) as f64;
+        let per_sec = |d: f64| if secs > 0.0 { d / secs } else { 0.0 };
+        let latency = |ticks: f64, ios: f64| if ios > 0.0 { ticks / ios } else { 0.0 };
+
+        let reads = delta(before.reads_completed, after.reads_completed);
+        let writes = delta(before.writes_completed, after.writes_completed);
+        let discards = delta(before.discards_completed, after.discards_completed);
+        let flushes = delta(before.flushes_completed, after.flushes_completed);
+        IoRates {
+            read_iops: per_sec(reads),
+            write_iops: per_sec(writes),
+            discard_iops: per_sec(discards),
+            flush_iops: per_sec(flushes),
+            read_bytes_per_sec: per_sec(
+                delta(before.sectors_read, after.sectors_read) * SECTOR_SIZE as f64,
+            ),
+            write_bytes_per_sec: per_sec(
+                delta(before.sectors_written, after.sectors_written) * SECTOR_SIZE as f64,
+            ),
+            discard_bytes_per_sec: per_sec(
+                delta(before.sectors_discarded, after.sectors_discarded) * SECTOR_SIZE as f64,
+            ),
+            read_latency_ms: latency(delta(before.read_ticks, after.read_ticks), reads),
+            write_latency_ms: latency(delta(before.write_ticks, after.write_ticks), writes),
+            discard_latency_ms: latency(delta(before.discard_ticks, after.discard_ticks), discards),
+            flush_latency_ms: latency(delta(before.flush_ticks, after.flush_ticks), flushes),
+            avg_queue_depth: if ms > 0.0 {
+                delta(before.time_in_queue, after.time_in_queue) / ms
+            } else {
+                0.0
+            },
+            utilization: if ms > 0.0 {
+                (delta(before.io_ticks, after.io_ticks) / ms * 100.0).min(100.0)
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Parse the contents of /proc/diskstats
+pub fn parse_diskstats(input: &str) -> BlockResult<Vec<DiskStats>> {
+    input
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < 14 {
+                return Err(BlockUtilsError::new(format!(
+                    "Invalid diskstats line: {}",
+                    line
+                )));
+            }
+            let devnum = DevNum {
+                major: parts[0].parse::<u64>()?,
+                minor: parts[1].parse::<u64>()?,
+            };
+            DiskStats::from_counters(parts[2].to_string(), Some(devnum), &parts[3..])
+        })
+        .collect()
+}
+
+/// Take a sample of every device in /proc/diskstats
+pub fn get_disk_stats() -> BlockResult<StatsSample> {
+    let input = fs::read_to_string(DISKSTATS_PATH)?;
+    let taken = Instant::now();
+    Ok(StatsSample {
+        taken,
+        stats: parse_diskstats(&input)?
+            .into_iter()
+            .map(|s| (s.name.clone(), s))
+            .collect(),
+    })
+}
+
+/// Read the counters of a single device from /sys/class/block/<dev>/stat
+pub fn get_device_stats(device: impl AsRef<Path>) -> BlockResult<DiskStats> {
+    let sys_path = crate::dev_path_to_sys_path(&device)?;
+    let input = fs::read_to_string(sys_path.join("stat"))?;
+    let devnum = fs::read_to_string(sys_path.join("dev"))
+        .ok()
+        .and_then(|d| d.parse::<DevNum>().ok());
+    let name = sys_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let counters: Vec<&str> = input.split_whitespace().collect();
+    DiskStats::from_counters(name, devnum, &counters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISKSTATS: &str =
+        "   8       0 sda 1000 10 80000 2000 500 5 40000 3000 2 2500 5000 10 0 800 20 50 100
+   8       1 sda1 900 10 72000 1800 500 5 40000 3000 0 2400 4800
+ 253       0 dm-0 10 0 80 5 0 0 0 0 0 5 5 0 0 0 0 0 0
+";
+
+    #[test]
+    fn test_parse_diskstats() {
+        let stats = parse_diskstats(DISKSTATS).unwrap();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[0].name, "sda");
+        assert_eq!(stats[0].devnum, Some(DevNum { major: 8, minor: 0 }));
+        assert_eq!(stats[0].sectors_written, 40000);
+        assert_eq!(stats[0].flushes_completed, 50);
+        // Older kernels without discard and flush counters
+        assert_eq!(stats[1].discards_completed, 0);
+        assert!(parse_diskstats("8 0 sda 1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_io_rates() {
+        let before = parse_diskstats(DISKSTATS).unwrap().remove(0);
+        let mut after = before.clone();
+        after.reads_completed += 200;
+        after.sectors_read += 4096;
+        after.read_ticks += 400;
+        after.writes_completed += 100;
+        after.write_ticks += 1000;
+        after.io_ticks += 1500;
+        after.time_in_queue += 4000;
+
+        let rates = IoRates::between(&before, &after, Duration::from_secs(2));
+        assert_eq!(rates.read_iops, 100.0);
+        assert_eq!(rates.write_iops, 50.0);
+        assert_eq!(rates.read_bytes_per_sec, 1_048_576.0);
+        assert_eq!(rates.read_latency_ms, 2.0);
+        assert_eq!(rates.write_latency_ms, 10.0);
+        assert_eq!(rates.avg_queue_depth, 2.0);
+        assert_eq!(rates.utilization, 75.0);
+
+        // A counter reset doesn't produce huge numbers
+        let reset = IoRates::between(&after, &before, Duration::from_secs(2));
+        assert_eq!(reset.read_iops, 0.0);
+    }
+}
@@ -0,0 +1,429 @@
+//! Persistent queue tuning through udev rules.
+//!
+//! Settings written straight to sysfs are lost on reboot.  Instead of keying
+//! on kernel names like `sda`, which can change between boots, the rules
+//! written here match the disk by WWN, serial number or model and set
+//! `queue/*` attributes whenever udev sees the disk.  The rules file is owned
+//! entirely by this module so it can be parsed back, diffed and rewritten
+//! idempotently.
+use crate::queue::{self, QueueSettings};
+use crate::{run_command, BlockResult, BlockUtilsError, Device, WwnSource};
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_RULES_PATH: &str = "/etc/udev/rules.d/60-block-utils-queue.rules";
+
+const HEADER: &str = "# Managed by block-utils.  Manual changes will be overwritten.";
+
+/// How a rule finds its disk
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceMatch {
+    /// Matches ID_WWN_WITH_EXTENSION, the value `Device::wwn` holds for scsi and ata disks
+    Wwn(String),
+    /// Matches ID_WWN, for disks that don't have the extended form
+    WwnWithoutExtension(String),
+    /// Matches the wwid sysfs attribute, ie: for nvme namespaces
+    Wwid(String),
+    /// Matches ID_SERIAL, the value `Device::serial_number` holds
+    Serial(String),
+    /// Matches ID_MODEL.  This applies to every disk of that model
+    Model(String),
+}
+
+impl DeviceMatch {
+    fn udev_key(&self) -> &'static str {
+        match *self {
+            DeviceMatch::Wwn(_) => "ENV{ID_WWN_WITH_EXTENSION}",
+            DeviceMatch::WwnWithoutExtension(_) => "ENV{ID_WWN}",
+            DeviceMatch::Wwid(_) => "ATTR{wwid}",
+            DeviceMatch::Serial(_) => "ENV{ID_SERIAL}",
+            DeviceMatch::Model(_) => "ENV{ID_MODEL}",
+        }
+    }
+
+    fn value(&self) -> &str {
+        match *self {
+            DeviceMatch::Wwn(ref v)
+            | DeviceMatch::WwnWithoutExtension(ref v)
+            | DeviceMatch::Wwid(ref v)
+            | DeviceMatch::Serial(ref v)
+            | DeviceMatch::Model(ref v) => v,
+        }
+    }
+
+    fn from_udev_key(key: &str, value: String) -> Option<Self> {
+        match key {
+            "ENV{ID_WWN_WITH_EXTENSION}" => Some(DeviceMatch::Wwn(value)),
+            "ENV{ID_WWN}" => Some(DeviceMatch::WwnWithoutExtension(value)),
+            "ATTR{wwid}" => Some(DeviceMatch::Wwid(value)),
+            "ENV{ID_SERIAL}" => Some(DeviceMatch::Serial(value)),
+            "ENV{ID_MODEL}" => Some(DeviceMatch::Model(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Queue attributes to set on one disk
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QueueRule {
+    pub device_match: DeviceMatch,
+    /// Attribute names relative to the queue directory and their values,
+    /// ie: ("scheduler", "bfq")
+    pub attributes: Vec<(String, String)>,
+}
+
+impl QueueRule {
+    pub fn new(device_match: DeviceMatch, settings: &QueueSettings) -> Self {
+        QueueRule {
+            device_match,
+            attributes: settings
+                .attributes()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        }
+    }
+
+    /// Build a rule for `device` matching on its WWN, using the same udev
+    /// key the WWN was read from, or its serial number if it doesn't have one.
+    pub fn for_device(device: &Device, settings: &QueueSettings) -> BlockResult<Self> {
+        let device_match = match (&device.wwn, device.wwn_source, &device.serial_number) {
+            (Some(wwn), Some(WwnSource::WwnWithExtension), _) => DeviceMatch::Wwn(wwn.clone()),
+            (Some(wwn), Some(WwnSource::Wwn), _) => DeviceMatch::WwnWithoutExtension(wwn.clone()),
+            (Some(wwn), Some(WwnSource::Wwid), _) => DeviceMatch::Wwid(wwn.clone()),
+            // Without a source there is no telling which key would match
+            (_, _, Some(serial)) => DeviceMatch::Serial(serial.clone()),
+            (_, _, None) => {
+                return Err(BlockUtilsError::new(format!(
+                    "{} has no WWN or serial number to match a udev rule on",
+                    device.name
+                )))
+            }
+        };
+        Ok(QueueRule::new(device_match, settings))
+    }
+
+    /// Set any queue attribute, including ones `QueueSettings` doesn't cover
+    pub fn with_attribute(mut self, name: &str, value: &str) -> Self {
+        match self.attributes.iter_mut().find(|(n, _)| n == name) {
+            Some(attr) => attr.1 = value.to_string(),
+            None => self.attributes.push((name.to_string(), value.to_string())),
+        }
+        self
+    }
+
+    /// Write the attributes to sysfs now instead of waiting for udev
+    pub fn apply_now(&self, device: impl AsRef<Path>) -> BlockResult<()> {
+        self.validate()?;
+        let queue = queue::queue_dir(device)?;
+        for (name, value) in &self.attributes {
+            fs::write(queue.join(name), value)?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> BlockResult<()> {
+        let value_ok = |v: &str| !v.is_empty() && !v.contains(['"', '\n']);
+        if !value_ok(self.device_match.value()) {
+            return Err(BlockUtilsError::new(format!(
+                "Invalid udev match value {:?}",
+                self.device_match.value()
+            )));
+        }
+        for (name, value) in &self.attributes {
+            let name_ok = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '/')
+                && !name.contains("..");
+            if !name_ok || !value_ok(value) {
+                return Err(BlockUtilsError::new(format!(
+                    "Invalid queue attribute {}={:?}",
+                    name, value
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Render as a single udev rule line
+    pub fn to_rule_line(&self) -> BlockResult<String> {
+        self.validate()?;
+        let mut line = format!(
+            "ACTION==\"add|change\", SUBSYSTEM==\"block\", ENV{{DEVTYPE}}==\"disk\", {}==\"{}\"",
+            self.device_match.udev_key(),
+            self.device_match.value()
+        );
+        for (name, value) in &self.attributes {
+            line.push_str(&format!(", ATTR{{queue/{}}}=\"{}\"", name, value));
+        }
+        Ok(line)
+    }
+
+    /// Parse a line written by `to_rule_line`.  Anything else is ignored.
+    fn from_rule_line(line: &str) -> Option<Self> {
+        let mut device_match = None;
+        let mut attributes = Vec::new();
+        for part in line.split(", ") {
+            if let Some((key, value)) = split_assignment(part, "==\"") {
+                if let Some(m) = DeviceMatch::from_udev_key(key, value) {
+                    device_match = Some(m);
+                }
+            } else if let Some(rest) = part.strip_prefix("ATTR{queue/") {
+                let (name, value) = split_assignment(rest, "}=\"")?;
+                attributes.push((name.to_string(), value));
+            }
+        }
+        Some(QueueRule {
+            device_match: device_match?,
+            attributes,
+        })
+    }
+}
+
+fn split_assignment<'a>(s: &'a str, separator: &str) -> Option<(&'a str, String)> {
+    let pos = s.find(separator)?;
+    let value = s[pos + separator.len()..].strip_suffix('"')?;
+    Some((&s[..pos], value.to_string()))
+}
+
+/// A line that differs between the rules on disk and the pending rules
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RuleChange {
+    Added(String),
+    Removed(String),
+}
+
+/// The managed rules file
+#[derive(Clone, Debug)]
+pub struct QueueRules {
+    path: PathBuf,
+    rules: Vec<QueueRule>,
+}
+
+impl QueueRules {
+    /// Load the rules at `DEFAULT_RULES_PATH`
+    pub fn load() -> BlockResult<Self> {
+        Self::load_from(DEFAULT_RULES_PATH)
+    }
+
+    /// Load the rules at `path`.  A missing file is an empty set of rules.
+    pub fn load_from(path: impl AsRef<Path>) -> BlockResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let rules = if path.exists() {
+            fs::read_to_string(&path)?
+                .lines()
+                .filter(|l| !l.trim_start().starts_with('#'))
+                .filter_map(QueueRule::from_rule_line)
+                .collect()
+        } else {
+            vec![]
+        };
+        Ok(QueueRules { path, rules })
+    }
+
+    pub fn rules(&self) -> &[QueueRule] {
+        &self.rules
+    }
+
+    /// Add `rule`, replacing any rule with the same match
+    pub fn set(&mut self, rule: QueueRule) {
+        match self
+            .rules
+            .iter_mut()
+            .find(|r| r.device_match == rule.device_match)
+        {
+            Some(existing) => *existing = rule,
+            None => self.rules.push(rule),
+        }
+    }
+
+    /// Remove the rule for `device_match`.  Returns true if there was one.
+    pub fn remove(&mut self, device_match: &DeviceMatch) -> bool {
+        let before = self.rules.len();
+        self.rules.retain(|r| r.device_match != *device_match);
+        before != self.rules.len()
+    }
+
+    /// The file contents these rules produce
+    pub fn render(&self) -> BlockResult<String> {
+        let mut out = format!("{}\n", HEADER);
+        for rule in &self.rules {
+            out.push_str(&rule.to_rule_line()?);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+
+    /// Lines that `save` would add or remove
+    pub fn diff(&self) -> BlockResult<Vec<RuleChange>> {
+        let current = if self.path.exists() {
+            fs::read_to_string(&self.path)?
+        } else {
+            String::new()
+        };
+        let pending = if self.rules.is_empty() {
+            String::new()
+        } else {
+            self.render()?
+        };
+        let mut changes: Vec<RuleChange> = current
+            .lines()
+            .filter(|l| !pending.lines().any(|p| p == *l))
+            .map(|l| RuleChange::Removed(l.to_string()))
+            .collect();
+        changes.extend(
+            pending
+                .lines()
+                .filter(|p| !current.lines().any(|l| l == *p))
+                .map(|p| RuleChange::Added(p.to_string())),
+        );
+        Ok(changes)
+    }
+
+    /// Write the rules file if it changed.  With no rules left the file is
+    /// removed.  Returns true if anything on disk changed.
+    pub fn save(&self) -> BlockResult<bool> {
+        if self.diff()?.is_empty() {
+            return Ok(false);
+        }
+        if self.rules.is_empty() {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::write(&self.path, self.render()?)?;
+        }
+        Ok(true)
+    }
+
+    /// Save the rules and, if they changed, have udev reload them and
+    /// re-run them against the block devices already present
+    pub fn apply(&self) -> BlockResult<bool> {
+        if !self.save()? {
+            return Ok(false);
+        }
+        crate::process_output(&run_command("udevadm", &["control", "--reload-rules"])?)?;
+        crate::process_output(&run_command(
+            "udevadm",
+            &["trigger", "--subsystem-match=block", "--action=change"],
+        )?)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::IoScheduler;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_queue_rules() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("60-block-utils-queue.rules");
+        let settings = QueueSettings {
+            scheduler: Some(IoScheduler::Bfq),
+            read_ahead_kb: Some(4096),
+            ..Default::default()
+        };
+        let rule = QueueRule::new(DeviceMatch::Wwn("0x5000c500a1b2c3d4".into()), &settings)
+            .with_attribute("iosched/slice_idle", "0");
+        assert_eq!(
+            rule.to_rule_line().unwrap(),
+            "ACTION==\"add|change\", SUBSYSTEM==\"block\", ENV{DEVTYPE}==\"disk\", \
+             ENV{ID_WWN_WITH_EXTENSION}==\"0x5000c500a1b2c3d4\", \
+             ATTR{queue/scheduler}=\"bfq\", ATTR{queue/read_ahead_kb}=\"4096\", \
+             ATTR{queue/iosched/slice_idle}=\"0\""
+        );
+
+        let mut rules = QueueRules::load_from(&path).unwrap();
+        rules.set(rule.clone());
+        rules.set(QueueRule::new(
+            DeviceMatch::Serial("ZC11ABCD".into()),
+            &settings,
+        ));
+        assert_eq!(rules.diff().unwrap().len(), 3);
+        assert!(rules.save().unwrap());
+        // Saving again is a no-op
+        assert!(!rules.save().unwrap());
+
+        let mut loaded = QueueRules::load_from(&path).unwrap();
+        assert_eq!(loaded.rules(), rules.rules());
+        assert!(loaded.remove(&DeviceMatch::Serial("ZC11ABCD".into())));
+        match loaded.diff().unwrap().as_slice() {
+            [RuleChange::Removed(line)] => assert!(line.contains("ZC11ABCD")),
+            other => panic!("unexpected diff {:?}", other),
+        }
+        assert!(loaded.remove(&rule.device_match));
+        assert!(loaded.save().unwrap());
+        assert!(!path.exists());
+
+        let bad = QueueRule::new(DeviceMatch::Model("x\"y".into()), &settings);
+        assert!(bad.to_rule_line().is_err());
+    }
+
+    #[test]
+    fn test_rule_for_device_matches_wwn_source() {
+        use crate::{DeviceType, FilesystemType, MediaType, Transport, ZonedModel};
+
+        let mut device = Device {
+            id: None,
+            name: "nvme0n1".to_string(),
+            media_type: MediaType::NVME,
+            device_type: DeviceType::Disk,
+            capacity: 1_000_204_886_016,
+            fs_type: FilesystemType::Unknown,
+            label: None,
+            serial_number: Some("S4EWNX0R123456".to_string()),
+            logical_block_size: Some(512),
+            physical_block_size: Some(512),
+            model: Some("Samsung SSD 970 EVO Plus 1TB".to_string()),
+            vendor: None,
+            wwn: Some("eui.0025388b91b12345".to_string()),
+            wwn_source: Some(WwnSource::Wwid),
+            firmware_revision: None,
+            transport: Transport::Nvme,
+            rotation_rate_rpm: None,
+            removable: false,
+            read_only: false,
+            hidden: false,
+            zoned: ZonedModel::None,
+            partition_number: None,
+            partition_label: None,
+            devnum: None,
+            links: vec![],
+        };
+        let settings = QueueSettings {
+            read_ahead_kb: Some(128),
+            ..Default::default()
+        };
+        let rule = QueueRule::for_device(&device, &settings).unwrap();
+        assert_eq!(
+            rule.device_match,
+            DeviceMatch::Wwid("eui.0025388b91b12345".into())
+        );
+        let line = rule.to_rule_line().unwrap();
+        assert!(line.contains("ATTR{wwid}==\"eui.0025388b91b12345\""));
+        assert_eq!(QueueRule::from_rule_line(&line), Some(rule));
+
+        device.wwn = Some("0x5002538e40a1b2c3".to_string());
+        device.wwn_source = Some(WwnSource::Wwn);
+        assert!(QueueRule::for_device(&device, &settings)
+            .unwrap()
+            .to_rule_line()
+            .unwrap()
+            .contains("ENV{ID_WWN}==\"0x5002538e40a1b2c3\""));
+
+        // A WWN of unknown origin falls back to the serial number
+        device.wwn_source = None;
+        assert_eq!(
+            QueueRule::for_device(&device, &settings)
+                .unwrap()
+                .device_match,
+            DeviceMatch::Serial("S4EWNX0R123456".into())
+        );
+    }
+}
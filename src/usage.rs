@@ -0,0 +1,496 @@
+//! How full mounted filesystems are.
+//!
+//! `statvfs` gives the numbers `df` shows.  Some filesystems hide part of the
+//! picture from it so a few extra details are collected where possible:
+//! btrfs allocates space in chunks per profile, xfs keeps reserved blocks
+//! for itself and ext2/3/4 reserve a percentage of blocks for root.
+use crate::mountinfo::{MountInfo, MountTable};
+use crate::{BlockResult, BlockUtilsError, Device};
+
+use log::debug;
+use nix::sys::statvfs::{statvfs, FsFlags, Statvfs};
+use nix::{ioctl_read, ioctl_readwrite};
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Flags of the mount as reported by statvfs
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MountFlags {
+    pub read_only: bool,
+    pub nosuid: bool,
+    pub nodev: bool,
+    pub noexec: bool,
+    pub synchronous: bool,
+    pub mandatory_locking: bool,
+    pub noatime: bool,
+    pub nodiratime: bool,
+    pub relatime: bool,
+}
+
+impl From<FsFlags> for MountFlags {
+    fn from(flags: FsFlags) -> Self {
+        MountFlags {
+            read_only: flags.contains(FsFlags::ST_RDONLY),
+            nosuid: flags.contains(FsFlags::ST_NOSUID),
+            nodev: flags.contains(FsFlags::ST_NODEV),
+            noexec: flags.contains(FsFlags::ST_NOEXEC),
+            synchronous: flags.contains(FsFlags::ST_SYNCHRONOUS),
+            mandatory_locking: flags.contains(FsFlags::ST_MANDLOCK),
+            noatime: flags.contains(FsFlags::ST_NOATIME),
+            nodiratime: flags.contains(FsFlags::ST_NODIRATIME),
+            relatime: flags.contains(FsFlags::ST_RELATIME),
+        }
+    }
+}
+
+/// Space and inode usage of a mounted filesystem
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FsUsage {
+    pub mount_point: PathBuf,
+    /// Source of the mount, ie: /dev/sda1 or tank/home
+    pub source: String,
+    pub fs_type: String,
+    /// Preferred I/O size
+    pub block_size: u64,
+    /// Unit the block counts are in
+    pub fragment_size: u64,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    /// Free bytes including the ones reserved for root
+    pub free_bytes: u64,
+    /// Free bytes unprivileged users can use
+    pub available_bytes: u64,
+    pub total_inodes: u64,
+    pub used_inodes: u64,
+    pub free_inodes: u64,
+    pub available_inodes: u64,
+    pub flags: MountFlags,
+    /// Filesystem specific details.  None for other filesystems or when they
+    /// couldn't be read, ie: without CAP_SYS_ADMIN
+    pub details: Option<FsDetails>,
+}
+
+impl FsUsage {
+    // The statvfs fields are only 32 bits wide on some targets
+    #[allow(clippy::unnecessary_cast)]
+    fn from_statvfs(mount: &MountInfo, stat: &Statvfs) -> Self {
+        let frsize = stat.fragment_size() as u64;
+        let total_bytes = stat.blocks() as u64 * frsize;
+        let free_bytes = stat.blocks_free() as u64 * frsize;
+        let total_inodes = stat.files() as u64;
+        let free_inodes = stat.files_free() as u64;
+        FsUsage {
+            mount_point: mount.mount_point.clone(),
+            source: mount.source.clone(),
+            fs_type: mount.fs_type.clone(),
+            block_size: stat.block_size() as u64,
+            fragment_size: frsize,
+            total_bytes,
+            used_bytes: total_bytes.saturating_sub(free_bytes),
+            free_bytes,
+            available_bytes: stat.blocks_available() as u64 * frsize,
+            total_inodes,
+            used_inodes: total_inodes.saturating_sub(free_inodes),
+            free_inodes,
+            available_inodes: stat.files_available() as u64,
+            flags: stat.flags().into(),
+            details: None,
+        }
+    }
+
+    /// Percentage of the space usable by unprivileged users that is used, the
+    /// same way df calculates Use%
+    pub fn used_percent(&self) -> f64 {
+        let usable = self.used_bytes + self.available_bytes;
+        if usable == 0 {
+            return 0.0;
+        }
+        self.used_bytes as f64 * 100.0 / usable as f64
+    }
+
+    /// Percentage of inodes used.  0 for filesystems without a fixed inode
+    /// count, like btrfs
+    pub fn inodes_used_percent(&self) -> f64 {
+        if self.total_inodes == 0 {
+            return 0.0;
+        }
+        self.used_inodes as f64 * 100.0 / self.total_inodes as f64
+    }
+}
+
+/// Details statvfs doesn't show
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsDetails {
+    Btrfs(Vec<BtrfsSpace>),
+    Xfs(XfsReserved),
+    Ext(ExtReserved),
+}
+
+/// Which kind of data a btrfs block group holds
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BtrfsBlockGroup {
+    Data,
+    Metadata,
+    System,
+    /// Data and metadata share block groups, the default on small filesystems
+    Mixed,
+    /// Metadata space set aside for emergencies
+    GlobalReserve,
+}
+
+/// How a btrfs block group is replicated across devices
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BtrfsProfile {
+    Single,
+    Dup,
+    Raid0,
+    Raid1,
+    Raid1c3,
+    Raid1c4,
+    Raid10,
+    Raid5,
+    Raid6,
+}
+
+/// Allocation of one btrfs block group type and profile, like the lines of
+/// `btrfs filesystem df`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BtrfsSpace {
+    pub group: BtrfsBlockGroup,
+    pub profile: BtrfsProfile,
+    /// Bytes allocated to chunks of this type
+    pub total_bytes: u64,
+    /// Bytes of those chunks in use
+    pub used_bytes: u64,
+}
+
+const BTRFS_BLOCK_GROUP_DATA: u64 = 1 << 0;
+const BTRFS_BLOCK_GROUP_SYSTEM: u64 = 1 << 1;
+const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+const BTRFS_SPACE_INFO_GLOBAL_RSV: u64 = 1 << 49;
+const BTRFS_PROFILES: &[(u64, BtrfsProfile)] = &[
+    (1 << 3, BtrfsProfile::Raid0),
+    (1 << 4, BtrfsProfile::Raid1),
+    (1 << 5, BtrfsProfile::Dup),
+    (1 << 6, BtrfsProfile::Raid10),
+    (1 << 7, BtrfsProfile::Raid5),
+    (1 << 8, BtrfsProfile::Raid6),
+    (1 << 9, BtrfsProfile::Raid1c3),
+    (1 << 10, BtrfsProfile::Raid1c4),
+];
+
+impl BtrfsSpace {
+    /// Decode the block group flags of a btrfs_ioctl_space_info
+    fn from_flags(flags: u64, total_bytes: u64, used_bytes: u64) -> Self {
+        let data = flags & BTRFS_BLOCK_GROUP_DATA != 0;
+        let metadata = flags & BTRFS_BLOCK_GROUP_METADATA != 0;
+        let group = if flags & BTRFS_SPACE_INFO_GLOBAL_RSV != 0 {
+            BtrfsBlockGroup::GlobalReserve
+        } else if data && metadata {
+            BtrfsBlockGroup::Mixed
+        } else if data {
+            BtrfsBlockGroup::Data
+        } else if flags & BTRFS_BLOCK_GROUP_SYSTEM != 0 {
+            BtrfsBlockGroup::System
+        } else {
+            BtrfsBlockGroup::Metadata
+        };
+        let profile = BTRFS_PROFILES
+            .iter()
+            .find(|(bit, _)| flags & bit != 0)
+            .map(|(_, profile)| *profile)
+            .unwrap_or(BtrfsProfile::Single);
+        BtrfsSpace {
+            group,
+            profile,
+            total_bytes,
+            used_bytes,
+        }
+    }
+}
+
+/// Blocks xfs keeps back so it can't run completely out of space
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct XfsReserved {
+    /// Reserved filesystem blocks
+    pub reserved_blocks: u64,
+    /// Reserved blocks that are still unused
+    pub reserved_available: u64,
+    pub reserved_bytes: u64,
+}
+
+/// Blocks of an ext2/3/4 filesystem only root can use
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExtReserved {
+    pub block_count: u64,
+    pub reserved_blocks: u64,
+    pub block_size: u64,
+    /// Percentage of blocks reserved, what `mkfs.ext4 -m` and `tune2fs -m` set
+    pub reserved_percent: f64,
+}
+
+#[repr(C)]
+struct BtrfsIoctlSpaceArgs {
+    space_slots: u64,
+    total_spaces: u64,
+}
+
+#[repr(C)]
+struct BtrfsIoctlSpaceInfo {
+    flags: u64,
+    total_bytes: u64,
+    used_bytes: u64,
+}
+
+#[repr(C)]
+struct XfsFsopResblks {
+    resblks: u64,
+    resblks_avail: u64,
+}
+
+ioctl_readwrite!(btrfs_ioc_space_info, 0x94, 20, BtrfsIoctlSpaceArgs);
+ioctl_read!(xfs_ioc_get_resblks, b'X', 115, XfsFsopResblks);
+
+/// Per profile allocation of the btrfs filesystem mounted at `mount_point`
+pub fn btrfs_space_info(mount_point: impl AsRef<Path>) -> BlockResult<Vec<BtrfsSpace>> {
+    let dir = File::open(mount_point.as_ref())?;
+    // The first call with no slots only returns how many there are
+    let mut args = BtrfsIoctlSpaceArgs {
+        space_slots: 0,
+        total_spaces: 0,
+    };
+    unsafe { btrfs_ioc_space_info(dir.as_raw_fd(), &mut args) }?;
+    let slots = args.total_spaces as usize;
+    let info_words = std::mem::size_of::<BtrfsIoctlSpaceInfo>() / 8;
+    let mut buf = vec![0u64; 2 + slots * info_words];
+    buf[0] = slots as u64;
+    unsafe {
+        btrfs_ioc_space_info(
+            dir.as_raw_fd(),
+            buf.as_mut_ptr() as *mut BtrfsIoctlSpaceArgs,
+        )
+    }?;
+    let filled = (buf[1] as usize).min(slots);
+    Ok(buf[2..2 + filled * info_words]
+        .chunks_exact(info_words)
+        .map(|info| BtrfsSpace::from_flags(info[0], info[1], info[2]))
+        .collect())
+}
+
+/// Reserved blocks of the xfs filesystem mounted at `mount_point`.  Needs
+/// CAP_SYS_ADMIN
+pub fn xfs_reserved(mount_point: impl AsRef<Path>, block_size: u64) -> BlockResult<XfsReserved> {
+    let dir = File::open(mount_point.as_ref())?;
+    let mut resblks = XfsFsopResblks {
+        resblks: 0,
+        resblks_avail: 0,
+    };
+    unsafe { xfs_ioc_get_resblks(dir.as_raw_fd(), &mut resblks) }?;
+    Ok(XfsReserved {
+        reserved_blocks: resblks.resblks,
+        reserved_available: resblks.resblks_avail,
+        reserved_bytes: resblks.resblks * block_size,
+    })
+}
+
+/// Read the reserved block count from the superblock of the ext2/3/4
+/// filesystem on `device`
+pub fn ext_reserved(device: impl AsRef<Path>) -> BlockResult<ExtReserved> {
+    let mut f = File::open(device.as_ref())?;
+    let mut superblock = [0u8; 1024];
+    f.seek(SeekFrom::Start(1024))?;
+    f.read_exact(&mut superblock)?;
+    parse_ext_superblock(&superblock).ok_or_else(|| {
+        BlockUtilsError::new(format!(
+            "{} doesn't have an ext superblock",
+            device.as_ref().display()
+        ))
+    })
+}
+
+const EXT_SUPER_MAGIC: u16 = 0xEF53;
+const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+
+/// Parse the block counts out of the 1024 byte ext superblock
+fn parse_ext_superblock(sb: &[u8]) -> Option<ExtReserved> {
+    let le32 = |off: usize| -> Option<u64> {
+        Some(u32::from_le_bytes(sb.get(off..off + 4)?.try_into().ok()?) as u64)
+    };
+    let magic = u16::from_le_bytes(sb.get(0x38..0x3A)?.try_into().ok()?);
+    if magic != EXT_SUPER_MAGIC {
+        return None;
+    }
+    // Blocks are 1KiB << s_log_block_size, at most 64KiB
+    let log_block_size = le32(0x18)?;
+    if log_block_size > 6 {
+        return None;
+    }
+    let block_size = 1024u64.checked_shl(log_block_size as u32)?;
+    let mut block_count = le32(0x04)?;
+    let mut reserved_blocks = le32(0x08)?;
+    if le32(0x60)? as u32 & EXT4_FEATURE_INCOMPAT_64BIT != 0 {
+        block_count |= le32(0x150)? << 32;
+        reserved_blocks |= le32(0x154)? << 32;
+    }
+    let reserved_percent = if block_count == 0 {
+        0.0
+    } else {
+        reserved_blocks as f64 * 100.0 / block_count as f64
+    };
+    Some(ExtReserved {
+        block_count,
+        reserved_blocks,
+        block_size,
+        reserved_percent,
+    })
+}
+
+/// Filesystem specific details of `usage`.  Failures are only logged since
+/// most of them need privileges the statvfs numbers don't
+fn fs_details(usage: &FsUsage) -> Option<FsDetails> {
+    let details = match usage.fs_type.as_str() {
+        "btrfs" => btrfs_space_info(&usage.mount_point).map(FsDetails::Btrfs),
+        "xfs" => xfs_reserved(&usage.mount_point, usage.fragment_size).map(FsDetails::Xfs),
+        "ext2" | "ext3" | "ext4" => ext_reserved(&usage.source).map(FsDetails::Ext),
+        _ => return None,
+    };
+    match details {
+        Ok(details) => Some(details),
+        Err(e) => {
+            debug!(
+                "Unable to read {} details of {}: {}",
+                usage.fs_type,
+                usage.mount_point.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn usage_of(mount: &MountInfo, path: &Path) -> BlockResult<FsUsage> {
+    let stat = statvfs(path)?;
+    let mut usage = FsUsage::from_statvfs(mount, &stat);
+    usage.details = fs_details(&usage);
+    Ok(usage)
+}
+
+/// Usage of the filesystem `path` is on.  `path` doesn't need to be the
+/// mount point itself
+pub fn get_fs_usage(path: impl AsRef<Path>) -> BlockResult<FsUsage> {
+    let path = fs::canonicalize(path.as_ref())?;
+    let table = MountTable::read()?;
+    let mount = table.containing(&path).ok_or_else(|| {
+        BlockUtilsError::new(format!("Unable to find the mount of {}", path.display()))
+    })?;
+    usage_of(mount, &path)
+}
+
+/// Usage of the filesystem on `device`.  None if it isn't mounted
+pub fn get_device_fs_usage(device: &Device) -> BlockResult<Option<FsUsage>> {
+    let table = MountTable::read()?;
+    match table
+        .by_device(Path::new("/dev").join(&device.name))
+        .first()
+    {
+        Some(mount) => Ok(Some(usage_of(mount, &mount.mount_point)?)),
+        None => Ok(None),
+    }
+}
+
+/// Usage of every mounted block device filesystem keyed by mount point
+#[cfg(target_os = "linux")]
+pub fn get_all_fs_usage() -> BlockResult<HashMap<PathBuf, FsUsage>> {
+    let mut usages = HashMap::new();
+    for mounted in MountTable::read()?.mounted_devices()? {
+        let mount = mounted.mount;
+        if usages.contains_key(&mount.mount_point) {
+            continue;
+        }
+        match usage_of(&mount, &mount.mount_point) {
+            Ok(usage) => {
+                usages.insert(mount.mount_point.clone(), usage);
+            }
+            // Mounts can go away while we're looking at them
+            Err(e) => debug!("statvfs of {} failed: {}", mount.mount_point.display(), e),
+        }
+    }
+    Ok(usages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_btrfs_flags() {
+        let data = BtrfsSpace::from_flags(BTRFS_BLOCK_GROUP_DATA, 10, 5);
+        assert_eq!(data.group, BtrfsBlockGroup::Data);
+        assert_eq!(data.profile, BtrfsProfile::Single);
+        let meta = BtrfsSpace::from_flags(BTRFS_BLOCK_GROUP_METADATA | 1 << 5, 10, 5);
+        assert_eq!(meta.group, BtrfsBlockGroup::Metadata);
+        assert_eq!(meta.profile, BtrfsProfile::Dup);
+        let system = BtrfsSpace::from_flags(BTRFS_BLOCK_GROUP_SYSTEM | 1 << 9, 10, 5);
+        assert_eq!(system.group, BtrfsBlockGroup::System);
+        assert_eq!(system.profile, BtrfsProfile::Raid1c3);
+        let mixed =
+            BtrfsSpace::from_flags(BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA, 10, 5);
+        assert_eq!(mixed.group, BtrfsBlockGroup::Mixed);
+        let rsv = BtrfsSpace::from_flags(
+            BTRFS_SPACE_INFO_GLOBAL_RSV | BTRFS_BLOCK_GROUP_METADATA,
+            10,
+            0,
+        );
+        assert_eq!(rsv.group, BtrfsBlockGroup::GlobalReserve);
+    }
+
+    #[test]
+    fn test_ext_superblock() {
+        let mut sb = [0u8; 1024];
+        sb[0x04..0x08].copy_from_slice(&1000u32.to_le_bytes());
+        sb[0x08..0x0C].copy_from_slice(&50u32.to_le_bytes());
+        sb[0x18..0x1C].copy_from_slice(&2u32.to_le_bytes());
+        sb[0x38..0x3A].copy_from_slice(&EXT_SUPER_MAGIC.to_le_bytes());
+        let reserved = parse_ext_superblock(&sb).unwrap();
+        assert_eq!(reserved.block_count, 1000);
+        assert_eq!(reserved.reserved_blocks, 50);
+        assert_eq!(reserved.block_size, 4096);
+        assert!((reserved.reserved_percent - 5.0).abs() < f64::EPSILON);
+
+        // The high words only count with the 64bit feature
+        sb[0x150..0x154].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(parse_ext_superblock(&sb).unwrap().block_count, 1000);
+        sb[0x60..0x64].copy_from_slice(&EXT4_FEATURE_INCOMPAT_64BIT.to_le_bytes());
+        assert_eq!(
+            parse_ext_superblock(&sb).unwrap().block_count,
+            (1 << 32) + 1000
+        );
+
+        sb[0x18..0x1C].copy_from_slice(&60u32.to_le_bytes());
+        assert!(parse_ext_superblock(&sb).is_none());
+        sb[0x18..0x1C].copy_from_slice(&6u32.to_le_bytes());
+        assert_eq!(parse_ext_superblock(&sb).unwrap().block_size, 65536);
+
+        sb[0x38] = 0;
+        assert!(parse_ext_superblock(&sb).is_none());
+    }
+
+    #[test]
+    fn test_statvfs_usage() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let usage = get_fs_usage(tmp_dir.path()).unwrap();
+        assert!(usage.total_bytes >= usage.used_bytes);
+        assert!(usage.free_bytes >= usage.available_bytes);
+        assert!(usage.used_percent() <= 100.0);
+    }
+}
@@ -0,0 +1,706 @@
+//! ZFS pool and dataset management through the zpool and zfs commands.
+//!
+//! Scripted output (`-H` tab separated, `-p` exact numbers) is parsed where
+//! the tools offer it.  Pool health, vdev trees and scrub progress only
+//! exist in the human readable `zpool status` output so that is parsed too.
+//! Pools are created with `mkfs::ZpoolSpec`.
+use crate::{run_command, BlockResult, BlockUtilsError};
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// State of a pool or a vdev
+#[derive(Clone, Debug, Eq, PartialEq, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "UPPERCASE")]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Health {
+    Online,
+    Degraded,
+    Faulted,
+    Offline,
+    Removed,
+    Unavail,
+    Suspended,
+    /// A spare that isn't in use
+    Avail,
+    /// A spare that replaced a device
+    Inuse,
+    #[strum(default)]
+    Other(String),
+}
+
+/// One line of `zpool list`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Pool {
+    pub name: String,
+    pub size: u64,
+    pub allocated: u64,
+    pub free: u64,
+    /// Percentage of free space fragmentation.  None when unknown
+    pub fragmentation: Option<u8>,
+    /// Percentage of space used
+    pub capacity: u8,
+    pub dedup_ratio: f64,
+    pub health: Health,
+}
+
+/// A vdev in the config section of `zpool status`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct VdevStatus {
+    /// Device path, vdev like raidz2-0 or section like logs
+    pub name: String,
+    /// None for the logs, cache, spares and special section headers
+    pub state: Option<Health>,
+    pub read_errors: u64,
+    pub write_errors: u64,
+    pub checksum_errors: u64,
+    /// Trailing text, ie: "cannot open" or "(resilvering)"
+    pub note: Option<String>,
+    pub children: Vec<VdevStatus>,
+}
+
+/// What a scan does
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScanKind {
+    Scrub,
+    Resilver,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScanState {
+    InProgress,
+    Paused,
+    Finished,
+    Canceled,
+}
+
+/// The last or current scrub or resilver of a pool
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Scan {
+    pub kind: ScanKind,
+    pub state: ScanState,
+    pub percent_done: Option<f64>,
+    /// Errors found by a finished scan
+    pub errors: Option<u64>,
+    /// The scan lines as zpool printed them
+    pub text: String,
+}
+
+/// Output of `zpool status` for one pool
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PoolStatus {
+    pub name: String,
+    pub state: Health,
+    /// Explanation of a problem, if any
+    pub status: Option<String>,
+    /// What to do about it
+    pub action: Option<String>,
+    /// None if the pool was never scrubbed or resilvered
+    pub scan: Option<Scan>,
+    /// The pool's vdev tree followed by the logs, cache, spares and special
+    /// sections
+    pub config: Vec<VdevStatus>,
+    pub errors: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Display, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DatasetKind {
+    Filesystem,
+    Volume,
+    Snapshot,
+    Bookmark,
+}
+
+/// One line of `zfs list`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Dataset {
+    pub name: String,
+    pub kind: DatasetKind,
+    pub used: u64,
+    /// None for snapshots
+    pub available: Option<u64>,
+    pub referenced: u64,
+    /// None for volumes, snapshots and legacy or unmounted filesystems
+    pub mountpoint: Option<PathBuf>,
+}
+
+/// Value of a dataset property and where it came from
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Property {
+    pub value: String,
+    /// ie: default, local or inherited from tank
+    pub source: String,
+}
+
+/// Run zpool or zfs and return stdout
+fn zfs_command(program: &str, args: &[&str]) -> BlockResult<String> {
+    let output = run_command(program, args)?;
+    if !output.status.success() {
+        return Err(BlockUtilsError::new(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// "-" is how the scripted output says a value doesn't apply
+fn optional(field: &str) -> Option<&str> {
+    match field {
+        "-" | "" => None,
+        value => Some(value),
+    }
+}
+
+pub fn parse_zpool_list(output: &str) -> BlockResult<Vec<Pool>> {
+    let mut pools = Vec::new();
+    for line in output.lines().filter(|l| !l.trim().is_empty()) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 8 {
+            return Err(BlockUtilsError::new(format!(
+                "Unable to parse zpool list line: {}",
+                line
+            )));
+        }
+        pools.push(Pool {
+            name: fields[0].to_string(),
+            size: fields[1].parse()?,
+            allocated: fields[2].parse()?,
+            free: fields[3].parse()?,
+            fragmentation: optional(fields[4])
+                .map(|f| f.trim_end_matches('%').parse())
+                .transpose()?,
+            capacity: fields[5].trim_end_matches('%').parse()?,
+            dedup_ratio: fields[6]
+                .trim_end_matches('x')
+                .parse()
+                .map_err(|_| BlockUtilsError::new(format!("Invalid dedup ratio {}", fields[6])))?,
+            health: Health::from_str(fields[7])?,
+        });
+    }
+    Ok(pools)
+}
+
+/// Every imported pool
+pub fn list_pools() -> BlockResult<Vec<Pool>> {
+    parse_zpool_list(&zfs_command(
+        "zpool",
+        &[
+            "list",
+            "-Hp",
+            "-o",
+            "name,size,alloc,free,frag,cap,dedupratio,health",
+        ],
+    )?)
+}
+
+fn parse_scan(text: &str) -> Option<Scan> {
+    if text.starts_with("none requested") {
+        return None;
+    }
+    let kind = if text.contains("resilver") {
+        ScanKind::Resilver
+    } else {
+        ScanKind::Scrub
+    };
+    let state = if text.contains("in progress") {
+        ScanState::InProgress
+    } else if text.contains("paused") {
+        ScanState::Paused
+    } else if text.contains("canceled") {
+        ScanState::Canceled
+    } else {
+        ScanState::Finished
+    };
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let percent_done = words
+        .windows(2)
+        .find(|w| w[1].starts_with("done") && w[0].ends_with('%'))
+        .and_then(|w| w[0].trim_end_matches('%').parse().ok());
+    let errors = words
+        .windows(3)
+        .find(|w| w[0] == "with" && w[2].starts_with("error"))
+        .and_then(|w| w[1].parse().ok());
+    Some(Scan {
+        kind,
+        state,
+        percent_done,
+        errors,
+        text: text.to_string(),
+    })
+}
+
+/// Parse a config row: name, state, read, write, cksum and a note
+fn parse_vdev_row(row: &str) -> VdevStatus {
+    let mut fields = row.split_whitespace();
+    let name = fields.next().unwrap_or_default().to_string();
+    let state = fields
+        .next()
+        .map(|s| Health::from_str(s).unwrap_or_else(|_| Health::Other(s.to_string())));
+    let mut counter = || fields.next().and_then(|c| c.parse().ok()).unwrap_or(0);
+    let read_errors = counter();
+    let write_errors = counter();
+    let checksum_errors = counter();
+    let note: Vec<&str> = fields.collect();
+    VdevStatus {
+        name,
+        state,
+        read_errors,
+        write_errors,
+        checksum_errors,
+        note: if note.is_empty() {
+            None
+        } else {
+            Some(note.join(" "))
+        },
+        children: vec![],
+    }
+}
+
+/// Build the vdev tree from the indented config rows.  Each level is
+/// indented by two more spaces than its parent
+fn parse_config(lines: &[&str]) -> Vec<VdevStatus> {
+    // (depth, vdev) of the rows whose children are still being read
+    let mut stack: Vec<(usize, VdevStatus)> = Vec::new();
+    let mut roots = Vec::new();
+    for line in lines {
+        let row = line.trim_start_matches('\t');
+        if row.trim().is_empty() || row.trim_start().starts_with("NAME ") {
+            continue;
+        }
+        let depth = (row.len() - row.trim_start().len()) / 2;
+        let vdev = parse_vdev_row(row);
+        while let Some((top_depth, _)) = stack.last() {
+            if *top_depth < depth {
+                break;
+            }
+            let (_, done) = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some((_, parent)) => parent.children.push(done),
+                None => roots.push(done),
+            }
+        }
+        stack.push((depth, vdev));
+    }
+    while let Some((_, done)) = stack.pop() {
+        match stack.last_mut() {
+            Some((_, parent)) => parent.children.push(done),
+            None => roots.push(done),
+        }
+    }
+    roots
+}
+
+pub fn parse_zpool_status(output: &str) -> BlockResult<Vec<PoolStatus>> {
+    // Each "key:" field runs until the next one, continuation lines are
+    // indented with a tab
+    let mut pools: Vec<Vec<(String, Vec<&str>)>> = Vec::new();
+    for line in output.lines() {
+        let key = line
+            .split_once(':')
+            .map(|(k, _)| k.trim())
+            .filter(|k| !line.starts_with('\t') && !k.is_empty() && !k.contains(' '));
+        match key {
+            Some(key) => {
+                let value = line
+                    .split_once(':')
+                    .map(|(_, v)| v.trim())
+                    .unwrap_or_default();
+                if key == "pool" {
+                    pools.push(Vec::new());
+                }
+                if let Some(fields) = pools.last_mut() {
+                    fields.push((key.to_string(), vec![value]));
+                }
+            }
+            None => {
+                if let Some((_, lines)) = pools.last_mut().and_then(|f| f.last_mut()) {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+    let mut statuses = Vec::new();
+    for fields in pools {
+        let text = |key: &str| -> Option<String> {
+            fields.iter().find(|(k, _)| k == key).map(|(_, lines)| {
+                lines
+                    .iter()
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty())
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            })
+        };
+        let name = text("pool").unwrap_or_default();
+        let state = text("state").ok_or_else(|| {
+            BlockUtilsError::new(format!("zpool status of {} has no state", name))
+        })?;
+        let config = fields
+            .iter()
+            .find(|(k, _)| k == "config")
+            .map(|(_, lines)| parse_config(&lines[1..]))
+            .unwrap_or_default();
+        statuses.push(PoolStatus {
+            state: Health::from_str(&state)?,
+            status: text("status"),
+            action: text("action"),
+            scan: text("scan").and_then(|s| parse_scan(&s)),
+            config,
+            errors: text("errors"),
+            name,
+        });
+    }
+    Ok(statuses)
+}
+
+/// Health, vdev tree and scan progress of `pool`
+pub fn pool_status(pool: &str) -> BlockResult<PoolStatus> {
+    parse_zpool_status(&zfs_command("zpool", &["status", "-P", pool])?)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| BlockUtilsError::new(format!("No status for pool {}", pool)))
+}
+
+/// Status of every imported pool
+pub fn pool_statuses() -> BlockResult<Vec<PoolStatus>> {
+    parse_zpool_status(&zfs_command("zpool", &["status", "-P"])?)
+}
+
+/// Names of the pools that could be imported
+pub fn importable_pools() -> BlockResult<Vec<String>> {
+    let output = zfs_command("zpool", &["import"])?;
+    Ok(output
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("pool:"))
+        .map(|name| name.trim().to_string())
+        .collect())
+}
+
+/// Import `pool`.  `force` imports a pool that looks like it's in use by
+/// another system.
+pub fn import_pool(pool: &str, force: bool) -> BlockResult<()> {
+    let mut args = vec!["import"];
+    if force {
+        args.push("-f");
+    }
+    args.push(pool);
+    zfs_command("zpool", &args)?;
+    Ok(())
+}
+
+/// Export `pool`, unmounting its datasets.  `force` unmounts busy datasets
+pub fn export_pool(pool: &str, force: bool) -> BlockResult<()> {
+    let mut args = vec!["export"];
+    if force {
+        args.push("-f");
+    }
+    args.push(pool);
+    zfs_command("zpool", &args)?;
+    Ok(())
+}
+
+pub fn parse_zfs_list(output: &str) -> BlockResult<Vec<Dataset>> {
+    let mut datasets = Vec::new();
+    for line in output.lines().filter(|l| !l.trim().is_empty()) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 6 {
+            return Err(BlockUtilsError::new(format!(
+                "Unable to parse zfs list line: {}",
+                line
+            )));
+        }
+        datasets.push(Dataset {
+            name: fields[0].to_string(),
+            kind: DatasetKind::from_str(fields[1])?,
+            used: fields[2].parse()?,
+            available: optional(fields[3]).map(|a| a.parse()).transpose()?,
+            referenced: fields[4].parse()?,
+            mountpoint: optional(fields[5])
+                .filter(|m| m.starts_with('/'))
+                .map(PathBuf::from),
+        });
+    }
+    Ok(datasets)
+}
+
+fn list(types: &str, extra: &[&str]) -> BlockResult<Vec<Dataset>> {
+    let mut args = vec![
+        "list",
+        "-Hp",
+        "-t",
+        types,
+        "-o",
+        "name,type,used,avail,refer,mountpoint",
+    ];
+    args.extend_from_slice(extra);
+    parse_zfs_list(&zfs_command("zfs", &args)?)
+}
+
+/// Filesystems and volumes under `root`, including `root` itself.  Every
+/// dataset when `root` is None
+pub fn list_datasets(root: Option<&str>) -> BlockResult<Vec<Dataset>> {
+    match root {
+        Some(root) => list("filesystem,volume", &["-r", root]),
+        None => list("filesystem,volume", &[]),
+    }
+}
+
+/// Snapshots of `dataset`, oldest first
+pub fn list_snapshots(dataset: &str) -> BlockResult<Vec<Dataset>> {
+    list("snapshot", &["-s", "createtxg", "-d", "1", dataset])
+}
+
+fn property_args(properties: &[(&str, &str)]) -> Vec<String> {
+    properties
+        .iter()
+        .flat_map(|(name, value)| vec!["-o".to_string(), format!("{}={}", name, value)])
+        .collect()
+}
+
+/// Create a filesystem dataset, ie: tank/home with compression=lz4
+pub fn create_dataset(name: &str, properties: &[(&str, &str)]) -> BlockResult<()> {
+    let mut args = vec!["create".to_string(), "-p".to_string()];
+    args.extend(property_args(properties));
+    args.push(name.to_string());
+    zfs_command(
+        "zfs",
+        &args.iter().map(|a| a.as_str()).collect::<Vec<&str>>(),
+    )?;
+    Ok(())
+}
+
+/// Create a volume of `size` bytes.  A sparse volume doesn't reserve its
+/// space up front
+pub fn create_zvol(
+    name: &str,
+    size: u64,
+    sparse: bool,
+    properties: &[(&str, &str)],
+) -> BlockResult<()> {
+    let mut args = vec!["create".to_string()];
+    if sparse {
+        args.push("-s".to_string());
+    }
+    args.push("-V".to_string());
+    args.push(size.to_string());
+    args.extend(property_args(properties));
+    args.push(name.to_string());
+    zfs_command(
+        "zfs",
+        &args.iter().map(|a| a.as_str()).collect::<Vec<&str>>(),
+    )?;
+    Ok(())
+}
+
+/// Destroy a dataset, volume or snapshot.  `recursive` also destroys its
+/// children and snapshots
+pub fn destroy(name: &str, recursive: bool) -> BlockResult<()> {
+    let mut args = vec!["destroy"];
+    if recursive {
+        args.push("-r");
+    }
+    args.push(name);
+    zfs_command("zfs", &args)?;
+    Ok(())
+}
+
+pub fn parse_zfs_get(output: &str) -> BTreeMap<String, Property> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let name = fields.next()?;
+            let value = fields.next()?;
+            let source = fields.next().unwrap_or("-");
+            Some((
+                name.to_string(),
+                Property {
+                    value: value.to_string(),
+                    source: source.to_string(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Every property of a pool's dataset, volume or snapshot
+pub fn get_properties(name: &str) -> BlockResult<BTreeMap<String, Property>> {
+    Ok(parse_zfs_get(&zfs_command(
+        "zfs",
+        &["get", "-Hp", "-o", "property,value,source", "all", name],
+    )?))
+}
+
+/// One property of a dataset.  None when it doesn't apply, ie: volsize of
+/// a filesystem
+pub fn get_property(name: &str, property: &str) -> BlockResult<Option<String>> {
+    let output = zfs_command("zfs", &["get", "-Hp", "-o", "value", property, name])?;
+    Ok(optional(output.trim()).map(|v| v.to_string()))
+}
+
+pub fn set_property(name: &str, property: &str, value: &str) -> BlockResult<()> {
+    zfs_command("zfs", &["set", &format!("{}={}", property, value), name])?;
+    Ok(())
+}
+
+/// Snapshot `dataset` as dataset@name.  `recursive` snapshots its children
+/// atomically as well
+pub fn snapshot(dataset: &str, name: &str, recursive: bool) -> BlockResult<()> {
+    let snap = format!("{}@{}", dataset, name);
+    let mut args = vec!["snapshot"];
+    if recursive {
+        args.push("-r");
+    }
+    args.push(&snap);
+    zfs_command("zfs", &args)?;
+    Ok(())
+}
+
+/// Roll a dataset back to `snapshot`, ie: tank/home@monday.  zfs refuses
+/// when newer snapshots exist unless `destroy_newer` is set
+pub fn rollback(snapshot: &str, destroy_newer: bool) -> BlockResult<()> {
+    let mut args = vec!["rollback"];
+    if destroy_newer {
+        args.push("-r");
+    }
+    args.push(snapshot);
+    zfs_command("zfs", &args)?;
+    Ok(())
+}
+
+/// Start scrubbing `pool`.  Poll the progress with `scrub_status`
+pub fn start_scrub(pool: &str) -> BlockResult<()> {
+    zfs_command("zpool", &["scrub", pool])?;
+    Ok(())
+}
+
+pub fn stop_scrub(pool: &str) -> BlockResult<()> {
+    zfs_command("zpool", &["scrub", "-s", pool])?;
+    Ok(())
+}
+
+/// Progress of the current scrub or result of the last one
+pub fn scrub_status(pool: &str) -> BlockResult<Option<Scan>> {
+    Ok(pool_status(pool)?.scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "  pool: tank
+ state: DEGRADED
+status: One or more devices could not be opened.  Sufficient replicas exist for
+\tthe pool to continue functioning in a degraded state.
+action: Attach the missing device and online it using 'zpool online'.
+   see: https://openzfs.github.io/openzfs-docs/msg/ZFS-8000-2Q
+  scan: scrub in progress since Sun Oct 18 10:00:00 2026
+\t1.23G / 4.56G scanned at 100M/s, 600M / 4.56G issued at 50M/s
+\t0B repaired, 13.16% done, 00:01:20 to go
+config:
+
+\tNAME                STATE     READ WRITE CKSUM
+\ttank                DEGRADED     0     0     0
+\t  raidz2-0          DEGRADED     0     0     0
+\t    /dev/sdb        ONLINE       0     0     3
+\t    /dev/sdc        UNAVAIL      0     0     0  cannot open
+\t    /dev/sdd        ONLINE       0     0     0
+\tlogs
+\t  /dev/nvme0n1p1    ONLINE       0     0     0
+\tspares
+\t  /dev/sdj          AVAIL
+
+errors: No known data errors
+
+  pool: boot
+ state: ONLINE
+  scan: scrub repaired 0B in 00:00:03 with 2 errors on Sun Oct 11 00:24:04 2026
+config:
+
+\tNAME          STATE     READ WRITE CKSUM
+\tboot          ONLINE       0     0     0
+\t  /dev/sda2   ONLINE       0     0     0
+
+errors: No known data errors
+";
+
+    #[test]
+    fn test_parse_zpool_status() {
+        let pools = parse_zpool_status(STATUS).unwrap();
+        assert_eq!(pools.len(), 2);
+        let tank = &pools[0];
+        assert_eq!(tank.name, "tank");
+        assert_eq!(tank.state, Health::Degraded);
+        assert!(tank
+            .status
+            .as_ref()
+            .unwrap()
+            .ends_with("in a degraded state."));
+        let scan = tank.scan.as_ref().unwrap();
+        assert_eq!(scan.kind, ScanKind::Scrub);
+        assert_eq!(scan.state, ScanState::InProgress);
+        assert_eq!(scan.percent_done, Some(13.16));
+
+        assert_eq!(tank.config.len(), 3);
+        let raidz = &tank.config[0].children[0];
+        assert_eq!(raidz.name, "raidz2-0");
+        assert_eq!(raidz.children.len(), 3);
+        assert_eq!(raidz.children[0].checksum_errors, 3);
+        assert_eq!(raidz.children[1].state, Some(Health::Unavail));
+        assert_eq!(raidz.children[1].note.as_deref(), Some("cannot open"));
+        assert_eq!(tank.config[1].name, "logs");
+        assert_eq!(tank.config[1].state, None);
+        assert_eq!(tank.config[2].children[0].state, Some(Health::Avail));
+        assert_eq!(tank.errors.as_deref(), Some("No known data errors"));
+
+        let boot = &pools[1];
+        let scan = boot.scan.as_ref().unwrap();
+        assert_eq!(scan.state, ScanState::Finished);
+        assert_eq!(scan.errors, Some(2));
+        assert_eq!(boot.config[0].children[0].name, "/dev/sda2");
+    }
+
+    #[test]
+    fn test_parse_scripted_output() {
+        let pools = parse_zpool_list(
+            "tank\t4000787030016\t1200000000000\t2800787030016\t12\t30\t1.00\tONLINE\n\
+             boot\t1073741824\t536870912\t536870912\t-\t50\t1.25x\tDEGRADED\n",
+        )
+        .unwrap();
+        assert_eq!(pools[0].size, 4000787030016);
+        assert_eq!(pools[0].fragmentation, Some(12));
+        assert_eq!(pools[1].fragmentation, None);
+        assert!((pools[1].dedup_ratio - 1.25).abs() < f64::EPSILON);
+        assert_eq!(pools[1].health, Health::Degraded);
+
+        let datasets = parse_zfs_list(
+            "tank\tfilesystem\t1000\t5000\t100\t/tank\n\
+             tank/vm\tvolume\t2000\t5000\t200\t-\n\
+             tank/old\tfilesystem\t10\t5000\t10\tlegacy\n\
+             tank@monday\tsnapshot\t0\t-\t100\t-\n",
+        )
+        .unwrap();
+        assert_eq!(datasets[0].mountpoint, Some(PathBuf::from("/tank")));
+        assert_eq!(datasets[1].kind, DatasetKind::Volume);
+        assert_eq!(datasets[2].mountpoint, None);
+        assert_eq!(datasets[3].available, None);
+
+        let properties =
+            parse_zfs_get("compression\tlz4\tlocal\nrecordsize\t131072\tinherited from tank\n");
+        assert_eq!(properties["compression"].value, "lz4");
+        assert_eq!(properties["recordsize"].source, "inherited from tank");
+    }
+}